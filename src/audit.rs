@@ -0,0 +1,541 @@
+use anyhow::{bail, Context, Result};
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, TimeZone};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+
+use crate::cli::CliArgs;
+use crate::config::{AuditConfig, Config, RedactConfig};
+use crate::engine::{FinishReason, Generation};
+use crate::presets::Preset;
+use crate::server::{AppState, ErrorResponse};
+
+/// Replacement for text matched by `audit.redact.patterns`
+const REDACTED: &str = "[REDACTED]";
+/// Records returned by GET /audit when no `limit` is given
+const DEFAULT_QUERY_LIMIT: usize = 100;
+/// Upper bound for `limit` of GET /audit
+const MAX_QUERY_LIMIT: usize = 10_000;
+
+/// One generation as stored in the audit log
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AuditRecord {
+    pub timestamp: String,
+    pub request_id: String,
+    pub endpoint: String,
+    /// API key name (`anonymous` when auth is off)
+    pub key: String,
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    /// User input; absent when redacted away
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<String>,
+    /// SHA-256 of the prompt after template rendering
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<FinishReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// Answered from the response cache
+    #[serde(default)]
+    pub cache_hit: bool,
+    pub latency_ms: u64,
+}
+
+/// Where a generation came from
+pub struct Source {
+    pub endpoint: &'static str,
+    pub request_id: String,
+    pub key: String,
+}
+
+impl Source {
+    pub fn new(endpoint: &'static str, request_id: &str, key: &str) -> Self {
+        Self {
+            endpoint,
+            request_id: request_id.to_string(),
+            key: key.to_string(),
+        }
+    }
+}
+
+/// A finished (or failed) generation handed to [`AuditLog::record`]
+pub struct Exchange<'a> {
+    pub source: &'a Source,
+    pub model: &'a str,
+    pub preset: Option<&'a Preset>,
+    pub input: &'a str,
+    /// `None` when the prompt could not be rendered
+    pub rendered_prompt: Option<&'a str>,
+    pub result: Result<&'a Generation, &'a anyhow::Error>,
+    pub latency: Duration,
+}
+
+struct Redactor {
+    omit_inputs: bool,
+    omit_outputs: bool,
+    presets: Vec<String>,
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    fn new(config: &RedactConfig) -> Result<Self> {
+        let patterns = config.patterns.iter()
+            .map(|p| Regex::new(p).with_context(|| format!("некорректное выражение в audit.redact.patterns: {}", p)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            omit_inputs: config.omit_inputs,
+            omit_outputs: config.omit_outputs,
+            presets: config.presets.clone(),
+            patterns,
+        })
+    }
+
+    fn apply(&self, text: &str, omit: bool, preset: Option<&Preset>) -> Option<String> {
+        if omit || preset.is_some_and(|p| self.presets.contains(&p.name)) {
+            return None;
+        }
+        let mut text = text.to_string();
+        for pattern in &self.patterns {
+            text = pattern.replace_all(&text, REDACTED).into_owned();
+        }
+        Some(text)
+    }
+}
+
+/// Current file plus rotated ones (`audit.jsonl.1` is the newest rotated file)
+struct AuditFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl AuditFile {
+    fn open(config: &AuditConfig, path: &str) -> Result<Self> {
+        let path = PathBuf::from(path);
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("не удалось создать директорию {}", dir.display()))?;
+        }
+        let file = open_append(&path)
+            .with_context(|| format!("не удалось открыть журнал аудита {}", path.display()))?;
+        Ok(Self {
+            size: file.metadata()?.len(),
+            file,
+            path,
+            max_size: config.max_file_mb.max(1) * 1024 * 1024,
+            max_files: config.max_files,
+        })
+    }
+
+    /// Shifts `audit.jsonl` to `audit.jsonl.1`, `.1` to `.2` and so on, dropping the oldest
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(rotated_path(&self.path, self.max_files));
+            for n in (1..self.max_files).rev() {
+                let from = rotated_path(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn append(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Optional JSONL log of every generation served over HTTP
+pub struct AuditLog {
+    path: Option<String>,
+    max_files: usize,
+    redactor: Redactor,
+    file: Mutex<Option<AuditFile>>,
+}
+
+impl AuditLog {
+    pub fn new(config: &AuditConfig) -> Result<Self> {
+        let file = match &config.path {
+            Some(path) => Some(AuditFile::open(config, path)?),
+            None => None,
+        };
+        Ok(Self {
+            path: config.path.clone(),
+            max_files: config.max_files,
+            redactor: Redactor::new(&config.redact)?,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Appends a record; write errors are logged and never fail the request
+    pub fn record(&self, exchange: Exchange) {
+        if !self.enabled() {
+            return;
+        }
+        let (generation, error) = match exchange.result {
+            Ok(generation) => (Some(generation), None),
+            Err(e) => (None, Some(format!("{:#}", e))),
+        };
+        let record = AuditRecord {
+            timestamp: Local::now().to_rfc3339(),
+            request_id: exchange.source.request_id.clone(),
+            endpoint: exchange.source.endpoint.to_string(),
+            key: exchange.source.key.clone(),
+            model: exchange.model.to_string(),
+            preset: exchange.preset.map(|p| p.name.clone()),
+            input: self.redactor.apply(exchange.input, self.redactor.omit_inputs, exchange.preset),
+            prompt_sha256: exchange.rendered_prompt.map(|p| format!("{:x}", Sha256::digest(p.as_bytes()))),
+            output: generation.and_then(|g| self.redactor.apply(&g.text, self.redactor.omit_outputs, exchange.preset)),
+            finish_reason: generation.map(|g| g.finish_reason),
+            error,
+            prompt_tokens: generation.map_or(0, |g| g.prompt_tokens),
+            completion_tokens: generation.map_or(0, |g| g.completion_tokens),
+            cache_hit: generation.is_some_and(|g| g.cache_hit),
+            latency_ms: exchange.latency.as_millis() as u64,
+        };
+
+        let mut line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(error = %e, "failed to serialize audit record");
+                return;
+            }
+        };
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        if let Some(file) = file.as_mut() {
+            if let Err(e) = file.append(&line) {
+                warn!(error = %e, path = %file.path.display(), "failed to write audit record");
+            }
+        }
+    }
+
+    /// Searches the current and rotated files
+    pub fn query(&self, query: &AuditQuery) -> Result<Vec<AuditRecord>> {
+        match &self.path {
+            Some(path) => search(Path::new(path), self.max_files, query),
+            None => bail!("журнал аудита выключен (audit.path не задан)"),
+        }
+    }
+}
+
+/// Filters for GET /audit and `chat-np audit`; every filter is optional
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct AuditQuery {
+    /// `YYYY-MM-DD` or RFC 3339, inclusive
+    pub since: Option<String>,
+    /// `YYYY-MM-DD` (whole day included) or RFC 3339, exclusive
+    pub until: Option<String>,
+    pub key: Option<String>,
+    pub model: Option<String>,
+    pub preset: Option<String>,
+    pub request_id: Option<String>,
+    /// Substring of the stored input or output
+    pub contains: Option<String>,
+    /// Only the latest `limit` matching records are returned
+    pub limit: Option<usize>,
+    /// `json` (default), `jsonl` or `csv`
+    pub format: Option<String>,
+}
+
+fn parse_time(value: &str, end_of_day: bool) -> Result<DateTime<FixedOffset>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time);
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .with_context(|| format!("некорректная дата '{}': ожидается YYYY-MM-DD или RFC 3339", value))?;
+    let date = if end_of_day { date + chrono::Days::new(1) } else { date };
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap_or_default();
+    Local.from_local_datetime(&midnight)
+        .earliest()
+        .map(|t| t.fixed_offset())
+        .with_context(|| format!("некорректная дата '{}'", value))
+}
+
+struct Filter<'a> {
+    query: &'a AuditQuery,
+    since: Option<DateTime<FixedOffset>>,
+    until: Option<DateTime<FixedOffset>>,
+}
+
+impl<'a> Filter<'a> {
+    fn new(query: &'a AuditQuery) -> Result<Self> {
+        Ok(Self {
+            query,
+            since: query.since.as_deref().map(|s| parse_time(s, false)).transpose()?,
+            until: query.until.as_deref().map(|s| parse_time(s, true)).transpose()?,
+        })
+    }
+
+    fn matches(&self, record: &AuditRecord) -> bool {
+        let q = self.query;
+        if self.since.is_some() || self.until.is_some() {
+            let Ok(time) = DateTime::parse_from_rfc3339(&record.timestamp) else { return false };
+            if self.since.is_some_and(|since| time < since) || self.until.is_some_and(|until| time >= until) {
+                return false;
+            }
+        }
+        let text_matches = |needle: &str| {
+            record.input.as_deref().is_some_and(|t| t.contains(needle))
+                || record.output.as_deref().is_some_and(|t| t.contains(needle))
+        };
+        q.key.as_ref().is_none_or(|k| &record.key == k)
+            && q.model.as_ref().is_none_or(|m| &record.model == m)
+            && q.preset.as_ref().is_none_or(|p| record.preset.as_ref() == Some(p))
+            && q.request_id.as_ref().is_none_or(|id| &record.request_id == id)
+            && q.contains.as_deref().is_none_or(text_matches)
+    }
+}
+
+/// Returns the latest `limit` matching records, oldest first
+fn search(path: &Path, max_files: usize, query: &AuditQuery) -> Result<Vec<AuditRecord>> {
+    let filter = Filter::new(query)?;
+    let limit = query.limit.unwrap_or(usize::MAX);
+
+    // Newest file first, so the scan can stop once enough records are found
+    let files = std::iter::once(path.to_path_buf())
+        .chain((1..=max_files).map(|n| rotated_path(path, n)));
+    let mut found = Vec::new();
+    for file in files {
+        let file = match File::open(&file) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("не удалось прочитать {}", file.display())),
+        };
+        let mut matched: Vec<AuditRecord> = BufReader::new(file)
+            .lines()
+            .map_while(|line| line.ok())
+            // A line being written concurrently may be incomplete
+            .filter_map(|line| serde_json::from_str::<AuditRecord>(&line).ok())
+            .filter(|record| filter.matches(record))
+            .collect();
+        matched.reverse();
+        found.extend(matched.into_iter().take(limit - found.len()));
+        if found.len() >= limit {
+            break;
+        }
+    }
+    found.reverse();
+    Ok(found)
+}
+
+/// One CSV line: unlike the JSON form every column is always written, missing values as empty cells
+#[derive(Serialize)]
+struct AuditCsvRow<'a> {
+    timestamp: &'a str,
+    request_id: &'a str,
+    endpoint: &'a str,
+    key: &'a str,
+    model: &'a str,
+    preset: &'a str,
+    input: &'a str,
+    prompt_sha256: &'a str,
+    output: &'a str,
+    finish_reason: Option<FinishReason>,
+    error: &'a str,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    cache_hit: bool,
+    latency_ms: u64,
+}
+
+impl<'a> From<&'a AuditRecord> for AuditCsvRow<'a> {
+    fn from(record: &'a AuditRecord) -> Self {
+        Self {
+            timestamp: &record.timestamp,
+            request_id: &record.request_id,
+            endpoint: &record.endpoint,
+            key: &record.key,
+            model: &record.model,
+            preset: record.preset.as_deref().unwrap_or_default(),
+            input: record.input.as_deref().unwrap_or_default(),
+            prompt_sha256: record.prompt_sha256.as_deref().unwrap_or_default(),
+            output: record.output.as_deref().unwrap_or_default(),
+            finish_reason: record.finish_reason,
+            error: record.error.as_deref().unwrap_or_default(),
+            prompt_tokens: record.prompt_tokens,
+            completion_tokens: record.completion_tokens,
+            cache_hit: record.cache_hit,
+            latency_ms: record.latency_ms,
+        }
+    }
+}
+
+fn to_csv(records: &[AuditRecord]) -> Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for record in records {
+        writer.serialize(AuditCsvRow::from(record))?;
+    }
+    Ok(writer.into_inner()?)
+}
+
+fn to_jsonl(records: &[AuditRecord]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    for record in records {
+        serde_json::to_writer(&mut out, record)?;
+        out.push(b'\n');
+    }
+    Ok(out)
+}
+
+#[derive(Serialize)]
+struct AuditResponse {
+    count: usize,
+    records: Vec<AuditRecord>,
+}
+
+/// GET /audit: search and export of the audit log (admin only)
+pub async fn audit_handler(State(state): State<Arc<AppState>>, Query(mut query): Query<AuditQuery>) -> Response {
+    if !state.audit.enabled() {
+        return ErrorResponse::new(StatusCode::NOT_FOUND, "Audit log is disabled (audit.path is not set)");
+    }
+    let format = query.format.clone().unwrap_or_else(|| "json".to_string());
+    if !["json", "jsonl", "csv"].contains(&format.as_str()) {
+        return ErrorResponse::new(StatusCode::BAD_REQUEST, format!("Unknown format '{}': use json, jsonl or csv", format));
+    }
+    query.limit = Some(query.limit.unwrap_or(DEFAULT_QUERY_LIMIT).min(MAX_QUERY_LIMIT));
+
+    // Files can be large, so the scan runs off the async runtime
+    let worker_state = Arc::clone(&state);
+    let records = match tokio::task::spawn_blocking(move || worker_state.audit.query(&query)).await {
+        Ok(Ok(records)) => records,
+        Ok(Err(e)) => return ErrorResponse::new(StatusCode::BAD_REQUEST, format!("{:#}", e)),
+        Err(e) => return ErrorResponse::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+
+    let body = match format.as_str() {
+        "jsonl" => to_jsonl(&records).map(|body| ("application/x-ndjson", body)),
+        "csv" => to_csv(&records).map(|body| ("text/csv; charset=utf-8", body)),
+        _ => return (StatusCode::OK, Json(AuditResponse { count: records.len(), records })).into_response(),
+    };
+    match body {
+        Ok((content_type, body)) => (StatusCode::OK, [(header::CONTENT_TYPE, content_type)], body).into_response(),
+        Err(e) => ErrorResponse::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)),
+    }
+}
+
+/// `chat-np audit [--since DATE] [--until DATE] [--key NAME] [--model FILE] [--preset NAME]
+/// [--request-id ID] [--contains TEXT] [--limit N] [--format jsonl|csv] [--output FILE] [--config FILE]`
+pub fn run_command(cli: &CliArgs) -> Result<()> {
+    let config = Config::load(cli.value("config"))?;
+    let Some(path) = config.audit.path.as_deref() else {
+        bail!("журнал аудита выключен: задайте audit.path в {}", cli.value("config").unwrap_or(crate::config::CONFIG_FILE));
+    };
+    let query = AuditQuery {
+        since: cli.value("since").map(str::to_string),
+        until: cli.value("until").map(str::to_string),
+        key: cli.value("key").map(str::to_string),
+        model: cli.value("model").map(str::to_string),
+        preset: cli.value("preset").map(str::to_string),
+        request_id: cli.value("request-id").map(str::to_string),
+        contains: cli.value("contains").map(str::to_string),
+        limit: cli.parse_value("limit")?,
+        format: None,
+    };
+    let records = search(Path::new(path), config.audit.max_files, &query)?;
+
+    let body = match cli.value("format").unwrap_or("jsonl") {
+        "jsonl" => to_jsonl(&records)?,
+        "csv" => to_csv(&records)?,
+        other => bail!("неизвестный формат: {} (поддерживаются jsonl и csv)", other),
+    };
+    match cli.value("output") {
+        Some(output) => {
+            fs::write(output, body).with_context(|| format!("не удалось записать {}", output))?;
+            eprintln!("Записей: {} → {}", records.len(), output);
+        }
+        None => io::stdout().write_all(&body)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(output: Option<&str>, error: Option<&str>) -> AuditRecord {
+        AuditRecord {
+            timestamp: "2026-10-18T12:00:00+03:00".to_string(),
+            request_id: "req-1".to_string(),
+            endpoint: "/chat".to_string(),
+            key: "anonymous".to_string(),
+            model: "qwen".to_string(),
+            preset: None,
+            input: Some("телевизор".to_string()),
+            prompt_sha256: None,
+            output: output.map(str::to_string),
+            finish_reason: output.map(|_| FinishReason::Stop),
+            error: error.map(str::to_string),
+            prompt_tokens: 12,
+            completion_tokens: 3,
+            cache_hit: false,
+            latency_ms: 250,
+        }
+    }
+
+    #[test]
+    fn csv_keeps_every_column_when_optional_fields_differ() {
+        let records = [record(Some("ДОРОГОЙ"), None), record(None, Some("Context overflow"))];
+        let body = to_csv(&records).unwrap();
+
+        let mut reader = csv::Reader::from_reader(body.as_slice());
+        let headers = reader.headers().unwrap().clone();
+        assert_eq!(headers.len(), 15);
+        let rows: Vec<csv::StringRecord> = reader.records().map(|row| row.unwrap()).collect();
+        assert_eq!(rows.len(), 2);
+        let column = |name: &str| headers.iter().position(|h| h == name).unwrap();
+        assert_eq!(&rows[0][column("output")], "ДОРОГОЙ");
+        assert_eq!(&rows[0][column("finish_reason")], "stop");
+        assert_eq!(&rows[0][column("error")], "");
+        assert_eq!(&rows[1][column("output")], "");
+        assert_eq!(&rows[1][column("finish_reason")], "");
+        assert_eq!(&rows[1][column("error")], "Context overflow");
+    }
+}
@@ -0,0 +1,177 @@
+use anyhow::{bail, Result};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::cli::CliArgs;
+use crate::config::ApiKeyConfig;
+use crate::presets::model_matches;
+use crate::ratelimit::Limits;
+use crate::server::{AppState, ErrorResponse};
+
+/// Header accepted in addition to `Authorization: Bearer <key>`
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// Permissions of the caller, attached to every request by [`require_api_key`]
+pub struct ApiKey {
+    pub name: String,
+    /// Model name fragments this key may use (empty means any model)
+    models: Vec<String>,
+    /// Preset names this key may use (empty means any preset)
+    presets: Vec<String>,
+    /// Access to preset reload/status and other admin endpoints
+    pub admin: bool,
+    /// Overrides of the server-wide rate limits
+    pub limits: Limits,
+}
+
+impl ApiKey {
+    /// Caller when authentication is disabled
+    fn unrestricted() -> Self {
+        Self {
+            name: "anonymous".to_string(),
+            models: Vec::new(),
+            presets: Vec::new(),
+            admin: true,
+            limits: Limits::default(),
+        }
+    }
+
+    pub fn allows_model(&self, model: &str) -> bool {
+        self.models.is_empty() || self.models.iter().any(|pattern| model_matches(pattern, model))
+    }
+
+    pub fn allows_preset(&self, preset: &str) -> bool {
+        self.presets.is_empty() || self.presets.iter().any(|p| p == preset)
+    }
+
+    /// Checks model and preset scopes; the error is a ready 403 response
+    pub fn authorize(&self, model: &str, preset: Option<&str>) -> Result<(), Response> {
+        self.authorize_requested(Some(model), preset)
+    }
+
+    /// Checks the names a request asks for before they are looked up, so a key
+    /// gets the same 403 for presets and models that exist and ones that do not
+    pub fn authorize_requested(&self, model: Option<&str>, preset: Option<&str>) -> Result<(), Response> {
+        if let Some(model) = model.filter(|m| !self.allows_model(m)) {
+            return Err(ErrorResponse::new(StatusCode::FORBIDDEN, format!("API key '{}' may not use model '{}'", self.name, model)));
+        }
+        if let Some(preset) = preset.filter(|p| !self.allows_preset(p)) {
+            return Err(ErrorResponse::new(StatusCode::FORBIDDEN, format!("API key '{}' may not use preset '{}'", self.name, preset)));
+        }
+        Ok(())
+    }
+}
+
+/// Configured keys, indexed by the SHA-256 of the key. Without keys authentication is off.
+pub struct Auth {
+    keys: HashMap<String, Arc<ApiKey>>,
+    anonymous: Arc<ApiKey>,
+}
+
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+impl Auth {
+    pub fn new(keys: &[ApiKeyConfig]) -> Result<Self> {
+        let mut by_hash = HashMap::new();
+        for key in keys {
+            let hash = key.key_sha256.trim().to_ascii_lowercase();
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                bail!("ключ '{}': key_sha256 должен быть SHA-256 в hex (64 символа)", key.name);
+            }
+            let api_key = Arc::new(ApiKey {
+                name: key.name.clone(),
+                models: key.models.clone(),
+                presets: key.presets.clone(),
+                admin: key.admin,
+                limits: Limits {
+                    requests_per_minute: key.requests_per_minute,
+                    tokens_per_day: key.tokens_per_day,
+                },
+            });
+            if by_hash.insert(hash, api_key).is_some() {
+                bail!("ключ '{}' совпадает с другим ключом", key.name);
+            }
+        }
+        Ok(Self {
+            keys: by_hash,
+            anonymous: Arc::new(ApiKey::unrestricted()),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    fn lookup(&self, key: &str) -> Option<Arc<ApiKey>> {
+        self.keys.get(&hash_key(key)).cloned()
+    }
+}
+
+fn presented_key(headers: &HeaderMap) -> Option<&str> {
+    let bearer = headers.get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    bearer
+        .or_else(|| headers.get(API_KEY_HEADER).and_then(|v| v.to_str().ok()))
+        .map(str::trim)
+}
+
+fn unauthorized(message: &str) -> Response {
+    let mut response = ErrorResponse::new(StatusCode::UNAUTHORIZED, message);
+    response.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+    response
+}
+
+/// Resolves the API key of a request and stores it as an `Arc<ApiKey>` extension
+pub async fn require_api_key(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let key = if state.auth.enabled() {
+        let Some(presented) = presented_key(request.headers()) else {
+            return unauthorized("Missing API key. Pass it as 'Authorization: Bearer <key>' or 'X-API-Key'");
+        };
+        match state.auth.lookup(presented) {
+            Some(key) => key,
+            None => return unauthorized("Invalid API key"),
+        }
+    } else {
+        Arc::clone(&state.auth.anonymous)
+    };
+    request.extensions_mut().insert(key);
+    next.run(request).await
+}
+
+/// Rejects callers without the `admin` scope; must run after [`require_api_key`]
+pub async fn require_admin(request: Request, next: Next) -> Response {
+    let admin = request.extensions().get::<Arc<ApiKey>>().is_some_and(|key| key.admin);
+    if !admin {
+        return ErrorResponse::new(StatusCode::FORBIDDEN, "This endpoint requires an API key with admin access");
+    }
+    next.run(request).await
+}
+
+/// `chat-np hash-key [--key KEY]`: prints the SHA-256 to put into `auth.keys`.
+/// Without `--key` a new random key is generated.
+pub fn hash_key_command(cli: &CliArgs) -> Result<()> {
+    match cli.value("key") {
+        Some(key) => println!("{}", hash_key(key)),
+        None => {
+            let key = format!("cnp_{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+            println!("key:        {}", key);
+            println!("key_sha256: {}", hash_key(&key));
+        }
+    }
+    Ok(())
+}
+
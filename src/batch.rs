@@ -0,0 +1,514 @@
+use anyhow::{bail, Context, Result};
+use llama_cpp_2::llama_backend::LlamaBackend;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::cli::CliArgs;
+use crate::engine::{self, GenerationParams, ModelCache};
+use crate::postprocess::{self, ValidationFailed};
+use crate::presets::load_presets;
+use crate::retrieval::Retriever;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum InputFormat {
+    Jsonl,
+    Csv,
+    Lines,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Jsonl,
+    Csv,
+}
+
+fn input_format(path: &str, explicit: Option<&str>) -> Result<InputFormat> {
+    let name = match explicit {
+        Some(f) => f.to_ascii_lowercase(),
+        None => Path::new(path).extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase(),
+    };
+    Ok(match name.as_str() {
+        "jsonl" | "ndjson" => InputFormat::Jsonl,
+        "csv" => InputFormat::Csv,
+        "lines" | "txt" | "" => InputFormat::Lines,
+        other if explicit.is_some() => bail!("неизвестный формат входа: {}", other),
+        _ => InputFormat::Lines,
+    })
+}
+
+fn output_format(path: &str, explicit: Option<&str>) -> Result<OutputFormat> {
+    let name = match explicit {
+        Some(f) => f.to_ascii_lowercase(),
+        None => Path::new(path).extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase(),
+    };
+    Ok(match name.as_str() {
+        "csv" => OutputFormat::Csv,
+        "jsonl" | "ndjson" | "json" | "" => OutputFormat::Jsonl,
+        other => bail!("неизвестный формат результата: {} (поддерживаются jsonl и csv)", other),
+    })
+}
+
+/// One input record to process
+pub struct InputRecord {
+    /// Position in the input file (0-based), used for resuming
+    pub index: usize,
+    pub id: Option<String>,
+    pub input: String,
+}
+
+/// One line of the result file
+#[derive(Serialize, Deserialize)]
+pub struct OutputRecord {
+    pub index: usize,
+    #[serde(default)]
+    pub id: Option<String>,
+    pub input: String,
+    pub output: String,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub duration_ms: u128,
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Result of the preset's `postprocess` or `output_parser` (objects as JSON text)
+    #[serde(default)]
+    pub parsed: Option<String>,
+    #[serde(default)]
+    pub parse_error: Option<String>,
+    /// Label probabilities of classification presets (JSON text)
+    #[serde(default)]
+    pub probabilities: Option<String>,
+}
+
+fn json_value_to_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn read_jsonl(path: &str, field: &str) -> Result<Vec<InputRecord>> {
+    let file = File::open(path).with_context(|| format!("не удалось открыть {}", path))?;
+    let mut records = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: serde_json::Value = serde_json::from_str(&line)
+            .with_context(|| format!("{}:{}: некорректный JSON", path, i + 1))?;
+        let Some(input) = value.get(field) else {
+            bail!("{}:{}: нет поля '{}'", path, i + 1, field);
+        };
+        records.push(InputRecord {
+            index: records.len(),
+            id: value.get("id").map(json_value_to_string),
+            input: json_value_to_string(input),
+        });
+    }
+    Ok(records)
+}
+
+fn read_csv(path: &str, column: Option<&str>) -> Result<Vec<InputRecord>> {
+    let mut reader = csv::Reader::from_path(path)
+        .with_context(|| format!("не удалось открыть {}", path))?;
+    let headers = reader.headers()?.clone();
+    let column_index = match column {
+        None => 0,
+        Some(name) => match headers.iter().position(|h| h == name) {
+            Some(i) => i,
+            None => match name.parse::<usize>() {
+                Ok(i) if i < headers.len() => i,
+                _ => bail!("в {} нет колонки '{}'. Колонки: {:?}", path, name, headers),
+            },
+        },
+    };
+    let id_index = headers.iter().position(|h| h == "id");
+
+    let mut records = Vec::new();
+    for (i, row) in reader.records().enumerate() {
+        let row = row.with_context(|| format!("{}: ошибка в строке {}", path, i + 2))?;
+        records.push(InputRecord {
+            index: records.len(),
+            id: id_index.and_then(|c| row.get(c)).map(|s| s.to_string()),
+            input: row.get(column_index).unwrap_or("").to_string(),
+        });
+    }
+    Ok(records)
+}
+
+fn read_lines(path: &str) -> Result<Vec<InputRecord>> {
+    let content = fs::read_to_string(path).with_context(|| format!("не удалось прочитать {}", path))?;
+    Ok(content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .enumerate()
+        .map(|(index, line)| InputRecord { index, id: None, input: line.to_string() })
+        .collect())
+}
+
+/// Results written by an earlier run, in file order
+fn read_results(path: &str, format: OutputFormat) -> Result<Vec<OutputRecord>> {
+    if !Path::new(path).is_file() {
+        return Ok(Vec::new());
+    }
+    let mut results = Vec::new();
+    match format {
+        OutputFormat::Jsonl => {
+            let file = File::open(path)?;
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                // A partially written last line (crash) is simply skipped
+                if let Ok(record) = serde_json::from_str::<OutputRecord>(&line) {
+                    results.push(record);
+                }
+            }
+        }
+        OutputFormat::Csv => {
+            let mut reader = csv::Reader::from_path(path)?;
+            results.extend(reader.deserialize::<OutputRecord>().flatten());
+        }
+    }
+    Ok(results)
+}
+
+/// Successful earlier results that still belong to the same input: a result counts
+/// only if the record at its `index` has the same text and `id`, so an edited or
+/// reordered input file is processed again. One result is kept per record.
+fn completed_results(previous: Vec<OutputRecord>, records: &[InputRecord]) -> Vec<OutputRecord> {
+    let mut seen = HashSet::new();
+    previous.into_iter()
+        .filter(|result| result.error.as_deref().map_or(true, str::is_empty))
+        .filter(|result| records.get(result.index)
+            .is_some_and(|record| record.input == result.input && record.id == result.id))
+        .filter(|result| seen.insert(result.index))
+        .collect()
+}
+
+/// Replaces the result file with `kept`, so a resumed run does not leave error rows
+/// or results of changed inputs next to the new ones
+fn rewrite_results(path: &str, format: OutputFormat, kept: &[OutputRecord]) -> Result<()> {
+    let temp = format!("{}.tmp", path);
+    let mut writer = OutputWriter::open(&temp, format, false)?;
+    for result in kept {
+        writer.write(result)?;
+    }
+    drop(writer);
+    fs::rename(&temp, path).with_context(|| format!("не удалось заменить {}", path))
+}
+
+enum OutputWriter {
+    Jsonl(BufWriter<File>),
+    Csv(csv::Writer<File>),
+}
+
+impl OutputWriter {
+    fn open(path: &str, format: OutputFormat, append: bool) -> Result<Self> {
+        let exists = append && Path::new(path).is_file() && fs::metadata(path)?.len() > 0;
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .with_context(|| format!("не удалось открыть {}", path))?;
+        Ok(match format {
+            OutputFormat::Jsonl => OutputWriter::Jsonl(BufWriter::new(file)),
+            OutputFormat::Csv => OutputWriter::Csv(
+                csv::WriterBuilder::new().has_headers(!exists).from_writer(file),
+            ),
+        })
+    }
+
+    /// Writes and flushes a record so an interrupted run can be resumed
+    fn write(&mut self, record: &OutputRecord) -> Result<()> {
+        match self {
+            OutputWriter::Jsonl(w) => {
+                serde_json::to_writer(&mut *w, record)?;
+                w.write_all(b"\n")?;
+                w.flush()?;
+            }
+            OutputWriter::Csv(w) => {
+                w.serialize(record)?;
+                w.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads input records in the format given by `--input-format` or the file extension
+pub fn read_inputs(path: &str, cli: &CliArgs) -> Result<Vec<InputRecord>> {
+    match input_format(path, cli.value("input-format"))? {
+        InputFormat::Jsonl => read_jsonl(path, cli.value("field").unwrap_or("prompt")),
+        InputFormat::Csv => read_csv(path, cli.value("column")),
+        InputFormat::Lines => read_lines(path),
+    }
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs.max(0.0) as u64;
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// `chat-np run --preset NAME --input FILE --output FILE [--model FILE] [--field prompt]
+/// [--column NAME] [--input-format jsonl|csv|lines] [--output-format jsonl|csv] [--overwrite]`
+///
+/// Results are appended record by record; rerunning the same command skips
+/// records that already have a successful result for the same input and
+/// rewrites the file without the failed rows, which are retried.
+pub fn run_command(backend: &LlamaBackend, available_models: &[String], cli: &CliArgs) -> Result<()> {
+    let preset_name = cli.require("preset")?;
+    let input_path = cli.require("input")?;
+    let output_path = cli.require("output")?;
+
+    let presets = load_presets();
+    let Some(preset) = presets.iter().find(|p| p.name == preset_name) else {
+        bail!("пресет '{}' не найден", preset_name);
+    };
+
+    let model_name = match cli.value("model") {
+        Some(m) => m.to_string(),
+        None => preset.preferred_model(available_models)
+            .or_else(|| available_models.first().cloned())
+            .unwrap_or_default(),
+    };
+    if !available_models.contains(&model_name) {
+        bail!("модель '{}' не найдена. Доступные модели: {:?}", model_name, available_models);
+    }
+    if !preset.allows_model(&model_name) {
+        bail!("пресет '{}' не разрешает модель '{}'", preset.name, model_name);
+    }
+
+    let records = read_inputs(input_path, cli)?;
+    let out_format = output_format(output_path, cli.value("output-format"))?;
+
+    let overwrite = cli.flag("overwrite");
+    let done: HashSet<usize> = if overwrite || !Path::new(output_path).is_file() {
+        HashSet::new()
+    } else {
+        let kept = completed_results(read_results(output_path, out_format)?, &records);
+        rewrite_results(output_path, out_format, &kept)?;
+        kept.iter().map(|result| result.index).collect()
+    };
+    let pending: Vec<&InputRecord> = records.iter()
+        .filter(|r| !done.contains(&r.index))
+        .collect();
+
+    eprintln!("Записей: {}, уже обработано: {}, осталось: {}", records.len(), records.len() - pending.len(), pending.len());
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!("Загрузка модели {}...", model_name);
+    // Retrieval embeddings come from the same cache, so the chat model is not loaded twice
+    let models = ModelCache::default();
+    let model = models.get_or_load(backend, &model_name)?;
+    let mut ctx = engine::new_context(&model, backend)?;
+    let preset = preset.for_model(&model_name);
+    let params = GenerationParams::from_preset(&preset);
+    let retriever = Retriever::default();
+
+    let mut writer = OutputWriter::open(output_path, out_format, !overwrite)?;
+    let started = Instant::now();
+    let mut errors = 0;
+    let mut total_tokens = 0;
+
+    for (n, record) in pending.iter().enumerate() {
+        let record_started = Instant::now();
+        let generated = retriever.context(&models, backend, &preset, &record.input)
+            .and_then(|context| engine::render_prompt(&model, &preset, &record.input, context.as_deref()))
+            .and_then(|prompt| postprocess::generate_validated(Some(&preset), &params, |params| {
+                engine::generate(&model, &mut ctx, &prompt, params)
+            }));
+
+        let output = match generated {
+            Ok((g, parsed)) => OutputRecord {
+                index: record.index,
+                id: record.id.clone(),
+                input: record.input.clone(),
+                output: g.text,
+                prompt_tokens: g.prompt_tokens,
+                completion_tokens: g.completion_tokens,
+                duration_ms: record_started.elapsed().as_millis(),
+                error: None,
+                parsed: parsed.text(),
+                parse_error: parsed.parse_error,
+                probabilities: (!g.probabilities.is_empty())
+                    .then(|| serde_json::to_string(&g.probabilities).unwrap_or_default()),
+            },
+            Err(e) => {
+                errors += 1;
+                OutputRecord {
+                    index: record.index,
+                    id: record.id.clone(),
+                    input: record.input.clone(),
+                    // The last rejected output helps to adjust the preset
+                    output: e.downcast_ref::<ValidationFailed>()
+                        .map(|failed| failed.response.clone())
+                        .unwrap_or_default(),
+                    prompt_tokens: 0,
+                    completion_tokens: 0,
+                    duration_ms: record_started.elapsed().as_millis(),
+                    error: Some(format!("{:#}", e)),
+                    parsed: None,
+                    parse_error: None,
+                    probabilities: None,
+                }
+            }
+        };
+        total_tokens += output.prompt_tokens + output.completion_tokens;
+        writer.write(&output)?;
+
+        let processed = n + 1;
+        let elapsed = started.elapsed().as_secs_f64();
+        let rate = processed as f64 / elapsed.max(0.001);
+        let eta = (pending.len() - processed) as f64 / rate;
+        eprint!(
+            "\r[{}/{}] {:.1}%  {:.2} зап/с  осталось {}  ошибок: {}   ",
+            processed, pending.len(), processed as f64 * 100.0 / pending.len() as f64,
+            rate, format_duration(eta), errors
+        );
+        io::stderr().flush()?;
+    }
+
+    eprintln!();
+    eprintln!(
+        "Готово за {}: обработано {}, ошибок {}, токенов {}. Результат: {}",
+        format_duration(started.elapsed().as_secs_f64()), pending.len(), errors, total_tokens, output_path
+    );
+    if errors > 0 {
+        eprintln!("Записи с ошибками будут обработаны повторно при следующем запуске.");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_file(extension: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("chat-np-batch-{}.{}", uuid::Uuid::new_v4(), extension));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn input(index: usize, text: &str) -> InputRecord {
+        InputRecord { index, id: None, input: text.to_string() }
+    }
+
+    fn result(index: usize, text: &str, error: Option<&str>) -> OutputRecord {
+        OutputRecord {
+            index,
+            id: None,
+            input: text.to_string(),
+            output: if error.is_some() { String::new() } else { "ДЕШЕВЫЙ".to_string() },
+            prompt_tokens: 10,
+            completion_tokens: 2,
+            duration_ms: 100,
+            error: error.map(str::to_string),
+            parsed: None,
+            parse_error: None,
+            probabilities: None,
+        }
+    }
+
+    fn indices(results: &[OutputRecord]) -> Vec<usize> {
+        results.iter().map(|r| r.index).collect()
+    }
+
+    #[test]
+    fn formats_come_from_the_flag_or_the_extension() {
+        assert!(input_format("data.jsonl", None).unwrap() == InputFormat::Jsonl);
+        assert!(input_format("data.CSV", None).unwrap() == InputFormat::Csv);
+        assert!(input_format("data.txt", None).unwrap() == InputFormat::Lines);
+        assert!(input_format("data.dat", None).unwrap() == InputFormat::Lines);
+        assert!(input_format("data.txt", Some("csv")).unwrap() == InputFormat::Csv);
+        assert!(input_format("data.txt", Some("xml")).is_err());
+
+        assert!(output_format("out.csv", None).unwrap() == OutputFormat::Csv);
+        assert!(output_format("out", None).unwrap() == OutputFormat::Jsonl);
+        assert!(output_format("out.csv", Some("jsonl")).unwrap() == OutputFormat::Jsonl);
+        assert!(output_format("out.xlsx", None).is_err());
+    }
+
+    #[test]
+    fn csv_column_is_selected_by_name_or_number() {
+        let path = temp_file("csv", "id,title,price\n7,iPhone 15,999\n8,газета,1\n");
+        let path = path.to_str().unwrap();
+
+        let records = read_csv(path, Some("title")).unwrap();
+        assert_eq!(records.iter().map(|r| r.input.as_str()).collect::<Vec<_>>(), ["iPhone 15", "газета"]);
+        assert_eq!(records[1].index, 1);
+        assert_eq!(records[1].id.as_deref(), Some("8"));
+
+        assert_eq!(read_csv(path, Some("2")).unwrap()[0].input, "999");
+        assert_eq!(read_csv(path, None).unwrap()[0].input, "7");
+        assert!(read_csv(path, Some("name")).is_err());
+        assert!(read_csv(path, Some("3")).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn truncated_jsonl_line_is_skipped() {
+        let complete = serde_json::to_string(&result(0, "iPhone 15", None)).unwrap();
+        let path = temp_file("jsonl", &format!("{}\n{{\"index\":1,\"inpu", complete));
+        let results = read_results(path.to_str().unwrap(), OutputFormat::Jsonl).unwrap();
+        assert_eq!(indices(&results), [0]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn csv_results_are_resumed_and_rewritten_without_errors() {
+        let path = std::env::temp_dir().join(format!("chat-np-batch-{}.csv", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let mut writer = OutputWriter::open(path, OutputFormat::Csv, false).unwrap();
+        for row in [result(0, "iPhone 15", None), result(1, "газета", Some("Context overflow")), result(1, "газета", None)] {
+            writer.write(&row).unwrap();
+        }
+        drop(writer);
+
+        let records = [input(0, "iPhone 15"), input(1, "газета"), input(2, "ноутбук")];
+        let kept = completed_results(read_results(path, OutputFormat::Csv).unwrap(), &records);
+        assert_eq!(indices(&kept), [0, 1]);
+
+        rewrite_results(path, OutputFormat::Csv, &kept).unwrap();
+        let rewritten = read_results(path, OutputFormat::Csv).unwrap();
+        assert_eq!(indices(&rewritten), [0, 1]);
+        assert!(rewritten.iter().all(|r| r.error.is_none()));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn results_of_changed_inputs_are_not_completed() {
+        let previous = vec![
+            result(0, "iPhone 15", None),
+            result(1, "газета", None),
+            result(2, "ноутбук", Some("validation_failed")),
+            result(3, "телевизор", None),
+        ];
+        // Rows 0 and 1 swapped, row 3 removed
+        let records = [input(0, "газета"), input(1, "iPhone 15"), input(2, "ноутбук")];
+        assert!(completed_results(previous, &records).is_empty());
+    }
+
+    #[test]
+    fn duplicate_results_keep_the_first() {
+        let previous = vec![result(0, "iPhone 15", None), result(0, "iPhone 15", None)];
+        let mut records = [input(0, "iPhone 15")];
+        assert_eq!(indices(&completed_results(previous, &records)), [0]);
+
+        records[0].id = Some("7".to_string());
+        assert!(completed_results(vec![result(0, "iPhone 15", None)], &records).is_empty());
+    }
+}
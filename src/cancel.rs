@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::auth::ApiKey;
+
+/// Shared flag checked by the token loop between tokens
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Cancels the token when dropped.
+///
+/// Axum drops the handler future (or the response stream) when the client
+/// disconnects, so keeping this guard alive there turns a disconnect into a cancel.
+pub struct CancelOnDrop(pub CancelToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Requests currently queued or running, by request id, for explicit cancellation
+#[derive(Default)]
+pub struct ActiveRequests {
+    /// Cancel token and the name of the API key that started the request
+    tokens: Mutex<HashMap<String, (CancelToken, String)>>,
+}
+
+/// Removes the request from [`ActiveRequests`] when dropped
+pub struct ActiveRequest {
+    registry: Arc<ActiveRequests>,
+    id: String,
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.registry.tokens.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl ActiveRequests {
+    /// Registers a request. Returns `None` if the id is already in use.
+    pub fn register(self: &Arc<Self>, id: &str, token: CancelToken, owner: &str) -> Option<ActiveRequest> {
+        let mut tokens = self.tokens.lock().unwrap();
+        if tokens.contains_key(id) {
+            return None;
+        }
+        tokens.insert(id.to_string(), (token, owner.to_string()));
+        Some(ActiveRequest {
+            registry: Arc::clone(self),
+            id: id.to_string(),
+        })
+    }
+
+    /// Cancels a request by id. Returns `false` if no such request is active
+    /// or it belongs to another API key (admin keys may cancel any request).
+    pub fn cancel(&self, id: &str, key: &ApiKey) -> bool {
+        match self.tokens.lock().unwrap().get(id) {
+            Some((token, owner)) if key.admin || *owner == key.name => {
+                token.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+}
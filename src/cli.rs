@@ -0,0 +1,79 @@
+use anyhow::{bail, Result};
+
+/// Minimal command line parser: `[command] [--option value | --option=value | --flag]...`
+pub struct CliArgs {
+    pub command: Option<String>,
+    options: Vec<(String, String)>,
+    flags: Vec<String>,
+}
+
+impl CliArgs {
+    /// Parses arguments (without the program name).
+    /// `flag_names` lists options that take no value.
+    pub fn parse(args: &[String], flag_names: &[&str]) -> Result<Self> {
+        let mut command = None;
+        let mut options = Vec::new();
+        let mut flags = Vec::new();
+
+        let mut iter = args.iter().peekable();
+        while let Some(arg) = iter.next() {
+            if let Some(name) = arg.strip_prefix("--") {
+                if let Some((name, value)) = name.split_once('=') {
+                    options.push((name.to_string(), value.to_string()));
+                } else if flag_names.contains(&name) {
+                    flags.push(name.to_string());
+                } else {
+                    match iter.next() {
+                        Some(value) => options.push((name.to_string(), value.clone())),
+                        None => bail!("не указано значение для --{}", name),
+                    }
+                }
+            } else if command.is_none() && options.is_empty() && flags.is_empty() {
+                command = Some(arg.clone());
+            } else {
+                bail!("неожиданный аргумент: {}", arg);
+            }
+        }
+
+        Ok(Self { command, options, flags })
+    }
+
+    /// Last value of an option (later values win)
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.options.iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All values of a repeatable option; comma-separated values are split
+    pub fn values(&self, name: &str) -> Vec<String> {
+        self.options.iter()
+            .filter(|(n, _)| n == name)
+            .flat_map(|(_, v)| v.split(','))
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect()
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|f| f == name)
+    }
+
+    pub fn require(&self, name: &str) -> Result<&str> {
+        match self.value(name) {
+            Some(v) => Ok(v),
+            None => bail!("не указан обязательный параметр --{}", name),
+        }
+    }
+
+    pub fn parse_value<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>> {
+        match self.value(name) {
+            Some(v) => match v.parse() {
+                Ok(parsed) => Ok(Some(parsed)),
+                Err(_) => bail!("некорректное значение --{}: {}", name, v),
+            },
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,560 @@
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::info;
+
+use crate::audit::{Exchange, Source};
+use crate::auth::ApiKey;
+use crate::cancel::{CancelOnDrop, CancelToken};
+use crate::engine::{self, FinishReason, Generation, GenerationParams, Logprobs, PromptCache, SamplingParams};
+use crate::logging::RequestId;
+use crate::metrics::RequestLabels;
+use crate::presets::Preset;
+use crate::ratelimit::Caller;
+use crate::server::{cap_max_time, check_logprobs, queue_error_status, resolve_request, AppState, ErrorResponse, ResolvedRequest};
+use crate::tools::{self, ToolCall, ToolChoice, ToolDefinition};
+
+/// Local tool calls executed for one request before the model has to answer with text
+const MAX_TOOL_ROUNDS: usize = 5;
+
+/// `content` of a message: a string or a list of parts (only text parts are used)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Deserialize)]
+struct ContentPart {
+    #[serde(default)]
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    role: String,
+    #[serde(default)]
+    content: Option<MessageContent>,
+    /// Calls made by the assistant in an earlier turn
+    #[serde(default)]
+    tool_calls: Vec<ToolCallMessage>,
+    /// Call a `tool` message answers
+    #[serde(default)]
+    tool_call_id: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ToolCallMessage {
+    id: String,
+    #[serde(rename = "type", default = "function_type")]
+    kind: String,
+    function: FunctionCall,
+}
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+#[derive(Deserialize, Serialize)]
+struct FunctionCall {
+    name: String,
+    /// JSON-encoded arguments, as in the OpenAI API
+    arguments: String,
+}
+
+#[derive(Deserialize)]
+struct ToolSpec {
+    #[serde(rename = "type")]
+    kind: String,
+    function: FunctionSpec,
+}
+
+#[derive(Deserialize)]
+struct FunctionSpec {
+    name: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default = "tools::default_parameters")]
+    parameters: serde_json::Value,
+}
+
+/// `tool_choice`: "none", "auto", "required" or `{"type": "function", "function": {"name": ...}}`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ToolChoiceSpec {
+    Mode(String),
+    Named { function: NamedFunction },
+}
+
+#[derive(Deserialize)]
+struct NamedFunction {
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct CompletionRequest {
+    messages: Vec<Message>,
+    #[serde(default)]
+    model: Option<String>,
+    /// Preset whose system prompt, examples, tools and retrieval are used
+    #[serde(default)]
+    preset: Option<String>,
+    /// Client tools; their calls are returned in `tool_calls`
+    #[serde(default)]
+    tools: Vec<ToolSpec>,
+    #[serde(default)]
+    tool_choice: Option<ToolChoiceSpec>,
+    #[serde(default, alias = "max_completion_tokens")]
+    max_tokens: Option<usize>,
+    #[serde(default)]
+    temperature: Option<f32>,
+    #[serde(default)]
+    top_p: Option<f32>,
+    #[serde(default)]
+    seed: Option<u32>,
+    #[serde(default)]
+    logprobs: bool,
+    #[serde(default)]
+    top_logprobs: Option<usize>,
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ChoiceFinishReason {
+    Stop,
+    Length,
+    ToolCalls,
+    Cancelled,
+    Timeout,
+}
+
+impl From<FinishReason> for ChoiceFinishReason {
+    fn from(reason: FinishReason) -> Self {
+        match reason {
+            FinishReason::Stop => ChoiceFinishReason::Stop,
+            FinishReason::Length => ChoiceFinishReason::Length,
+            FinishReason::Cancelled => ChoiceFinishReason::Cancelled,
+            FinishReason::Timeout => ChoiceFinishReason::Timeout,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ResponseMessage {
+    role: &'static str,
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCallMessage>,
+}
+
+#[derive(Serialize)]
+struct CompletionChoice {
+    index: usize,
+    message: ResponseMessage,
+    /// `null` unless the request asked for logprobs
+    logprobs: Option<Logprobs>,
+    finish_reason: ChoiceFinishReason,
+}
+
+#[derive(Serialize)]
+struct CompletionUsage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    total_tokens: usize,
+}
+
+#[derive(Serialize)]
+struct CompletionResponse {
+    id: String,
+    object: &'static str,
+    created: i64,
+    model: String,
+    choices: Vec<CompletionChoice>,
+    usage: CompletionUsage,
+}
+
+/// Client messages as (role, content) turns for the chat template
+struct Conversation {
+    /// Contents of system and developer messages
+    system: Vec<String>,
+    turns: Vec<(String, String)>,
+    /// Index in `turns` of the latest user message
+    last_user: Option<usize>,
+}
+
+impl Conversation {
+    fn last_user_text(&self) -> &str {
+        self.last_user.map_or("", |i| &self.turns[i].1)
+    }
+}
+
+fn content_text(content: &Option<MessageContent>) -> String {
+    match content {
+        None => String::new(),
+        Some(MessageContent::Text(text)) => text.clone(),
+        Some(MessageContent::Parts(parts)) => parts.iter()
+            .filter_map(|p| p.text.as_deref())
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+/// Converts OpenAI messages; tool calls and results become text turns in the same
+/// format the model is asked to use
+fn parse_messages(messages: &[Message]) -> Result<Conversation, String> {
+    let mut conversation = Conversation { system: Vec::new(), turns: Vec::new(), last_user: None };
+    // Tool names by call id, for tool messages without `name`
+    let mut call_names: HashMap<&str, &str> = HashMap::new();
+
+    for (i, message) in messages.iter().enumerate() {
+        let content = content_text(&message.content);
+        match message.role.as_str() {
+            "system" | "developer" => conversation.system.push(content),
+            "user" => {
+                conversation.last_user = Some(conversation.turns.len());
+                conversation.turns.push(("user".to_string(), content));
+            }
+            "assistant" => {
+                let mut parts = Vec::new();
+                if !content.is_empty() {
+                    parts.push(content);
+                }
+                for call in &message.tool_calls {
+                    let arguments = serde_json::from_str(&call.function.arguments)
+                        .map_err(|e| format!("messages[{}]: tool call arguments are not valid JSON: {}", i, e))?;
+                    call_names.insert(&call.id, &call.function.name);
+                    parts.push(ToolCall { id: call.id.clone(), name: call.function.name.clone(), arguments }.to_text());
+                }
+                conversation.turns.push(("assistant".to_string(), parts.join("\n")));
+            }
+            "tool" => {
+                let name = message.name.as_deref()
+                    .or_else(|| message.tool_call_id.as_deref().and_then(|id| call_names.get(id).copied()))
+                    .ok_or_else(|| format!("messages[{}]: tool message without a known tool_call_id", i))?;
+                conversation.turns.push(("user".to_string(), tools::result_text(name, &content)));
+            }
+            other => return Err(format!("messages[{}]: unknown role '{}'", i, other)),
+        }
+    }
+    if conversation.turns.is_empty() {
+        return Err("'messages' must contain a user message".to_string());
+    }
+    Ok(conversation)
+}
+
+/// Preset tools followed by the client's tools
+fn collect_tools(preset: Option<&Preset>, specs: &[ToolSpec]) -> Result<Vec<ToolDefinition>, String> {
+    let mut tools: Vec<ToolDefinition> = preset.map(|p| p.tools.clone()).unwrap_or_default();
+    for spec in specs {
+        if spec.kind != "function" {
+            return Err(format!("Unsupported tool type '{}'", spec.kind));
+        }
+        tools.push(ToolDefinition {
+            name: spec.function.name.clone(),
+            description: spec.function.description.clone(),
+            parameters: spec.function.parameters.clone(),
+            exec: None,
+        });
+    }
+    tools::validate(&tools).map_err(|e| e.to_string())?;
+    // With tools, an answer starting with `{` must be a call, so a JSON answer cannot be produced
+    if let Some(preset) = preset.filter(|p| !tools.is_empty() && p.expects_json_answer()) {
+        return Err(format!("Preset '{}' answers with JSON (output_parser: json), which cannot be combined with tools", preset.name));
+    }
+    Ok(tools)
+}
+
+fn parse_tool_choice(spec: Option<&ToolChoiceSpec>, tools: &[ToolDefinition]) -> Result<ToolChoice, String> {
+    let choice = match spec {
+        None => ToolChoice::Auto,
+        Some(ToolChoiceSpec::Mode(mode)) => match mode.as_str() {
+            "none" => ToolChoice::None,
+            "auto" => ToolChoice::Auto,
+            "required" => ToolChoice::Required,
+            other => return Err(format!("Unknown tool_choice '{}': use none, auto, required or a function", other)),
+        },
+        Some(ToolChoiceSpec::Named { function }) => ToolChoice::Function(function.name.clone()),
+    };
+    match &choice {
+        ToolChoice::Required if tools.is_empty() => Err("tool_choice 'required' needs tools".to_string()),
+        ToolChoice::Function(name) if !tools.iter().any(|t| &t.name == name) => {
+            Err(format!("tool_choice names unknown tool '{}'", name))
+        }
+        _ => Ok(choice),
+    }
+}
+
+/// Everything the worker needs besides the resolved preset and model
+struct CompletionInput {
+    conversation: Conversation,
+    tools: Vec<ToolDefinition>,
+    choice: ToolChoice,
+    max_tokens: Option<usize>,
+    sampling: SamplingParams,
+    /// Alternatives per token when logprobs are requested
+    logprobs: Option<usize>,
+}
+
+/// Outcome of a request after all local tool calls
+struct Completion {
+    /// Last generation, with token counts summed over all rounds
+    generation: Generation,
+    /// Calls the client has to execute
+    tool_calls: Vec<ToolCall>,
+    /// Preset tool calls executed on the server
+    executed: usize,
+}
+
+/// System message (preset, client, tools), preset examples and the conversation.
+/// Retrieval context goes in front of the latest user message.
+fn initial_messages(state: &AppState, preset: Option<&Preset>, input: &CompletionInput) -> anyhow::Result<Vec<(String, String)>> {
+    let mut system = Vec::new();
+    if let Some(text) = preset.map(|p| p.system_text(false)).filter(|t| !t.is_empty()) {
+        system.push(text);
+    }
+    system.extend(input.conversation.system.iter().filter(|s| !s.is_empty()).cloned());
+    system.extend(tools::system_text(&input.tools));
+
+    let mut messages = Vec::new();
+    if !system.is_empty() {
+        messages.push(("system".to_string(), system.join("\n\n")));
+    }
+    messages.extend(preset.map(Preset::example_messages).unwrap_or_default());
+
+    let mut turns = input.conversation.turns.clone();
+    if let (Some(preset), Some(i)) = (preset, input.conversation.last_user) {
+        if let Some(context) = state.retriever.context(&state.models, &state.backend, preset, &turns[i].1)? {
+            turns[i].1 = format!("{}\n\n{}", context, turns[i].1);
+        }
+    }
+    messages.extend(turns);
+    Ok(messages)
+}
+
+/// Generates answers until the model replies with text or calls a tool the client has to run
+fn complete(
+    state: &AppState,
+    resolved: &ResolvedRequest,
+    input: &CompletionInput,
+    token: &CancelToken,
+    rendered_prompt: &mut Option<String>,
+) -> anyhow::Result<Completion> {
+    let model = state.models.get_or_load(&state.backend, &resolved.model_name)?;
+    let mut ctx = engine::new_context(&model, &state.backend)?;
+    // Every round repeats the previous prompt, so its KV cache is reused
+    let mut prompt_cache = PromptCache::default();
+    let mut messages = initial_messages(state, resolved.preset.as_ref(), input)?;
+
+    let mut params = resolved.preset.as_ref().map_or_else(GenerationParams::default, GenerationParams::from_preset);
+    if let Some(max_tokens) = input.max_tokens {
+        params.max_tokens = max_tokens;
+    }
+    params.sampling = params.sampling.merge(&input.sampling);
+    // Classification presets generate text here: the answer may be a tool call
+    params.labels.clear();
+    params.logprobs = input.logprobs;
+    cap_max_time(state, &mut params);
+    // The time limit covers the whole request: every round and tool call share one budget
+    let deadline = params.max_time.map(|limit| Instant::now() + limit);
+
+    let mut choice = input.choice.clone();
+    let mut prompt_tokens = 0;
+    let mut completion_tokens = 0;
+    let mut executed = 0;
+    loop {
+        if executed == MAX_TOOL_ROUNDS {
+            choice = ToolChoice::None;
+        }
+        let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        if remaining.is_some_and(|r| r.is_zero()) {
+            let mut generation = Generation::cancelled();
+            generation.finish_reason = FinishReason::Timeout;
+            generation.prompt_tokens = prompt_tokens;
+            generation.completion_tokens = completion_tokens;
+            return Ok(Completion { generation, tool_calls: Vec::new(), executed });
+        }
+        params.max_time = remaining;
+        params.grammar = tools::grammar(&input.tools, &choice);
+        let prompt = engine::render_chat(&model, messages.clone())?;
+        let mut generation = engine::generate_cached(&model, &mut ctx, &mut prompt_cache, &prompt, &params, &mut |_, _| !token.is_cancelled())?;
+        *rendered_prompt = Some(prompt);
+        state.metrics.record_generation(&resolved.model_name, &generation);
+        prompt_tokens += generation.prompt_tokens;
+        completion_tokens += generation.completion_tokens;
+
+        let call = (generation.finish_reason == FinishReason::Stop)
+            .then(|| tools::parse_call(&generation.text, &input.tools))
+            .flatten();
+        let local = call.as_ref()
+            .and_then(|c| input.tools.iter().find(|t| t.name == c.name && t.exec.is_some()));
+        match (call, local) {
+            (Some(call), Some(tool)) => {
+                let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+                let output = tools::execute(tool, &call.arguments, remaining);
+                messages.push(("assistant".to_string(), call.to_text()));
+                messages.push(("user".to_string(), tools::result_text(&call.name, &output)));
+                executed += 1;
+                // tool_choice only applies to the first answer
+                choice = ToolChoice::Auto;
+            }
+            (call, _) => {
+                generation.prompt_tokens = prompt_tokens;
+                generation.completion_tokens = completion_tokens;
+                return Ok(Completion { generation, tool_calls: call.into_iter().collect(), executed });
+            }
+        }
+    }
+}
+
+/// Runs a completion on the calling (worker) thread and writes the audit record
+fn run_completion(
+    state: &AppState,
+    source: &Source,
+    resolved: &ResolvedRequest,
+    input: &CompletionInput,
+    token: &CancelToken,
+) -> anyhow::Result<Completion> {
+    let started = Instant::now();
+    let mut rendered_prompt = None;
+    let result = complete(state, resolved, input, token, &mut rendered_prompt);
+    state.audit.record(Exchange {
+        source,
+        model: &resolved.model_name,
+        preset: resolved.preset.as_ref(),
+        input: input.conversation.last_user_text(),
+        rendered_prompt: rendered_prompt.as_deref(),
+        result: result.as_ref().map(|c| &c.generation),
+        latency: started.elapsed(),
+    });
+    if let Ok(completion) = &result {
+        info!(
+            model = %resolved.model_name,
+            preset = resolved.preset.as_ref().map(|p| p.name.as_str()).unwrap_or_default(),
+            prompt_tokens = completion.generation.prompt_tokens,
+            completion_tokens = completion.generation.completion_tokens,
+            tools_executed = completion.executed,
+            tool_calls = completion.tool_calls.len(),
+            "chat completion finished"
+        );
+    }
+    result
+}
+
+/// POST /v1/chat/completions (OpenAI-compatible)
+pub async fn chat_completions_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(key): Extension<Arc<ApiKey>>,
+    Extension(caller): Extension<Caller>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Json(req): Json<CompletionRequest>,
+) -> Response {
+    if req.stream {
+        return ErrorResponse::new(StatusCode::BAD_REQUEST, "Streaming is not supported by /v1/chat/completions, use /chat with \"stream\": true");
+    }
+    let conversation = match parse_messages(&req.messages) {
+        Ok(c) => c,
+        Err(message) => return ErrorResponse::new(StatusCode::BAD_REQUEST, message),
+    };
+    if let Err(response) = key.authorize_requested(req.model.as_deref(), req.preset.as_deref()) {
+        return response;
+    }
+    let resolved = match resolve_request(&state, req.preset.as_deref(), req.model.as_deref()) {
+        Ok(r) => r,
+        Err(message) => return ErrorResponse::new(StatusCode::BAD_REQUEST, message),
+    };
+    if let Err(response) = key.authorize(&resolved.model_name, req.preset.as_deref()) {
+        return response;
+    }
+    let labels = RequestLabels::new(&resolved.model_name, req.preset.as_deref());
+
+    let tools = match collect_tools(resolved.preset.as_ref(), &req.tools) {
+        Ok(t) => t,
+        Err(message) => return labels.apply(ErrorResponse::new(StatusCode::BAD_REQUEST, message)),
+    };
+    let choice = match parse_tool_choice(req.tool_choice.as_ref(), &tools) {
+        Ok(c) => c,
+        Err(message) => return labels.apply(ErrorResponse::new(StatusCode::BAD_REQUEST, message)),
+    };
+    let logprobs = match check_logprobs(req.logprobs, req.top_logprobs) {
+        Ok(l) => l,
+        Err(message) => return labels.apply(ErrorResponse::new(StatusCode::BAD_REQUEST, message)),
+    };
+    let input = CompletionInput {
+        conversation,
+        tools,
+        choice,
+        max_tokens: req.max_tokens,
+        sampling: SamplingParams {
+            temperature: req.temperature,
+            top_p: req.top_p,
+            seed: req.seed,
+            ..SamplingParams::default()
+        },
+        logprobs,
+    };
+
+    let token = CancelToken::default();
+    let _cancel_on_drop = CancelOnDrop(token.clone());
+    let source = Source::new("/v1/chat/completions", &request_id, &key.name);
+    let worker_state = Arc::clone(&state);
+    let result = state.queue.run(move || {
+        let result = run_completion(&worker_state, &source, &resolved, &input, &token);
+        if let Ok(completion) = &result {
+            worker_state.limits.record_tokens(&caller, completion.generation.completion_tokens);
+        }
+        (resolved, result)
+    }).await;
+
+    let response = match result {
+        Ok((resolved, Ok(completion))) => {
+            let generation = completion.generation;
+            let (content, finish_reason) = if completion.tool_calls.is_empty() {
+                (Some(generation.text), ChoiceFinishReason::from(generation.finish_reason))
+            } else {
+                (None, ChoiceFinishReason::ToolCalls)
+            };
+            (StatusCode::OK, Json(CompletionResponse {
+                id: format!("chatcmpl-{}", uuid::Uuid::new_v4().simple()),
+                object: "chat.completion",
+                created: chrono::Utc::now().timestamp(),
+                model: resolved.model_name,
+                choices: vec![CompletionChoice {
+                    index: 0,
+                    message: ResponseMessage {
+                        role: "assistant",
+                        content,
+                        tool_calls: completion.tool_calls.into_iter()
+                            .map(|call| ToolCallMessage {
+                                id: call.id,
+                                kind: function_type(),
+                                function: FunctionCall { name: call.name, arguments: call.arguments.to_string() },
+                            })
+                            .collect(),
+                    },
+                    logprobs: generation.logprobs,
+                    finish_reason,
+                }],
+                usage: CompletionUsage {
+                    prompt_tokens: generation.prompt_tokens,
+                    completion_tokens: generation.completion_tokens,
+                    total_tokens: generation.prompt_tokens + generation.completion_tokens,
+                },
+            })).into_response()
+        }
+        Ok((_, Err(e))) => ErrorResponse::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)),
+        Err(e) => ErrorResponse::new(queue_error_status(&e), e.to_string()),
+    };
+    labels.apply(response)
+}
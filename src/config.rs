@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/// Server configuration file, read when present
+pub const CONFIG_FILE: &str = "config.json";
+
+/// Server settings from `config.json`. Every section is optional.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub queue: QueueConfig,
+    pub jobs: JobsConfig,
+    pub generation: GenerationConfig,
+    pub auth: AuthConfig,
+    pub limits: LimitsConfig,
+    pub audit: AuditConfig,
+    pub cache: CacheConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Addresses to serve on: `IP:port`, `[IPv6]:port` or `unix:/path/to.sock`
+    pub listen: Vec<String>,
+    /// Serve HTTPS on TCP addresses when set
+    pub tls: Option<TlsConfig>,
+    pub cors: CorsConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec!["127.0.0.1:3000".to_string()],
+            tls: None,
+            cors: CorsConfig::default(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain
+    pub cert: String,
+    /// PEM private key
+    pub key: String,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API from a browser; `"*"` allows any origin
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self { allowed_origins: vec!["*".to_string()] }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct QueueConfig {
+    /// Number of worker threads running generations in parallel
+    pub workers: usize,
+    /// Maximum number of waiting tasks before requests are rejected with 503
+    pub capacity: usize,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self { workers: 1, capacity: 256 }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct JobsConfig {
+    /// How long finished jobs (and their results) are kept, in seconds
+    pub retention_secs: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self { retention_secs: 3600 }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct GenerationConfig {
+    /// Upper bound for `max_time_ms` of any request or preset; also applies when neither sets one
+    pub max_time_ms: Option<u64>,
+}
+
+/// API keys; with no keys configured the server accepts every request
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub keys: Vec<ApiKeyConfig>,
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeyConfig {
+    /// Name shown in errors and logs instead of the key
+    pub name: String,
+    /// SHA-256 of the key in hex, see `chat-np hash-key`
+    pub key_sha256: String,
+    /// Model name fragments the key may use (empty means any model)
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// Preset names the key may use (empty means any preset)
+    #[serde(default)]
+    pub presets: Vec<String>,
+    /// Access to /presets/status, /presets/reload, /audit and jobs of other keys
+    #[serde(default)]
+    pub admin: bool,
+    /// Overrides `limits.requests_per_minute` for this key
+    #[serde(default)]
+    pub requests_per_minute: Option<u32>,
+    /// Overrides `limits.tokens_per_day` for this key
+    #[serde(default)]
+    pub tokens_per_day: Option<u64>,
+}
+
+/// Default limits per API key (or per client IP when auth is off); unset means unlimited
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub requests_per_minute: Option<u32>,
+    /// Generated (completion) tokens per calendar day
+    pub tokens_per_day: Option<u64>,
+}
+
+/// Response cache for greedy requests; off while `max_entries` is 0
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Cached responses kept in memory; the least recently used one is evicted first
+    pub max_entries: usize,
+    /// How long a cached response is reused, in seconds
+    pub ttl_secs: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self { max_entries: 0, ttl_secs: 3600 }
+    }
+}
+
+/// Audit log of generations; off unless `path` is set
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    /// JSONL file the records are appended to
+    pub path: Option<String>,
+    /// The file is rotated once it grows beyond this size
+    pub max_file_mb: u64,
+    /// Rotated files kept next to the current one (`audit.jsonl.1`, `.2`, ...)
+    pub max_files: usize,
+    pub redact: RedactConfig,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_file_mb: 64,
+            max_files: 10,
+            redact: RedactConfig::default(),
+        }
+    }
+}
+
+/// What is kept out of the audit log; the rendered prompt hash is always stored
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct RedactConfig {
+    /// Do not store user inputs
+    pub omit_inputs: bool,
+    /// Do not store model outputs
+    pub omit_outputs: bool,
+    /// Presets whose inputs and outputs are never stored
+    pub presets: Vec<String>,
+    /// Regular expressions replaced with `[REDACTED]` in stored texts
+    pub patterns: Vec<String>,
+}
+
+impl Config {
+    /// Loads the config from an explicit path, or from `config.json` if it exists
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let path = match path {
+            Some(p) => p,
+            None if Path::new(CONFIG_FILE).is_file() => CONFIG_FILE,
+            None => return Ok(Self::default()),
+        };
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("не удалось прочитать {}", path))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("ошибка парсинга {}", path))
+    }
+}
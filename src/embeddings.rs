@@ -0,0 +1,313 @@
+use anyhow::{bail, Context, Result};
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::Engine as _;
+use llama_cpp_2::{
+    context::params::{LlamaContextParams, LlamaPoolingType},
+    context::LlamaContext,
+    llama_backend::LlamaBackend,
+    llama_batch::LlamaBatch,
+    model::{AddBos, LlamaModel},
+};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::Arc;
+
+use crate::auth::ApiKey;
+use crate::batch;
+use crate::cli::CliArgs;
+use crate::engine::{self, N_CTX};
+use crate::metrics::RequestLabels;
+use crate::server::{queue_error_status, resolve_request, AppState, ErrorResponse};
+
+/// Upper bound for inputs in a single /v1/embeddings request
+const MAX_EMBEDDING_INPUTS: usize = 2048;
+
+/// How token embeddings are combined into one vector per input
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Pooling {
+    /// Average of the token embeddings; works with any model
+    #[default]
+    Mean,
+    /// Whatever the GGUF metadata specifies. Generative models usually declare no pooling,
+    /// so this only works with dedicated embedding models.
+    Model,
+    /// Embedding of the first token
+    Cls,
+    /// Embedding of the last token
+    Last,
+}
+
+impl Pooling {
+    pub fn parse(value: &str) -> Result<Self> {
+        Ok(match value {
+            "mean" => Pooling::Mean,
+            "model" => Pooling::Model,
+            "cls" => Pooling::Cls,
+            "last" => Pooling::Last,
+            other => bail!("неизвестный способ пулинга: {} (поддерживаются mean, model, cls и last)", other),
+        })
+    }
+
+    fn llama_type(self) -> LlamaPoolingType {
+        match self {
+            Pooling::Model => LlamaPoolingType::Unspecified,
+            Pooling::Mean => LlamaPoolingType::Mean,
+            Pooling::Cls => LlamaPoolingType::Cls,
+            Pooling::Last => LlamaPoolingType::Last,
+        }
+    }
+}
+
+pub struct Embeddings {
+    pub vectors: Vec<Vec<f32>>,
+    pub prompt_tokens: usize,
+}
+
+fn new_embedding_context<'a>(model: &'a LlamaModel, backend: &LlamaBackend, pooling: Pooling) -> Result<LlamaContext<'a>> {
+    // Non-causal embedding models need the whole input in a single micro-batch
+    let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(std::num::NonZero::new(N_CTX))
+        .with_n_batch(N_CTX)
+        .with_n_ubatch(N_CTX)
+        .with_embeddings(true)
+        .with_pooling_type(pooling.llama_type());
+    model.new_context(backend, ctx_params)
+        .context("Failed to create embedding context")
+}
+
+fn normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+}
+
+/// Embeds every input with the given pooling; vectors are L2-normalised when `normalize` is set
+pub fn embed(
+    model: &LlamaModel,
+    backend: &LlamaBackend,
+    inputs: &[String],
+    pooling: Pooling,
+    normalize_vectors: bool,
+) -> Result<Embeddings> {
+    let mut ctx = new_embedding_context(model, backend, pooling)?;
+    let mut batch = LlamaBatch::new(N_CTX as usize, 1);
+    let mut vectors = Vec::with_capacity(inputs.len());
+    let mut prompt_tokens = 0;
+
+    for (index, input) in inputs.iter().enumerate() {
+        let tokens = model.str_to_token(input, AddBos::Always)
+            .with_context(|| format!("Failed to tokenize input {}", index))?;
+        if tokens.len() > N_CTX as usize {
+            bail!("Input {} has {} tokens, more than the context size {}", index, tokens.len(), N_CTX);
+        }
+        prompt_tokens += tokens.len();
+
+        // One sequence per decode keeps the context small and independent of the number of inputs
+        ctx.clear_kv_cache();
+        batch.clear();
+        batch.add_sequence(&tokens, 0, false)?;
+        ctx.decode(&mut batch)
+            .with_context(|| format!("Failed to embed input {}", index))?;
+        let mut vector = ctx.embeddings_seq_ith(0)
+            .with_context(|| match pooling {
+                Pooling::Model => format!("No embedding for input {}: the model declares no pooling, use 'mean'", index),
+                _ => format!("No embedding for input {}", index),
+            })?
+            .to_vec();
+        if normalize_vectors {
+            normalize(&mut vector);
+        }
+        vectors.push(vector);
+    }
+    Ok(Embeddings { vectors, prompt_tokens })
+}
+
+/// `input` of an OpenAI embeddings request: one string or a list
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+pub struct EmbeddingRequest {
+    input: EmbeddingInput,
+    #[serde(default)]
+    model: Option<String>,
+    /// `float` (default) or `base64` (little-endian f32, as OpenAI clients request by default)
+    #[serde(default)]
+    encoding_format: Option<String>,
+    #[serde(default)]
+    pooling: Pooling,
+    #[serde(default = "default_true")]
+    normalize: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum EmbeddingVector {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+#[derive(Serialize)]
+struct EmbeddingData {
+    object: &'static str,
+    index: usize,
+    embedding: EmbeddingVector,
+}
+
+#[derive(Serialize)]
+struct EmbeddingUsage {
+    prompt_tokens: usize,
+    total_tokens: usize,
+}
+
+#[derive(Serialize)]
+struct EmbeddingResponse {
+    object: &'static str,
+    data: Vec<EmbeddingData>,
+    model: String,
+    usage: EmbeddingUsage,
+}
+
+fn to_base64(vector: &[f32]) -> String {
+    let bytes: Vec<u8> = vector.iter().flat_map(|v| v.to_le_bytes()).collect();
+    base64::engine::general_purpose::STANDARD.encode(bytes)
+}
+
+/// POST /v1/embeddings (OpenAI-compatible)
+pub async fn embeddings_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(key): Extension<Arc<ApiKey>>,
+    Json(req): Json<EmbeddingRequest>,
+) -> Response {
+    let inputs = match req.input {
+        EmbeddingInput::One(text) => vec![text],
+        EmbeddingInput::Many(texts) => texts,
+    };
+    if inputs.is_empty() || inputs.len() > MAX_EMBEDDING_INPUTS {
+        return ErrorResponse::new(StatusCode::BAD_REQUEST, format!("'input' must contain from 1 to {} items", MAX_EMBEDDING_INPUTS));
+    }
+    let as_base64 = match req.encoding_format.as_deref() {
+        None | Some("float") => false,
+        Some("base64") => true,
+        Some(other) => return ErrorResponse::new(StatusCode::BAD_REQUEST, format!("Unknown encoding_format '{}': use float or base64", other)),
+    };
+
+    if let Err(response) = key.authorize_requested(req.model.as_deref(), None) {
+        return response;
+    }
+    let resolved = match resolve_request(&state, None, req.model.as_deref()) {
+        Ok(r) => r,
+        Err(message) => return ErrorResponse::new(StatusCode::BAD_REQUEST, message),
+    };
+    if let Err(response) = key.authorize(&resolved.model_name, None) {
+        return response;
+    }
+    let labels = RequestLabels::new(&resolved.model_name, None);
+
+    let worker_state = Arc::clone(&state);
+    let model_name = resolved.model_name.clone();
+    let result = state.queue.run(move || {
+        let model = worker_state.models.get_or_load(&worker_state.backend, &model_name)?;
+        embed(&model, &worker_state.backend, &inputs, req.pooling, req.normalize)
+    }).await;
+
+    let response = match result {
+        Ok(Ok(embeddings)) => (StatusCode::OK, Json(EmbeddingResponse {
+            object: "list",
+            data: embeddings.vectors.into_iter()
+                .enumerate()
+                .map(|(index, vector)| EmbeddingData {
+                    object: "embedding",
+                    index,
+                    embedding: if as_base64 { EmbeddingVector::Base64(to_base64(&vector)) } else { EmbeddingVector::Float(vector) },
+                })
+                .collect(),
+            model: resolved.model_name,
+            usage: EmbeddingUsage {
+                prompt_tokens: embeddings.prompt_tokens,
+                total_tokens: embeddings.prompt_tokens,
+            },
+        })).into_response(),
+        Ok(Err(e)) => ErrorResponse::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)),
+        Err(e) => ErrorResponse::new(queue_error_status(&e), e.to_string()),
+    };
+    labels.apply(response)
+}
+
+#[derive(Serialize)]
+struct EmbedOutput<'a> {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a str>,
+    input: &'a str,
+    embedding: &'a [f32],
+}
+
+/// `chat-np embed (--text TEXT | --input FILE [--input-format jsonl|csv|lines] [--field prompt] [--column NAME])
+/// [--model FILE] [--pooling mean|model|cls|last] [--no-normalize] [--output FILE]`
+///
+/// Writes one JSON line per input with its embedding to `--output` or stdout.
+pub fn run_command(backend: &LlamaBackend, available_models: &[String], cli: &CliArgs) -> Result<()> {
+    let records = match (cli.value("text"), cli.value("input")) {
+        (Some(text), _) => vec![batch::InputRecord { index: 0, id: None, input: text.to_string() }],
+        (None, Some(path)) => batch::read_inputs(path, cli)?,
+        (None, None) => bail!("укажите --text или --input"),
+    };
+    if records.is_empty() {
+        bail!("нет входных текстов");
+    }
+
+    let model_name = cli.value("model")
+        .map(str::to_string)
+        .or_else(|| available_models.first().cloned())
+        .unwrap_or_default();
+    if !available_models.contains(&model_name) {
+        bail!("модель '{}' не найдена. Доступные модели: {:?}", model_name, available_models);
+    }
+    let pooling = Pooling::parse(cli.value("pooling").unwrap_or("mean"))?;
+
+    eprintln!("Загрузка модели {}...", model_name);
+    let model = engine::load_model(backend, &model_name)?;
+    let inputs: Vec<String> = records.iter().map(|r| r.input.clone()).collect();
+    let embeddings = embed(&model, backend, &inputs, pooling, !cli.flag("no-normalize"))?;
+
+    let mut out: Box<dyn Write> = match cli.value("output") {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).with_context(|| format!("не удалось создать {}", path))?,
+        )),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    for (record, vector) in records.iter().zip(&embeddings.vectors) {
+        serde_json::to_writer(&mut out, &EmbedOutput {
+            index: record.index,
+            id: record.id.as_deref(),
+            input: &record.input,
+            embedding: vector,
+        })?;
+        writeln!(out)?;
+    }
+    out.flush()?;
+    eprintln!(
+        "Готово: {} векторов размерности {}, токенов {}",
+        embeddings.vectors.len(),
+        embeddings.vectors.first().map_or(0, Vec::len),
+        embeddings.prompt_tokens
+    );
+    Ok(())
+}
@@ -0,0 +1,610 @@
+use anyhow::{bail, Context, Result};
+use llama_cpp_2::{
+    context::params::LlamaContextParams,
+    context::LlamaContext,
+    llama_backend::LlamaBackend,
+    llama_batch::LlamaBatch,
+    model::params::LlamaModelParams,
+    model::{AddBos, LlamaChatMessage, LlamaModel, Special},
+    sampling::LlamaSampler,
+    token::LlamaToken,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, debug_span};
+
+use crate::presets::{Preset, PromptFormat};
+
+/// Context size used for every generation
+pub const N_CTX: u32 = 2048;
+/// Default token limit for requests without a preset
+pub const DEFAULT_MAX_TOKENS: usize = 100;
+/// llama.cpp treats this seed as "pick a random seed"
+const RANDOM_SEED: u32 = u32::MAX;
+/// How many recent tokens the repeat penalty looks at
+const PENALTY_LAST_N: i32 = 64;
+/// Upper bound for alternatives per token in `logprobs` (same as OpenAI)
+pub const MAX_TOP_LOGPROBS: usize = 20;
+
+/// Sampling settings. Without a positive `temperature` decoding is greedy.
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SamplingParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u32>,
+}
+
+impl SamplingParams {
+    /// Returns `self` with every value set in `other` replaced
+    pub fn merge(self, other: &SamplingParams) -> SamplingParams {
+        SamplingParams {
+            temperature: other.temperature.or(self.temperature),
+            top_k: other.top_k.or(self.top_k),
+            top_p: other.top_p.or(self.top_p),
+            min_p: other.min_p.or(self.min_p),
+            repeat_penalty: other.repeat_penalty.or(self.repeat_penalty),
+            seed: other.seed.or(self.seed),
+        }
+    }
+
+    pub fn is_greedy(&self) -> bool {
+        self.temperature.map_or(true, |t| t <= 0.0)
+    }
+
+    pub fn sampler(&self) -> LlamaSampler {
+        let mut chain = Vec::new();
+        if let Some(penalty) = self.repeat_penalty {
+            chain.push(LlamaSampler::penalties(PENALTY_LAST_N, penalty, 0.0, 0.0));
+        }
+        match self.temperature {
+            Some(temperature) if temperature > 0.0 => {
+                if let Some(k) = self.top_k {
+                    chain.push(LlamaSampler::top_k(k));
+                }
+                if let Some(p) = self.top_p {
+                    chain.push(LlamaSampler::top_p(p, 1));
+                }
+                if let Some(p) = self.min_p {
+                    chain.push(LlamaSampler::min_p(p, 1));
+                }
+                chain.push(LlamaSampler::temp(temperature));
+                chain.push(LlamaSampler::dist(self.seed.unwrap_or(RANDOM_SEED)));
+            }
+            _ => chain.push(LlamaSampler::greedy()),
+        }
+        LlamaSampler::chain_simple(chain)
+    }
+}
+
+/// Limits and stop conditions for a single generation
+#[derive(Clone)]
+pub struct GenerationParams {
+    pub max_tokens: usize,
+    pub stop_on_newline: bool,
+    /// Stop as soon as the output is a complete JSON object
+    pub stop_on_json: bool,
+    /// Wall-clock limit counted from the start of prompt decoding
+    pub max_time: Option<Duration>,
+    pub sampling: SamplingParams,
+    /// GBNF grammar the output must follow (root rule `root`)
+    pub grammar: Option<String>,
+    /// Continuations scored instead of generating (classification mode); the best one is the output
+    pub labels: Vec<String>,
+    /// Record the log-probability of every generated token with this many top alternatives
+    pub logprobs: Option<usize>,
+}
+
+impl GenerationParams {
+    pub fn from_preset(preset: &Preset) -> Self {
+        Self {
+            max_tokens: preset.max_tokens,
+            stop_on_newline: preset.stop_on_newline,
+            stop_on_json: false,
+            max_time: preset.max_time_ms.map(Duration::from_millis),
+            sampling: preset.sampling.clone().unwrap_or_default(),
+            grammar: None,
+            labels: preset.classification.as_ref()
+                .map(|c| c.continuations(preset.prompt_format))
+                .unwrap_or_default(),
+            logprobs: None,
+        }
+    }
+
+    /// Lowers `max_time` to `cap` when one is set
+    pub fn cap_max_time(&mut self, cap: Option<Duration>) {
+        if let Some(cap) = cap {
+            self.max_time = Some(self.max_time.map_or(cap, |t| t.min(cap)));
+        }
+    }
+}
+
+impl Default for GenerationParams {
+    fn default() -> Self {
+        Self {
+            max_tokens: DEFAULT_MAX_TOKENS,
+            stop_on_newline: false,
+            stop_on_json: false,
+            max_time: None,
+            sampling: SamplingParams::default(),
+            grammar: None,
+            labels: Vec::new(),
+            logprobs: None,
+        }
+    }
+}
+
+/// Why a generation ended
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// End-of-generation token or a stop condition
+    Stop,
+    /// `max_tokens` or the context size was reached
+    Length,
+    /// Stopped by the caller
+    Cancelled,
+    /// `max_time` ran out
+    Timeout,
+}
+
+/// Normalised probability of one label in classification mode
+#[derive(Serialize, Clone, Debug)]
+pub struct LabelProbability {
+    pub label: String,
+    pub probability: f32,
+}
+
+/// An alternative token in `top_logprobs`
+#[derive(Serialize, Clone, Debug)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Vec<u8>,
+}
+
+/// A generated token with its log-probability, in the OpenAI `logprobs` shape
+#[derive(Serialize, Clone, Debug)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Vec<u8>,
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+/// `logprobs` of a response: one entry per generated token
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Logprobs {
+    pub content: Vec<TokenLogprob>,
+}
+
+pub struct Generation {
+    pub text: String,
+    pub prompt_tokens: usize,
+    /// Prompt tokens taken from the KV cache instead of being decoded
+    pub cached_tokens: usize,
+    pub completion_tokens: usize,
+    pub finish_reason: FinishReason,
+    /// Time spent decoding the prompt
+    pub prompt_eval_time: Duration,
+    /// Time spent in the token loop
+    pub generation_time: Duration,
+    /// Taken from the response cache instead of being generated
+    pub cache_hit: bool,
+    /// Label probabilities in classification mode, most probable first
+    pub probabilities: Vec<LabelProbability>,
+    /// Token log-probabilities when `GenerationParams::logprobs` is set
+    pub logprobs: Option<Logprobs>,
+}
+
+impl Generation {
+    /// Result of a request that was cancelled before generation started
+    pub fn cancelled() -> Self {
+        Self {
+            text: String::new(),
+            prompt_tokens: 0,
+            cached_tokens: 0,
+            completion_tokens: 0,
+            finish_reason: FinishReason::Cancelled,
+            prompt_eval_time: Duration::ZERO,
+            generation_time: Duration::ZERO,
+            cache_hit: false,
+            probabilities: Vec::new(),
+            logprobs: None,
+        }
+    }
+}
+
+pub fn load_model(backend: &LlamaBackend, path: &str) -> Result<LlamaModel> {
+    let model_params = LlamaModelParams::default().with_n_gpu_layers(0);
+    LlamaModel::load_from_file(backend, path, &model_params)
+        .with_context(|| format!("Failed to load model {}", path))
+}
+
+/// Models loaded once and shared between requests
+#[derive(Default)]
+pub struct ModelCache {
+    loaded: Mutex<HashMap<String, Arc<LlamaModel>>>,
+}
+
+impl ModelCache {
+    /// Names and tensor sizes in bytes of the loaded models
+    pub fn loaded_sizes(&self) -> Vec<(String, u64)> {
+        let loaded = self.loaded.lock().unwrap();
+        let mut sizes: Vec<(String, u64)> = loaded.iter()
+            .map(|(name, model)| (name.clone(), model.size()))
+            .collect();
+        sizes.sort();
+        sizes
+    }
+
+    /// Returns a loaded model, loading it on first use
+    pub fn get_or_load(&self, backend: &LlamaBackend, name: &str) -> Result<Arc<LlamaModel>> {
+        let mut loaded = self.loaded.lock().unwrap();
+        if let Some(model) = loaded.get(name) {
+            return Ok(Arc::clone(model));
+        }
+        let model = Arc::new(load_model(backend, name)?);
+        loaded.insert(name.to_string(), Arc::clone(&model));
+        Ok(model)
+    }
+}
+
+pub fn new_context<'a>(model: &'a LlamaModel, backend: &LlamaBackend) -> Result<LlamaContext<'a>> {
+    let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(std::num::NonZero::new(N_CTX));
+    model.new_context(backend, ctx_params)
+        .context("Failed to create context")
+}
+
+/// Renders the model input for a preset according to its `prompt_format`.
+/// `context` is the text found by the preset's `retrieval` section, if any.
+pub fn render_prompt(model: &LlamaModel, preset: &Preset, user_input: &str, context: Option<&str>) -> Result<String> {
+    match preset.prompt_format {
+        PromptFormat::Plain => Ok(preset.build_full_prompt(user_input, context)),
+        PromptFormat::Chat => render_chat(model, preset.build_chat_messages(user_input, context)),
+    }
+}
+
+/// Applies the model's built-in chat template to (role, content) messages
+/// and opens the assistant turn
+pub fn render_chat(model: &LlamaModel, messages: Vec<(String, String)>) -> Result<String> {
+    let template = model.chat_template(None)
+        .context("Model has no built-in chat template")?;
+    let messages = messages.into_iter()
+        .map(|(role, content)| LlamaChatMessage::new(role, content))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Invalid chat message")?;
+    model.apply_chat_template(&template, &messages, true)
+        .context("Failed to apply chat template")
+}
+
+/// Collects token bytes and only exposes complete UTF-8 text
+/// (a single character may be split across several tokens)
+#[derive(Default)]
+struct Utf8Buffer {
+    text: String,
+    pending: Vec<u8>,
+}
+
+impl Utf8Buffer {
+    fn push(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+        match std::str::from_utf8(&self.pending) {
+            Ok(s) => {
+                self.text.push_str(s);
+                self.pending.clear();
+            }
+            Err(e) => {
+                let valid = e.valid_up_to();
+                self.text.push_str(&String::from_utf8_lossy(&self.pending[..valid]));
+                if e.error_len().is_some() {
+                    // Invalid bytes, not just an incomplete sequence
+                    self.text.push_str(&String::from_utf8_lossy(&self.pending[valid..]));
+                    self.pending.clear();
+                } else {
+                    self.pending.drain(..valid);
+                }
+            }
+        }
+    }
+}
+
+fn is_complete_json_object(text: &str) -> bool {
+    let trimmed = text.trim();
+    if trimmed.starts_with('{') && trimmed.ends_with('}') {
+        // Check if it's a complete JSON by counting braces
+        let open_braces = trimmed.chars().filter(|&c| c == '{').count();
+        let close_braces = trimmed.chars().filter(|&c| c == '}').count();
+        return open_braces == close_braces && open_braces > 0;
+    }
+    false
+}
+
+/// Prompt tokens currently held in the KV cache of a context.
+///
+/// Consecutive prompts that share a prefix (e.g. the same preset) only decode
+/// the part after the longest common prefix.
+#[derive(Default)]
+pub struct PromptCache {
+    tokens: Vec<LlamaToken>,
+}
+
+/// Runs prompt decoding and the token loop on a fresh KV cache
+pub fn generate(
+    model: &LlamaModel,
+    ctx: &mut LlamaContext,
+    prompt: &str,
+    params: &GenerationParams,
+) -> Result<Generation> {
+    ctx.clear_kv_cache();
+    generate_cached(model, ctx, &mut PromptCache::default(), prompt, params, &mut |_, _| true)
+}
+
+/// Like [`generate`], but reuses the KV cache for the prefix shared with the previous prompt.
+///
+/// `on_token` is called after every generated token with the text so far and the
+/// number of generated tokens; returning `false` stops generation as cancelled.
+pub fn generate_cached(
+    model: &LlamaModel,
+    ctx: &mut LlamaContext,
+    cache: &mut PromptCache,
+    prompt: &str,
+    params: &GenerationParams,
+    on_token: &mut dyn FnMut(&str, usize) -> bool,
+) -> Result<Generation> {
+    let started = Instant::now();
+    let deadline = params.max_time.map(|limit| started + limit);
+    let tokens = {
+        let _span = debug_span!("tokenize", prompt_bytes = prompt.len()).entered();
+        let tokens = model.str_to_token(prompt, AddBos::Always)
+            .context("Tokenization error")?;
+        debug!(tokens = tokens.len(), "prompt tokenized");
+        tokens
+    };
+    if tokens.is_empty() {
+        bail!("Empty prompt");
+    }
+    let n_ctx = ctx.n_ctx() as usize;
+    if tokens.len() >= n_ctx {
+        bail!("Prompt is too long: {} tokens, context size is {}", tokens.len(), n_ctx);
+    }
+
+    // At least the last prompt token is decoded again to get fresh logits
+    let reused = cache.tokens.iter()
+        .zip(tokens.iter())
+        .take_while(|(a, b)| a == b)
+        .count()
+        .min(tokens.len() - 1);
+    cache.tokens.clear();
+    ctx.clear_kv_cache_seq(Some(0), Some(reused as u32), None)
+        .context("KV cache error")?;
+
+    let mut batch = LlamaBatch::new((tokens.len() - reused).max(512), 1);
+    {
+        let _span = debug_span!("prompt_decode", tokens = tokens.len() - reused, cached = reused).entered();
+        let decode_started = Instant::now();
+        let last_index = tokens.len() - 1;
+        for (i, token) in tokens.iter().enumerate().skip(reused) {
+            let is_last = i == last_index;
+            batch.add(*token, i as i32, &[0], is_last).context("Batch error")?;
+        }
+        ctx.decode(&mut batch).context("Decode error")?;
+        debug!(elapsed_ms = decode_started.elapsed().as_millis() as u64, "prompt decoded");
+    }
+    cache.tokens = tokens.clone();
+    let prompt_eval_time = started.elapsed();
+
+    if !params.labels.is_empty() {
+        let probabilities = {
+            let _span = debug_span!("classify", labels = params.labels.len()).entered();
+            classify(model, ctx, &mut batch, prompt, &tokens, &params.labels)?
+        };
+        let text = probabilities[0].label.clone();
+        // Streams and jobs get the label as a single piece of text
+        on_token(&text, 0);
+        return Ok(Generation {
+            text,
+            prompt_tokens: tokens.len(),
+            cached_tokens: reused,
+            completion_tokens: 0,
+            finish_reason: FinishReason::Stop,
+            prompt_eval_time,
+            generation_time: started.elapsed() - prompt_eval_time,
+            cache_hit: false,
+            probabilities,
+            logprobs: None,
+        });
+    }
+
+    let _span = debug_span!("generate", max_tokens = params.max_tokens).entered();
+
+    let mut sampler = match &params.grammar {
+        // The grammar masks disallowed tokens before the regular sampling chain picks one
+        Some(grammar) => LlamaSampler::chain_simple([
+            LlamaSampler::grammar(model, grammar, "root").context("Invalid grammar")?,
+            params.sampling.sampler(),
+        ]),
+        None => params.sampling.sampler(),
+    };
+    let mut output = Utf8Buffer::default();
+    let mut pos = tokens.len() as i32;
+    let mut completion_tokens = 0;
+    let mut finish_reason = FinishReason::Length;
+    let mut logprobs = params.logprobs.map(|_| Logprobs::default());
+
+    for _ in 0..params.max_tokens {
+        if pos as usize >= n_ctx {
+            break;
+        }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            finish_reason = FinishReason::Timeout;
+            break;
+        }
+
+        let token = sampler.sample(ctx, batch.n_tokens() - 1);
+        if model.is_eog_token(token) {
+            finish_reason = FinishReason::Stop;
+            break;
+        }
+        completion_tokens += 1;
+
+        // Tokens that cannot be converted are skipped but still fed back to the model
+        let bytes = model.token_to_bytes(token, Special::Tokenize).unwrap_or_default();
+        output.push(&bytes);
+        if let (Some(logprobs), Some(top_n)) = (logprobs.as_mut(), params.logprobs) {
+            let logits = ctx.get_logits_ith(batch.n_tokens() - 1);
+            logprobs.content.push(token_logprob(model, logits, token, bytes, top_n));
+        }
+
+        if !on_token(&output.text, completion_tokens) {
+            finish_reason = FinishReason::Cancelled;
+            break;
+        }
+        if params.stop_on_newline && output.text.contains('\n') {
+            finish_reason = FinishReason::Stop;
+            break;
+        }
+        if params.stop_on_json && is_complete_json_object(&output.text) {
+            finish_reason = FinishReason::Stop;
+            break;
+        }
+
+        batch.clear();
+        batch.add(token, pos, &[0], true).context("Batch add error")?;
+        pos += 1;
+        ctx.decode(&mut batch).context("Decode error")?;
+    }
+
+    let generation_time = started.elapsed() - prompt_eval_time;
+    debug!(
+        completion_tokens,
+        finish_reason = ?finish_reason,
+        prompt_eval_ms = prompt_eval_time.as_millis() as u64,
+        generation_ms = generation_time.as_millis() as u64,
+        "generation finished"
+    );
+
+    Ok(Generation {
+        text: output.text.trim().to_string(),
+        prompt_tokens: tokens.len(),
+        cached_tokens: reused,
+        completion_tokens,
+        finish_reason,
+        prompt_eval_time,
+        generation_time,
+        cache_hit: false,
+        probabilities: Vec::new(),
+        logprobs,
+    })
+}
+
+/// Log-probability of the chosen token and the `top_n` most probable tokens at this position.
+/// Values come from the model's raw distribution, before sampling settings and grammar.
+fn token_logprob(model: &LlamaModel, logits: &[f32], token: LlamaToken, bytes: Vec<u8>, top_n: usize) -> TokenLogprob {
+    let norm = log_sum_exp(logits);
+    let mut top: Vec<usize> = (0..logits.len()).collect();
+    let top_n = top_n.min(top.len());
+    if top_n > 0 {
+        top.select_nth_unstable_by(top_n - 1, |a, b| logits[*b].total_cmp(&logits[*a]));
+    }
+    top.truncate(top_n);
+    top.sort_by(|a, b| logits[*b].total_cmp(&logits[*a]));
+
+    TokenLogprob {
+        token: String::from_utf8_lossy(&bytes).into_owned(),
+        logprob: logits[token.0 as usize] - norm,
+        bytes,
+        top_logprobs: top.into_iter()
+            .map(|i| {
+                let bytes = model.token_to_bytes(LlamaToken(i as i32), Special::Tokenize).unwrap_or_default();
+                TopLogprob {
+                    token: String::from_utf8_lossy(&bytes).into_owned(),
+                    logprob: logits[i] - norm,
+                    bytes,
+                }
+            })
+            .collect(),
+    }
+}
+
+/// `ln(sum(exp(logits)))`, computed without overflow
+fn log_sum_exp(logits: &[f32]) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    max + logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln()
+}
+
+/// Scores every label as a continuation of the prompt decoded into `batch` and
+/// returns their normalised probabilities, most probable first.
+///
+/// The logits of the last prompt token give the first label token; the rest of a
+/// multi-token label is decoded after the prompt and then removed from the KV cache.
+fn classify(
+    model: &LlamaModel,
+    ctx: &mut LlamaContext,
+    batch: &mut LlamaBatch,
+    prompt: &str,
+    prompt_tokens: &[LlamaToken],
+    labels: &[String],
+) -> Result<Vec<LabelProbability>> {
+    let start = prompt_tokens.len();
+    let first_logits = ctx.get_logits_ith(batch.n_tokens() - 1).to_vec();
+    let first_norm = log_sum_exp(&first_logits);
+
+    let mut scores = Vec::with_capacity(labels.len());
+    for label in labels {
+        // Tokenized together with the prompt, the label starts with the tokens the model would produce
+        let full = model.str_to_token(&format!("{}{}", prompt, label), AddBos::Always)
+            .context("Tokenization error")?;
+        let tokens = match full.strip_prefix(prompt_tokens) {
+            Some(rest) if !rest.is_empty() => rest.to_vec(),
+            _ => model.str_to_token(label, AddBos::Never).context("Tokenization error")?,
+        };
+        if tokens.is_empty() {
+            bail!("Label '{}' has no tokens", label.trim());
+        }
+        if start + tokens.len() > ctx.n_ctx() as usize {
+            bail!("Prompt is too long to score label '{}'", label.trim());
+        }
+
+        let mut logprob = first_logits[tokens[0].0 as usize] - first_norm;
+        if tokens.len() > 1 {
+            batch.clear();
+            for (i, token) in tokens[..tokens.len() - 1].iter().enumerate() {
+                batch.add(*token, (start + i) as i32, &[0], true).context("Batch error")?;
+            }
+            ctx.decode(batch).context("Decode error")?;
+            for (i, next) in tokens[1..].iter().enumerate() {
+                let logits = ctx.get_logits_ith(i as i32);
+                logprob += logits[next.0 as usize] - log_sum_exp(logits);
+            }
+            ctx.clear_kv_cache_seq(Some(0), Some(start as u32), None)
+                .context("KV cache error")?;
+        }
+        scores.push(logprob);
+    }
+
+    // Softmax over the label log-probabilities
+    let norm = log_sum_exp(&scores);
+    let mut probabilities: Vec<LabelProbability> = labels.iter()
+        .zip(scores)
+        .map(|(label, score)| LabelProbability {
+            label: label.trim().to_string(),
+            probability: (score - norm).exp(),
+        })
+        .collect();
+    probabilities.sort_by(|a, b| b.probability.total_cmp(&a.probability));
+    debug!(label = %probabilities[0].label, probability = probabilities[0].probability, "labels scored");
+    Ok(probabilities)
+}
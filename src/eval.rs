@@ -0,0 +1,396 @@
+use anyhow::{bail, Context, Result};
+use llama_cpp_2::llama_backend::LlamaBackend;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::time::{Duration, Instant};
+
+use crate::cli::CliArgs;
+use crate::engine::{self, GenerationParams, ModelCache};
+use crate::postprocess;
+use crate::presets::{load_presets, Preset};
+use crate::retrieval::Retriever;
+
+/// How a generated output is compared with the expected one
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    /// Trimmed outputs must be equal
+    #[default]
+    Exact,
+    /// Both outputs are parsed as JSON and the selected fields compared
+    JsonFields,
+    /// `expected` is a regular expression the output must match
+    Regex,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct TestCase {
+    pub input: String,
+    pub expected: String,
+    /// Overrides the preset-level match mode for this case
+    #[serde(default, rename = "match")]
+    pub match_mode: Option<MatchMode>,
+    /// Overrides the preset-level field list for `json_fields`
+    #[serde(default)]
+    pub fields: Option<Vec<String>>,
+}
+
+/// Regression test settings stored in a preset under `eval`
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct EvalConfig {
+    #[serde(default, rename = "match")]
+    pub match_mode: MatchMode,
+    /// Fields compared in `json_fields` mode (empty means all expected fields)
+    #[serde(default)]
+    pub fields: Vec<String>,
+    /// Extra test cases in addition to the preset's examples
+    #[serde(default)]
+    pub cases: Vec<TestCase>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct CaseResult {
+    pub input: String,
+    pub expected: String,
+    pub output: String,
+    #[serde(rename = "match")]
+    pub match_mode: MatchMode,
+    pub passed: bool,
+    pub completion_tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ModelReport {
+    pub model: String,
+    pub passed: usize,
+    pub total: usize,
+    pub pass_rate: f64,
+    pub duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub cases: Vec<CaseResult>,
+}
+
+#[derive(Serialize)]
+pub struct EvalReport {
+    pub preset: String,
+    pub min_pass_rate: f64,
+    /// `true` when every model reached `min_pass_rate`
+    pub passed: bool,
+    pub models: Vec<ModelReport>,
+}
+
+/// What an evaluation runs with: the server passes its own model cache and retriever,
+/// so models it has already loaded are not loaded again
+pub struct EvalRuntime<'a> {
+    pub backend: &'a LlamaBackend,
+    pub models: &'a ModelCache,
+    pub retriever: &'a Retriever,
+    /// Cap for the generation time of every case
+    pub max_time: Option<Duration>,
+}
+
+/// Collects test cases: preset examples (optional), preset `eval.cases` and extra cases
+pub fn collect_cases(preset: &Preset, include_examples: bool, extra: &[TestCase]) -> Vec<TestCase> {
+    let mut cases = Vec::new();
+    if include_examples {
+        for ex in preset.examples.iter().flatten() {
+            cases.push(TestCase {
+                input: ex.input.clone(),
+                expected: ex.output.clone(),
+                match_mode: None,
+                fields: None,
+            });
+        }
+    }
+    if let Some(eval) = &preset.eval {
+        cases.extend(eval.cases.iter().cloned());
+    }
+    cases.extend(extra.iter().cloned());
+    cases
+}
+
+/// Reads test cases from a JSONL file (one `TestCase` per line)
+pub fn load_cases(path: &str) -> Result<Vec<TestCase>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("не удалось прочитать {}", path))?;
+    content.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str::<TestCase>(line)
+                .with_context(|| format!("{}:{}: некорректный тест", path, i + 1))
+        })
+        .collect()
+}
+
+/// Extracts the first JSON object from model output (models often add text around it)
+pub(crate) fn extract_json(text: &str) -> Option<serde_json::Value> {
+    if let Ok(value) = serde_json::from_str(text.trim()) {
+        return Some(value);
+    }
+    let start = text.find('{')?;
+    let end = text.rfind('}')?;
+    if end <= start {
+        return None;
+    }
+    serde_json::from_str(&text[start..=end]).ok()
+}
+
+fn json_values_equal(a: &serde_json::Value, b: &serde_json::Value) -> bool {
+    match (a, b) {
+        (serde_json::Value::String(a), serde_json::Value::String(b)) => a.trim() == b.trim(),
+        _ => a == b,
+    }
+}
+
+/// Scores a single output. Returns an error for invalid expectations (bad regex, bad JSON).
+pub fn score(output: &str, expected: &str, mode: MatchMode, fields: &[String]) -> Result<bool> {
+    match mode {
+        MatchMode::Exact => Ok(output.trim() == expected.trim()),
+        MatchMode::Regex => {
+            let re = Regex::new(expected)
+                .with_context(|| format!("некорректное регулярное выражение: {}", expected))?;
+            Ok(re.is_match(output.trim()))
+        }
+        MatchMode::JsonFields => {
+            let expected_json = extract_json(expected)
+                .with_context(|| format!("ожидаемый результат не является JSON: {}", expected))?;
+            let Some(output_json) = extract_json(output) else {
+                return Ok(false);
+            };
+            let keys: Vec<String> = if fields.is_empty() {
+                expected_json.as_object()
+                    .map(|o| o.keys().cloned().collect())
+                    .unwrap_or_default()
+            } else {
+                fields.to_vec()
+            };
+            if keys.is_empty() {
+                return Ok(json_values_equal(&output_json, &expected_json));
+            }
+            Ok(keys.iter().all(|key| {
+                match (output_json.get(key), expected_json.get(key)) {
+                    (Some(a), Some(b)) => json_values_equal(a, b),
+                    _ => false,
+                }
+            }))
+        }
+    }
+}
+
+/// Runs all cases against one model. `on_case` is called after every case (for progress output).
+pub fn eval_model(
+    runtime: &EvalRuntime,
+    preset: &Preset,
+    model_name: &str,
+    cases: &[TestCase],
+    on_case: &mut dyn FnMut(&CaseResult),
+) -> ModelReport {
+    let started = Instant::now();
+    let mut report = ModelReport {
+        model: model_name.to_string(),
+        passed: 0,
+        total: cases.len(),
+        pass_rate: 0.0,
+        duration_ms: 0,
+        error: None,
+        cases: Vec::new(),
+    };
+
+    let backend = runtime.backend;
+    let model = match runtime.models.get_or_load(backend, model_name) {
+        Ok(m) => m,
+        Err(e) => {
+            report.error = Some(format!("{:#}", e));
+            return report;
+        }
+    };
+    let mut ctx = match engine::new_context(&model, backend) {
+        Ok(c) => c,
+        Err(e) => {
+            report.error = Some(format!("{:#}", e));
+            return report;
+        }
+    };
+
+    let preset = preset.for_model(model_name);
+    let mut params = GenerationParams::from_preset(&preset);
+    params.cap_max_time(runtime.max_time);
+    let defaults = preset.eval.clone().unwrap_or_default();
+
+    for case in cases {
+        let mode = case.match_mode.unwrap_or(defaults.match_mode);
+        let fields = case.fields.as_ref().unwrap_or(&defaults.fields);
+
+        let generated = runtime.retriever.context(runtime.models, backend, &preset, &case.input)
+            .and_then(|context| engine::render_prompt(&model, &preset, &case.input, context.as_deref()))
+            .and_then(|prompt| postprocess::generate_validated(Some(&preset), &params, |params| {
+                engine::generate(&model, &mut ctx, &prompt, params)
+            }));
+        let result = match generated {
+            Ok((generation, parsed)) => {
+                // Scored on what clients get: the output after `output_parser` or `postprocess`
+                let output = parsed.text().unwrap_or(generation.text);
+                let scored = match parsed.parse_error {
+                    Some(error) => Err(anyhow::anyhow!(error)),
+                    None => score(&output, &case.expected, mode, fields),
+                };
+                CaseResult {
+                    input: case.input.clone(),
+                    expected: case.expected.clone(),
+                    output,
+                    match_mode: mode,
+                    passed: matches!(scored, Ok(true)),
+                    completion_tokens: generation.completion_tokens,
+                    error: scored.err().map(|e| format!("{:#}", e)),
+                }
+            }
+            Err(e) => CaseResult {
+                input: case.input.clone(),
+                expected: case.expected.clone(),
+                output: String::new(),
+                match_mode: mode,
+                passed: false,
+                completion_tokens: 0,
+                error: Some(format!("{:#}", e)),
+            },
+        };
+
+        if result.passed {
+            report.passed += 1;
+        }
+        on_case(&result);
+        report.cases.push(result);
+    }
+
+    report.pass_rate = if report.total == 0 {
+        0.0
+    } else {
+        report.passed as f64 / report.total as f64
+    };
+    report.duration_ms = started.elapsed().as_millis();
+    report
+}
+
+/// Evaluates a preset on several models
+pub fn run_eval(
+    runtime: &EvalRuntime,
+    preset: &Preset,
+    models: &[String],
+    cases: &[TestCase],
+    min_pass_rate: f64,
+    on_case: &mut dyn FnMut(&str, &CaseResult),
+) -> EvalReport {
+    let mut reports = Vec::new();
+    for model in models {
+        let report = eval_model(runtime, preset, model, cases, &mut |case| on_case(model, case));
+        reports.push(report);
+    }
+    EvalReport {
+        preset: preset.name.clone(),
+        min_pass_rate,
+        passed: !reports.is_empty()
+            && reports.iter().all(|r| r.error.is_none() && r.total > 0 && r.pass_rate >= min_pass_rate),
+        models: reports,
+    }
+}
+
+pub fn print_report(report: &EvalReport) {
+    println!("\nПресет: {}", report.preset);
+    for model in &report.models {
+        println!("\nМодель: {}", model.model);
+        if let Some(error) = &model.error {
+            println!("  Ошибка: {}", error);
+            continue;
+        }
+        for case in &model.cases {
+            let mark = if case.passed { "✓" } else { "✗" };
+            println!("  {} {} → {}", mark, case.input, case.output);
+            if !case.passed {
+                println!("      ожидалось ({:?}): {}", case.match_mode, case.expected);
+            }
+            if let Some(error) = &case.error {
+                println!("      ошибка: {}", error);
+            }
+        }
+    }
+
+    println!("\nИтог (порог {:.1}%):", report.min_pass_rate * 100.0);
+    for model in &report.models {
+        let status = if model.error.is_none() && model.pass_rate >= report.min_pass_rate { "OK" } else { "FAIL" };
+        println!(
+            "  {:<40} {:>3}/{:<3} {:>6.1}%  {:>7} мс  {}",
+            model.model, model.passed, model.total, model.pass_rate * 100.0, model.duration_ms, status
+        );
+    }
+}
+
+/// `chat-np eval --preset NAME [--model FILE]... [--cases FILE] [--min-pass-rate 0.9] [--no-examples] [--json]`
+///
+/// Exits with code 1 when any model is below the pass-rate threshold.
+pub fn run_command(backend: &LlamaBackend, available_models: &[String], cli: &CliArgs) -> Result<()> {
+    let preset_name = cli.require("preset")?;
+    let presets = load_presets();
+    let Some(preset) = presets.iter().find(|p| p.name == preset_name) else {
+        bail!("пресет '{}' не найден", preset_name);
+    };
+
+    let mut models = cli.values("model");
+    if models.is_empty() {
+        models.extend(
+            preset.preferred_model(available_models)
+                .or_else(|| available_models.first().cloned()),
+        );
+    }
+    for model in &models {
+        if !available_models.contains(model) {
+            bail!("модель '{}' не найдена. Доступные модели: {:?}", model, available_models);
+        }
+        if !preset.allows_model(model) {
+            bail!("пресет '{}' не разрешает модель '{}'", preset.name, model);
+        }
+    }
+
+    let extra = match cli.value("cases") {
+        Some(path) => load_cases(path)?,
+        None => vec![],
+    };
+    let cases = collect_cases(preset, !cli.flag("no-examples"), &extra);
+    if cases.is_empty() {
+        bail!("у пресета '{}' нет примеров и тестов", preset_name);
+    }
+    let min_pass_rate = cli.parse_value::<f64>("min-pass-rate")?.unwrap_or(1.0);
+    let json = cli.flag("json");
+
+    if !json {
+        println!("Тестов: {}, моделей: {}", cases.len(), models.len());
+    }
+    let (models_cache, retriever) = (ModelCache::default(), Retriever::default());
+    let runtime = EvalRuntime {
+        backend,
+        models: &models_cache,
+        retriever: &retriever,
+        max_time: None,
+    };
+    let report = run_eval(&runtime, preset, &models, &cases, min_pass_rate, &mut |model, case| {
+        if !json {
+            let mark = if case.passed { "✓" } else { "✗" };
+            eprintln!("[{}] {} {}", model, mark, case.input);
+        }
+    });
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report);
+    }
+
+    if !report.passed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
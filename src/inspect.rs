@@ -0,0 +1,382 @@
+use anyhow::{bail, Context, Result};
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use llama_cpp_2::{
+    llama_backend::LlamaBackend,
+    model::{AddBos, LlamaModel, Special},
+    token::LlamaToken,
+};
+use serde::{Deserialize, Serialize};
+use std::io::{self, IsTerminal, Read};
+use std::sync::Arc;
+
+use crate::auth::ApiKey;
+use crate::cli::CliArgs;
+use crate::engine::{self, N_CTX};
+use crate::metrics::RequestLabels;
+use crate::presets::{load_presets, Preset, PromptFormat};
+use crate::retrieval::Retriever;
+use crate::server::{prepare_prompt, queue_error_status, resolve_request, AppState, ErrorResponse};
+
+/// A token id with the text it stands for
+#[derive(Serialize)]
+pub struct TokenPiece {
+    pub id: i32,
+    /// Lossy UTF-8: a token may hold only part of a multi-byte character
+    pub piece: String,
+}
+
+fn piece(model: &LlamaModel, token: LlamaToken) -> String {
+    String::from_utf8_lossy(&model.token_to_bytes(token, Special::Tokenize).unwrap_or_default()).into_owned()
+}
+
+/// Splits text into tokens exactly as generation does; `add_special` prepends BOS
+pub fn tokenize(model: &LlamaModel, text: &str, add_special: bool) -> Result<Vec<TokenPiece>> {
+    let add_bos = if add_special { AddBos::Always } else { AddBos::Never };
+    let tokens = model.str_to_token(text, add_bos)
+        .context("Tokenization error")?;
+    Ok(tokens.into_iter()
+        .map(|token| TokenPiece { id: token.0, piece: piece(model, token) })
+        .collect())
+}
+
+/// Joins token ids back into text
+pub fn detokenize(model: &LlamaModel, ids: &[i32]) -> Result<String> {
+    let n_vocab = model.n_vocab();
+    let mut bytes = Vec::new();
+    for &id in ids {
+        if !(0..n_vocab).contains(&id) {
+            bail!("Token id {} is outside the vocabulary (0..{})", id, n_vocab);
+        }
+        let token_bytes = model.token_to_bytes(LlamaToken(id), Special::Tokenize)
+            .with_context(|| format!("Failed to detokenize token {}", id))?;
+        bytes.extend(token_bytes);
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// The exact model input for a request and how much of the context it takes
+#[derive(Serialize)]
+pub struct RenderedPrompt {
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_format: Option<PromptFormat>,
+    pub prompt: String,
+    pub tokens: Vec<TokenPiece>,
+    pub prompt_tokens: usize,
+    pub n_ctx: u32,
+    /// Context left for the answer; generation stops early when it is smaller than `max_tokens`
+    pub available_tokens: usize,
+    pub max_tokens: usize,
+}
+
+impl RenderedPrompt {
+    fn new(model: &LlamaModel, model_name: &str, preset: Option<&Preset>, prompt: String, max_tokens: usize) -> Result<Self> {
+        let tokens = tokenize(model, &prompt, true)?;
+        Ok(Self {
+            model: model_name.to_string(),
+            preset: preset.map(|p| p.name.clone()),
+            prompt_format: preset.map(|p| p.prompt_format),
+            prompt,
+            prompt_tokens: tokens.len(),
+            tokens,
+            n_ctx: N_CTX,
+            available_tokens: (N_CTX as usize).saturating_sub(tokens.len()),
+            max_tokens,
+        })
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+pub struct TokenizeRequest {
+    content: String,
+    #[serde(default)]
+    model: Option<String>,
+    /// Prepend BOS as generation does
+    #[serde(default = "default_true")]
+    add_special: bool,
+}
+
+#[derive(Serialize)]
+struct TokenizeResponse {
+    model: String,
+    tokens: Vec<TokenPiece>,
+    count: usize,
+    n_ctx: u32,
+}
+
+#[derive(Deserialize)]
+pub struct DetokenizeRequest {
+    tokens: Vec<i32>,
+    #[serde(default)]
+    model: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DetokenizeResponse {
+    model: String,
+    content: String,
+}
+
+/// Same fields as /chat that shape the prompt
+#[derive(Deserialize)]
+pub struct RenderRequest {
+    prompt: String,
+    #[serde(default)]
+    preset: Option<String>,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    system_prompt: Option<String>,
+    #[serde(default)]
+    max_tokens: Option<usize>,
+}
+
+/// POST /tokenize
+pub async fn tokenize_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(key): Extension<Arc<ApiKey>>,
+    Json(req): Json<TokenizeRequest>,
+) -> Response {
+    if let Err(response) = key.authorize_requested(req.model.as_deref(), None) {
+        return response;
+    }
+    let resolved = match resolve_request(&state, None, req.model.as_deref()) {
+        Ok(r) => r,
+        Err(message) => return ErrorResponse::new(StatusCode::BAD_REQUEST, message),
+    };
+    if let Err(response) = key.authorize(&resolved.model_name, None) {
+        return response;
+    }
+    let labels = RequestLabels::new(&resolved.model_name, None);
+
+    let worker_state = Arc::clone(&state);
+    let model_name = resolved.model_name.clone();
+    let result = state.queue.run(move || {
+        let model = worker_state.models.get_or_load(&worker_state.backend, &model_name)?;
+        tokenize(&model, &req.content, req.add_special)
+    }).await;
+
+    let response = match result {
+        Ok(Ok(tokens)) => (StatusCode::OK, Json(TokenizeResponse {
+            model: resolved.model_name,
+            count: tokens.len(),
+            tokens,
+            n_ctx: N_CTX,
+        })).into_response(),
+        Ok(Err(e)) => ErrorResponse::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)),
+        Err(e) => ErrorResponse::new(queue_error_status(&e), e.to_string()),
+    };
+    labels.apply(response)
+}
+
+/// POST /detokenize
+pub async fn detokenize_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(key): Extension<Arc<ApiKey>>,
+    Json(req): Json<DetokenizeRequest>,
+) -> Response {
+    if let Err(response) = key.authorize_requested(req.model.as_deref(), None) {
+        return response;
+    }
+    let resolved = match resolve_request(&state, None, req.model.as_deref()) {
+        Ok(r) => r,
+        Err(message) => return ErrorResponse::new(StatusCode::BAD_REQUEST, message),
+    };
+    if let Err(response) = key.authorize(&resolved.model_name, None) {
+        return response;
+    }
+    let labels = RequestLabels::new(&resolved.model_name, None);
+
+    let worker_state = Arc::clone(&state);
+    let model_name = resolved.model_name.clone();
+    let result = state.queue.run(move || {
+        let model = worker_state.models.get_or_load(&worker_state.backend, &model_name)?;
+        anyhow::Ok(detokenize(&model, &req.tokens))
+    }).await;
+
+    let response = match result {
+        Ok(Ok(Ok(content))) => (StatusCode::OK, Json(DetokenizeResponse {
+            model: resolved.model_name,
+            content,
+        })).into_response(),
+        // Unknown token ids are the client's mistake
+        Ok(Ok(Err(e))) => ErrorResponse::new(StatusCode::BAD_REQUEST, format!("{:#}", e)),
+        Ok(Err(e)) => ErrorResponse::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)),
+        Err(e) => ErrorResponse::new(queue_error_status(&e), e.to_string()),
+    };
+    labels.apply(response)
+}
+
+/// POST /render: the prompt /chat would send for the same request, without generating
+pub async fn render_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(key): Extension<Arc<ApiKey>>,
+    Json(req): Json<RenderRequest>,
+) -> Response {
+    if let Err(response) = key.authorize_requested(req.model.as_deref(), req.preset.as_deref()) {
+        return response;
+    }
+    let resolved = match resolve_request(&state, req.preset.as_deref(), req.model.as_deref()) {
+        Ok(r) => r,
+        Err(message) => return ErrorResponse::new(StatusCode::BAD_REQUEST, message),
+    };
+    if let Err(response) = key.authorize(&resolved.model_name, req.preset.as_deref()) {
+        return response;
+    }
+    let labels = RequestLabels::new(&resolved.model_name, req.preset.as_deref());
+
+    let worker_state = Arc::clone(&state);
+    let result = state.queue.run(move || {
+        let model = worker_state.models.get_or_load(&worker_state.backend, &resolved.model_name)?;
+        let (prompt, params) = prepare_prompt(&worker_state, &model, &resolved, &req.prompt, req.system_prompt.as_deref(), req.max_tokens, None)?;
+        RenderedPrompt::new(&model, &resolved.model_name, resolved.preset.as_ref(), prompt, params.max_tokens)
+    }).await;
+
+    let response = match result {
+        Ok(Ok(rendered)) => (StatusCode::OK, Json(rendered)).into_response(),
+        Ok(Err(e)) => ErrorResponse::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)),
+        Err(e) => ErrorResponse::new(queue_error_status(&e), e.to_string()),
+    };
+    labels.apply(response)
+}
+
+/// Value of `--{name}`, or stdin when it is piped
+fn read_text(cli: &CliArgs, name: &str) -> Result<String> {
+    if let Some(text) = cli.value(name) {
+        return Ok(text.to_string());
+    }
+    let stdin = io::stdin();
+    if stdin.is_terminal() {
+        bail!("укажите --{} или передайте текст через stdin", name);
+    }
+    let mut text = String::new();
+    stdin.lock().read_to_string(&mut text).context("не удалось прочитать stdin")?;
+    Ok(text)
+}
+
+fn select_model(cli: &CliArgs, available_models: &[String], preset: Option<&Preset>) -> Result<String> {
+    let model_name = cli.value("model").map(str::to_string)
+        .or_else(|| preset.and_then(|p| p.preferred_model(available_models)))
+        .or_else(|| available_models.first().cloned())
+        .unwrap_or_default();
+    if !available_models.contains(&model_name) {
+        bail!("модель '{}' не найдена. Доступные модели: {:?}", model_name, available_models);
+    }
+    Ok(model_name)
+}
+
+/// Runs the `tokenize`, `detokenize` or `render` command
+pub fn run_command(command: &str, backend: &LlamaBackend, available_models: &[String], cli: &CliArgs) -> Result<()> {
+    match command {
+        "tokenize" => tokenize_command(backend, available_models, cli),
+        "detokenize" => detokenize_command(backend, available_models, cli),
+        "render" => render_command(backend, available_models, cli),
+        other => bail!("неизвестная команда: {}", other),
+    }
+}
+
+/// `chat-np tokenize [--text TEXT] [--model FILE] [--no-special] [--json]`
+///
+/// Prints one `id<TAB>piece` line per token (or the /tokenize response with `--json`); the count goes to stderr.
+fn tokenize_command(backend: &LlamaBackend, available_models: &[String], cli: &CliArgs) -> Result<()> {
+    let text = read_text(cli, "text")?;
+    let model_name = select_model(cli, available_models, None)?;
+    let model = engine::load_model(backend, &model_name)?;
+    let tokens = tokenize(&model, &text, !cli.flag("no-special"))?;
+
+    if cli.flag("json") {
+        println!("{}", serde_json::to_string(&TokenizeResponse {
+            model: model_name,
+            count: tokens.len(),
+            tokens,
+            n_ctx: N_CTX,
+        })?);
+        return Ok(());
+    }
+    for token in &tokens {
+        println!("{}\t{:?}", token.id, token.piece);
+    }
+    eprintln!("Токенов: {} (размер контекста {})", tokens.len(), N_CTX);
+    Ok(())
+}
+
+/// `chat-np detokenize --tokens ID,ID,... [--model FILE]`
+fn detokenize_command(backend: &LlamaBackend, available_models: &[String], cli: &CliArgs) -> Result<()> {
+    let ids = cli.values("tokens").iter()
+        .map(|id| id.parse::<i32>().with_context(|| format!("некорректный id токена: {}", id)))
+        .collect::<Result<Vec<_>>>()?;
+    if ids.is_empty() {
+        bail!("не указан обязательный параметр --tokens");
+    }
+    let model_name = select_model(cli, available_models, None)?;
+    let model = engine::load_model(backend, &model_name)?;
+    println!("{}", detokenize(&model, &ids)?);
+    Ok(())
+}
+
+/// `chat-np render [--preset NAME] [--prompt TEXT] [--system-prompt TEXT] [--model FILE] [--max-tokens N] [--json]`
+///
+/// Prints the exact prompt the preset produces for the input (or the /render response with `--json`)
+/// and its size relative to the context to stderr.
+fn render_command(backend: &LlamaBackend, available_models: &[String], cli: &CliArgs) -> Result<()> {
+    let input = read_text(cli, "prompt")?;
+    let input = input.trim();
+    let preset = match cli.value("preset") {
+        Some(name) => match load_presets().into_iter().find(|p| p.name == name) {
+            Some(p) => Some(p),
+            None => bail!("пресет '{}' не найден", name),
+        },
+        None => None,
+    };
+    let model_name = select_model(cli, available_models, preset.as_ref())?;
+    if let Some(p) = &preset {
+        if !p.allows_model(&model_name) {
+            bail!("пресет '{}' не разрешает модель '{}'", p.name, model_name);
+        }
+    }
+    let preset = preset.map(|p| p.for_model(&model_name));
+    let max_tokens = cli.parse_value::<usize>("max-tokens")?;
+
+    // Retrieval embeddings come from the same cache, so the chat model is not loaded twice
+    let models = engine::ModelCache::default();
+    let model = models.get_or_load(backend, &model_name)?;
+    let (prompt, preset_max_tokens) = match &preset {
+        Some(p) => {
+            let context = Retriever::default().context(&models, backend, p, input)?;
+            (engine::render_prompt(&model, p, input, context.as_deref())?, p.max_tokens)
+        }
+        None => {
+            let prompt = match cli.value("system-prompt") {
+                Some(system) if !system.is_empty() => format!("{}\n\n{}", system, input),
+                _ => input.to_string(),
+            };
+            (prompt, engine::DEFAULT_MAX_TOKENS)
+        }
+    };
+    let rendered = RenderedPrompt::new(&model, &model_name, preset.as_ref(), prompt, max_tokens.unwrap_or(preset_max_tokens))?;
+
+    if cli.flag("json") {
+        println!("{}", serde_json::to_string(&rendered)?);
+        return Ok(());
+    }
+    println!("{}", rendered.prompt);
+    eprintln!(
+        "Токенов промпта: {} из {} (свободно {}, max_tokens {})",
+        rendered.prompt_tokens, rendered.n_ctx, rendered.available_tokens, rendered.max_tokens
+    );
+    if rendered.prompt_tokens >= rendered.n_ctx as usize {
+        eprintln!("Промпт не помещается в контекст: генерация завершится ошибкой");
+    }
+    Ok(())
+}
@@ -0,0 +1,358 @@
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::audit::Source;
+use crate::auth::ApiKey;
+use crate::engine::{FinishReason, Generation, LabelProbability, Logprobs};
+use crate::metrics::RequestLabels;
+use crate::postprocess::Parsed;
+use crate::ratelimit::Caller;
+use crate::server::{queue_error_status, resolve_request, run_chat, AppState, ChatRequest, ErrorResponse};
+
+/// How often expired jobs are removed
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    fn is_finished(self) -> bool {
+        matches!(self, JobStatus::Completed | JobStatus::Failed | JobStatus::Cancelled)
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct JobResult {
+    pub response: String,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub finish_reason: FinishReason,
+    pub cache_hit: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub probabilities: Vec<LabelProbability>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<Logprobs>,
+    #[serde(flatten)]
+    pub parsed: Parsed,
+}
+
+#[derive(Serialize, Clone)]
+pub struct JobProgress {
+    pub completion_tokens: usize,
+    pub max_tokens: usize,
+}
+
+struct JobState {
+    status: JobStatus,
+    started_at: Option<String>,
+    finished_at: Option<String>,
+    /// Monotonic finish time used for retention
+    finished: Option<Instant>,
+    completion_tokens: usize,
+    partial_output: String,
+    result: Option<JobResult>,
+    error: Option<String>,
+}
+
+/// A generation running in the background on the inference queue
+pub struct Job {
+    pub id: String,
+    created_at: String,
+    model: String,
+    preset: Option<String>,
+    max_tokens: usize,
+    /// Name of the API key that created the job
+    owner: String,
+    cancelled: AtomicBool,
+    state: Mutex<JobState>,
+}
+
+#[derive(Serialize)]
+pub struct JobView {
+    pub id: String,
+    pub status: JobStatus,
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    pub created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<String>,
+    pub progress: JobProgress,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partial_output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<JobResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn now() -> String {
+    chrono::Local::now().to_rfc3339()
+}
+
+impl Job {
+    /// Marks the job as running. Returns `false` if it was cancelled while queued.
+    pub fn start(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.status != JobStatus::Queued {
+            return false;
+        }
+        state.status = JobStatus::Running;
+        state.started_at = Some(now());
+        true
+    }
+
+    /// Records progress; returns `false` when the job should stop
+    pub fn progress(&self, text: &str, completion_tokens: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.completion_tokens = completion_tokens;
+        state.partial_output.clear();
+        state.partial_output.push_str(text);
+        !self.is_cancelled()
+    }
+
+    pub fn finish(&self, result: anyhow::Result<(Generation, Parsed)>) {
+        let mut state = self.state.lock().unwrap();
+        state.finished_at = Some(now());
+        state.finished = Some(Instant::now());
+        match result {
+            Ok((generation, parsed)) => {
+                state.status = if generation.finish_reason == FinishReason::Cancelled {
+                    JobStatus::Cancelled
+                } else {
+                    JobStatus::Completed
+                };
+                state.completion_tokens = generation.completion_tokens;
+                state.partial_output.clear();
+                state.result = Some(JobResult {
+                    response: generation.text,
+                    prompt_tokens: generation.prompt_tokens,
+                    completion_tokens: generation.completion_tokens,
+                    finish_reason: generation.finish_reason,
+                    cache_hit: generation.cache_hit,
+                    probabilities: generation.probabilities,
+                    logprobs: generation.logprobs,
+                    parsed,
+                });
+            }
+            Err(e) => {
+                state.status = JobStatus::Failed;
+                state.error = Some(format!("{:#}", e));
+            }
+        }
+    }
+
+    /// Requests cancellation. A queued job is cancelled immediately,
+    /// a running one stops after the current token.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        let mut state = self.state.lock().unwrap();
+        if state.status == JobStatus::Queued {
+            state.status = JobStatus::Cancelled;
+            state.finished_at = Some(now());
+            state.finished = Some(Instant::now());
+        }
+    }
+
+    /// Jobs are visible only to the key that created them and to admin keys
+    pub fn visible_to(&self, key: &ApiKey) -> bool {
+        key.admin || self.owner == key.name
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn view(&self) -> JobView {
+        let state = self.state.lock().unwrap();
+        JobView {
+            id: self.id.clone(),
+            status: state.status,
+            model: self.model.clone(),
+            preset: self.preset.clone(),
+            created_at: self.created_at.clone(),
+            started_at: state.started_at.clone(),
+            finished_at: state.finished_at.clone(),
+            progress: JobProgress {
+                completion_tokens: state.completion_tokens,
+                max_tokens: self.max_tokens,
+            },
+            partial_output: (state.status == JobStatus::Running).then(|| state.partial_output.clone()),
+            result: state.result.clone(),
+            error: state.error.clone(),
+        }
+    }
+}
+
+/// All known jobs; finished ones are dropped after the retention period
+pub struct JobStore {
+    jobs: Mutex<HashMap<String, Arc<Job>>>,
+    retention: Duration,
+}
+
+impl JobStore {
+    pub fn new(retention: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            jobs: Mutex::new(HashMap::new()),
+            retention,
+        })
+    }
+
+    pub fn create(&self, model: &str, preset: Option<&str>, max_tokens: usize, owner: &str) -> Arc<Job> {
+        let id = format!("job_{}", uuid::Uuid::new_v4().simple());
+        let job = Arc::new(Job {
+            id: id.clone(),
+            created_at: now(),
+            model: model.to_string(),
+            preset: preset.map(str::to_string),
+            max_tokens,
+            owner: owner.to_string(),
+            cancelled: AtomicBool::new(false),
+            state: Mutex::new(JobState {
+                status: JobStatus::Queued,
+                started_at: None,
+                finished_at: None,
+                finished: None,
+                completion_tokens: 0,
+                partial_output: String::new(),
+                result: None,
+                error: None,
+            }),
+        });
+        self.jobs.lock().unwrap().insert(id, Arc::clone(&job));
+        job
+    }
+
+    /// Finds a job the key is allowed to see
+    pub fn get(&self, id: &str, key: &ApiKey) -> Option<Arc<Job>> {
+        self.jobs.lock().unwrap().get(id)
+            .filter(|job| job.visible_to(key))
+            .cloned()
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.jobs.lock().unwrap().remove(id);
+    }
+
+    /// Drops finished jobs older than the retention period
+    pub fn purge_expired(&self) {
+        let retention = self.retention;
+        self.jobs.lock().unwrap().retain(|_, job| {
+            let state = job.state.lock().unwrap();
+            match state.finished {
+                Some(finished) if state.status.is_finished() => finished.elapsed() < retention,
+                _ => true,
+            }
+        });
+    }
+
+    pub fn spawn_cleanup(self: &Arc<Self>) {
+        let store = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+            loop {
+                interval.tick().await;
+                store.purge_expired();
+            }
+        });
+    }
+}
+
+pub async fn create_job_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(key): Extension<Arc<ApiKey>>,
+    Extension(caller): Extension<Caller>,
+    Json(req): Json<ChatRequest>,
+) -> Response {
+    // The body is shared with /chat, but these fields have no meaning for a job
+    if req.stream {
+        return ErrorResponse::new(StatusCode::BAD_REQUEST, "Streaming is not supported by /jobs, poll GET /jobs/{id} for partial_output");
+    }
+    if req.request_id.is_some() {
+        return ErrorResponse::new(StatusCode::BAD_REQUEST, "request_id is not supported by /jobs, cancel a job with DELETE /jobs/{id}");
+    }
+    if let Err(response) = key.authorize_requested(req.model.as_deref(), req.preset.as_deref()) {
+        return response;
+    }
+    let resolved = match resolve_request(&state, req.preset.as_deref(), req.model.as_deref()) {
+        Ok(r) => r,
+        Err(message) => return ErrorResponse::new(StatusCode::BAD_REQUEST, message),
+    };
+    if let Err(response) = key.authorize(&resolved.model_name, req.preset.as_deref()) {
+        return response;
+    }
+    let labels = RequestLabels::new(&resolved.model_name, req.preset.as_deref());
+    if let Err(message) = req.logprobs() {
+        return labels.apply(ErrorResponse::new(StatusCode::BAD_REQUEST, message));
+    }
+
+    let job = state.jobs.create(
+        &resolved.model_name,
+        resolved.preset.as_ref().map(|p| p.name.as_str()),
+        resolved.max_tokens(req.max_tokens),
+        &key.name,
+    );
+
+    let worker_state = Arc::clone(&state);
+    let worker_job = Arc::clone(&job);
+    let submitted = state.queue.submit(move || {
+        if !worker_job.start() {
+            return;
+        }
+        let source = Source::new("/jobs", &worker_job.id, &worker_job.owner);
+        let result = run_chat(&worker_state, &caller, &source, &resolved, &req, &mut |text, tokens| {
+            worker_job.progress(text, tokens)
+        });
+        worker_job.finish(result);
+    });
+
+    if let Err(e) = submitted {
+        state.jobs.remove(&job.id);
+        return labels.apply(ErrorResponse::new(queue_error_status(&e), e.to_string()));
+    }
+
+    labels.apply((StatusCode::ACCEPTED, Json(job.view())).into_response())
+}
+
+pub async fn get_job_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(key): Extension<Arc<ApiKey>>,
+    Path(id): Path<String>,
+) -> Response {
+    match state.jobs.get(&id, &key) {
+        Some(job) => (StatusCode::OK, Json(job.view())).into_response(),
+        None => ErrorResponse::new(StatusCode::NOT_FOUND, format!("Job '{}' not found", id)),
+    }
+}
+
+pub async fn delete_job_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(key): Extension<Arc<ApiKey>>,
+    Path(id): Path<String>,
+) -> Response {
+    match state.jobs.get(&id, &key) {
+        Some(job) => {
+            job.cancel();
+            (StatusCode::OK, Json(job.view())).into_response()
+        }
+        None => ErrorResponse::new(StatusCode::NOT_FOUND, format!("Job '{}' not found", id)),
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::{bail, Context, Result};
+use axum::{
+    http::{header, HeaderName, HeaderValue, Method},
+    Router,
+};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::warn;
+
+use crate::config::{CorsConfig, TlsConfig};
+
+/// Prefix that marks a Unix domain socket path in `server.listen`
+const UNIX_PREFIX: &str = "unix:";
+/// How long browsers may cache a CORS preflight response
+const CORS_MAX_AGE: Duration = Duration::from_secs(600);
+
+/// One address from `server.listen` / `--listen`
+pub enum Listen {
+    /// `127.0.0.1:3000`, `0.0.0.0:3000`, `[::]:3000`
+    Tcp(SocketAddr),
+    /// `unix:/run/chat-np.sock`
+    Unix(String),
+}
+
+impl Listen {
+    pub fn parse(value: &str) -> Result<Self> {
+        if let Some(path) = value.strip_prefix(UNIX_PREFIX) {
+            if path.is_empty() {
+                bail!("не указан путь Unix-сокета: {}", value);
+            }
+            return Ok(Listen::Unix(path.to_string()));
+        }
+        value.parse::<SocketAddr>()
+            .map(Listen::Tcp)
+            .with_context(|| format!("некорректный адрес '{}': ожидается IP:порт, [IPv6]:порт или unix:путь", value))
+    }
+
+    /// Base URL for the startup banner
+    pub fn url(&self, tls: bool) -> String {
+        match self {
+            Listen::Tcp(addr) => format!("{}://{}", if tls { "https" } else { "http" }, addr),
+            Listen::Unix(path) => format!("unix:{}", path),
+        }
+    }
+}
+
+/// CORS policy from `server.cors`: `"*"` allows any origin, otherwise only the listed ones
+pub fn cors_layer(config: &CorsConfig) -> Result<CorsLayer> {
+    if config.allowed_origins.iter().any(|o| o == "*") {
+        return Ok(CorsLayer::permissive());
+    }
+    let origins = config.allowed_origins.iter()
+        .map(|o| HeaderValue::from_str(o.trim_end_matches('/'))
+            .with_context(|| format!("некорректный origin в server.cors.allowed_origins: {}", o)))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::OPTIONS])
+        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION, HeaderName::from_static("x-api-key")])
+        .expose_headers([
+            HeaderName::from_static("x-ratelimit-limit"),
+            HeaderName::from_static("x-ratelimit-remaining"),
+            HeaderName::from_static("x-ratelimit-reset"),
+            HeaderName::from_static("x-ratelimit-limit-tokens"),
+            HeaderName::from_static("x-ratelimit-remaining-tokens"),
+            header::RETRY_AFTER,
+            HeaderName::from_static("x-request-id"),
+        ])
+        .max_age(CORS_MAX_AGE))
+}
+
+/// Binds every address first (so configuration errors show up before serving),
+/// then serves the app on all of them until one fails
+pub async fn serve(listen: &[Listen], tls: Option<&TlsConfig>, app: Router) -> Result<()> {
+    // Unix sockets are served without TLS; refuse rather than expose plaintext unexpectedly
+    if let (Some(_), Some(Listen::Unix(path))) = (tls, listen.iter().find(|l| matches!(l, Listen::Unix(_)))) {
+        bail!("TLS не поддерживается для Unix-сокетов ({}): уберите server.tls или адрес unix:", path);
+    }
+    let tls_config = match tls {
+        Some(tls) => Some(
+            axum_server::tls_rustls::RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+                .await
+                .with_context(|| format!("не удалось загрузить TLS-сертификат {} и ключ {}", tls.cert, tls.key))?,
+        ),
+        None => None,
+    };
+
+    let mut servers = JoinSet::new();
+    for address in listen {
+        match address {
+            Listen::Tcp(addr) => {
+                let listener = std::net::TcpListener::bind(addr)
+                    .with_context(|| format!("не удалось открыть {}", addr))?;
+                listener.set_nonblocking(true)?;
+                let service = app.clone().into_make_service_with_connect_info::<SocketAddr>();
+                match &tls_config {
+                    Some(tls_config) => {
+                        let server = axum_server::from_tcp_rustls(listener, tls_config.clone());
+                        servers.spawn(async move { server.serve(service).await.map_err(anyhow::Error::from) });
+                    }
+                    None => {
+                        let listener = tokio::net::TcpListener::from_std(listener)?;
+                        servers.spawn(async move { axum::serve(listener, service).await.map_err(anyhow::Error::from) });
+                    }
+                }
+            }
+            Listen::Unix(path) => {
+                let listener = bind_unix(path)?;
+                servers.spawn(serve_unix(listener, app.clone()));
+            }
+        }
+    }
+
+    while let Some(result) = servers.join_next().await {
+        result.context("сервер остановился с ошибкой")??;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &str) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // A socket file left over from a previous run would make bind fail.
+    // Anything else at the path is left alone: it is most likely a typo in the address.
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            std::fs::remove_file(path)
+                .with_context(|| format!("не удалось удалить старый сокет {}", path))?;
+        }
+        Ok(_) => bail!("{} уже существует и не является сокетом", path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("не удалось проверить {}", path)),
+    }
+    tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("не удалось открыть Unix-сокет {}", path))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(path: &str) -> Result<std::convert::Infallible> {
+    bail!("Unix-сокеты не поддерживаются на этой платформе: {}", path)
+}
+
+/// Client of a Unix socket connection (`pid:…`, or `uid:…` / `connection:…` when the
+/// pid is unknown); Unix sockets have no client address to account requests to
+#[derive(Clone)]
+#[cfg_attr(not(unix), allow(dead_code))]
+pub struct UnixPeer(pub String);
+
+/// axum 0.7 only serves TCP listeners, so Unix connections are driven by hyper directly
+#[cfg(unix)]
+async fn serve_unix(listener: tokio::net::UnixListener, app: Router) -> Result<()> {
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::server::conn::auto::Builder;
+    use tower::Service;
+
+    let mut connections: u64 = 0;
+    loop {
+        let (socket, _) = listener.accept().await?;
+        connections += 1;
+        let peer = UnixPeer(match socket.peer_cred() {
+            Ok(cred) => match cred.pid() {
+                Some(pid) => format!("pid:{}", pid),
+                None => format!("uid:{}", cred.uid()),
+            },
+            Err(_) => format!("connection:{}", connections),
+        });
+        let router = app.clone();
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(move |mut request: axum::extract::Request<hyper::body::Incoming>| {
+                request.extensions_mut().insert(peer.clone());
+                router.clone().call(request)
+            });
+            if let Err(e) = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(socket), service)
+                .await
+            {
+                warn!(error = %e, "unix socket connection error");
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(listener: std::convert::Infallible, _app: Router) -> Result<()> {
+    match listener {}
+}
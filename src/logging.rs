@@ -0,0 +1,73 @@
+use anyhow::{bail, Result};
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::time::Instant;
+use tracing::{info, info_span, Instrument};
+use tracing_subscriber::EnvFilter;
+
+use crate::cli::CliArgs;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+/// Longest client-supplied request id that is accepted as is
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Installs the global subscriber. Logs go to stderr so stdout stays clean for
+/// one-shot output; the level comes from `--log-level`, then `RUST_LOG`, then `default_level`.
+pub fn init(cli: &CliArgs, default_level: &str) -> Result<()> {
+    let filter = match cli.value("log-level") {
+        Some(level) => EnvFilter::try_new(level)?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_level)),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_target(false);
+    match cli.value("log-format").unwrap_or("text") {
+        "text" => builder.init(),
+        "json" => builder.json().with_current_span(true).with_span_list(true).init(),
+        other => bail!("неизвестный формат логов: {} (поддерживаются text и json)", other),
+    }
+    Ok(())
+}
+
+/// Id of the current HTTP request, available to handlers as an extension
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+fn incoming_request_id(request: &Request) -> Option<String> {
+    let id = request.headers().get(&X_REQUEST_ID)?.to_str().ok()?.trim();
+    (!id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN).then(|| id.to_string())
+}
+
+/// Wraps every request in a `request` span carrying its id, logs the outcome
+/// and returns the id as `X-Request-Id` (the client's own id is kept if it sent one)
+pub async fn trace_request(mut request: Request, next: Next) -> Response {
+    let id = incoming_request_id(&request)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+    request.extensions_mut().insert(RequestId(id.clone()));
+
+    let span = info_span!(
+        "request",
+        request_id = %id,
+        method = %request.method(),
+        path = %request.uri().path(),
+    );
+    let started = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+
+    span.in_scope(|| {
+        info!(
+            status = response.status().as_u16(),
+            latency_ms = started.elapsed().as_millis() as u64,
+            "request completed"
+        );
+    });
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(X_REQUEST_ID, value);
+    }
+    response
+}
@@ -1,30 +1,42 @@
+mod audit;
+mod auth;
+mod batch;
+mod cancel;
+mod cli;
+mod completions;
+mod config;
+mod embeddings;
+mod engine;
+mod eval;
+mod inspect;
+mod jobs;
+mod listener;
+mod logging;
+mod metrics;
+mod oneshot;
+mod postprocess;
+mod preset_store;
+mod presets;
+mod queue;
+mod ratelimit;
+mod response_cache;
+mod retrieval;
+mod server;
+mod tools;
+
 use anyhow::Result;
-use llama_cpp_2::{
-    context::params::LlamaContextParams,
-    llama_backend::LlamaBackend,
-    llama_batch::LlamaBatch,
-    model::params::LlamaModelParams,
-    model::LlamaModel,
-    context::LlamaContext,
-};
+use llama_cpp_2::llama_backend::LlamaBackend;
 use std::io::{self, Write};
 use std::fs;
-use std::sync::{Arc, Mutex};
 use crossterm::{
     event::{self, Event, KeyCode, KeyEvent},
     terminal::{disable_raw_mode, enable_raw_mode},
     execute,
     cursor,
 };
-use axum::{
-    extract::State,
-    http::StatusCode,
-    response::IntoResponse,
-    routing::post,
-    Json, Router,
-};
-use serde::{Deserialize, Serialize};
-use tower_http::cors::CorsLayer;
+use cli::CliArgs;
+use engine::GenerationParams;
+use presets::load_presets;
 
 fn select_model(models: &[String]) -> Result<Option<String>> {
     // Clear screen
@@ -120,344 +132,63 @@ fn select_model(models: &[String]) -> Result<Option<String>> {
     }
 }
 
-#[derive(Deserialize, Serialize, Clone)]
-struct Preset {
-    name: String,
-    description: String,
-    #[serde(default)]
-    system_prompt: String,
-    #[serde(default)]
-    instruction: Option<String>,
-    #[serde(default)]
-    examples: Option<Vec<PromptExample>>,
-    #[serde(default)]
-    negative_prompt: Option<String>,
-    #[serde(default)]
-    response_format: Option<String>,
-    max_tokens: usize,
-    stop_on_newline: bool,
-    #[serde(default)]
-    include_current_date: bool,
-}
-
-#[derive(Deserialize, Serialize, Clone)]
-struct PromptExample {
-    input: String,
-    output: String,
-}
-
-impl Preset {
-    fn build_full_prompt(&self, user_input: &str) -> String {
-        let mut parts = Vec::new();
-        
-        // System prompt (main role)
-        if !self.system_prompt.is_empty() {
-            parts.push(self.system_prompt.clone());
-        }
-        
-        // Current date (if required)
-        if self.include_current_date {
-            use chrono::{Local, Datelike};
-            let now = Local::now();
-            let date_str = now.format("%d.%m.%Y").to_string();
-            let weekday = match now.date_naive().weekday() {
-                chrono::Weekday::Mon => "понедельник",
-                chrono::Weekday::Tue => "вторник",
-                chrono::Weekday::Wed => "среда",
-                chrono::Weekday::Thu => "четверг",
-                chrono::Weekday::Fri => "пятница",
-                chrono::Weekday::Sat => "суббота",
-                chrono::Weekday::Sun => "воскресенье",
-            };
-            parts.push(format!("Сегодня: {} ({})", date_str, weekday));
-        }
-        
-        // Instruction (detailed instructions)
-        if let Some(instruction) = &self.instruction {
-            parts.push(instruction.clone());
-        }
-        
-        // Examples (few-shot examples)
-        if let Some(examples) = &self.examples {
-            if !examples.is_empty() {
-                let examples_text = examples.iter()
-                    .map(|ex| format!("Вход: {}\nВыход: {}", ex.input, ex.output))
-                    .collect::<Vec<_>>()
-                    .join("\n\n");
-                parts.push(format!("Примеры:\n{}", examples_text));
-            }
-        }
-        
-        // Negative prompt (what NOT to do)
-        if let Some(negative) = &self.negative_prompt {
-            parts.push(format!("НЕ ДЕЛАЙ: {}", negative));
-        }
-        
-        // Response format (output format)
-        if let Some(format) = &self.response_format {
-            parts.push(format!("Формат ответа: {}", format));
-        }
-        
-        // Combine everything together
-        let full_system = parts.join("\n\n");
-        
-        // Add user input
-        format!("{}\n\nВход: {}\nВыход:", full_system, user_input)
-    }
-}
-
-#[derive(Deserialize, Serialize)]
-struct PresetsConfig {
-    presets: Vec<Preset>,
-}
-
-#[derive(Deserialize)]
-struct ChatRequest {
-    prompt: String,
-    #[serde(default)]
-    system_prompt: Option<String>,
-    #[serde(default)]
-    max_tokens: Option<usize>,
-    #[serde(default)]
-    model: Option<String>,
-    #[serde(default)]
-    preset: Option<String>,
-}
-
-#[derive(Serialize)]
-struct ChatResponse {
-    response: String,
-}
-
-#[derive(Serialize)]
-struct ModelsResponse {
-    models: Vec<String>,
-}
-
-#[derive(Serialize)]
-struct PresetsResponse {
-    presets: Vec<PresetInfo>,
-}
-
-#[derive(Serialize)]
-struct PresetInfo {
-    name: String,
-    description: String,
-}
-
-struct AppState {
-    backend: LlamaBackend,
-    available_models: Vec<String>,
-}
-
-fn load_presets() -> Vec<Preset> {
-    match fs::read_to_string("presets.json") {
-        Ok(content) => {
-            match serde_json::from_str::<PresetsConfig>(&content) {
-                Ok(config) => config.presets,
-                Err(e) => {
-                    eprintln!("Ошибка парсинга presets.json: {}", e);
-                    vec![]
+/// Finds all .gguf files in the current directory
+fn find_models() -> Result<Vec<String>> {
+    let mut models = Vec::new();
+    for entry in fs::read_dir(".")? {
+        let entry = entry?;
+        let path = entry.path();
+        if let Some(ext) = path.extension() {
+            if ext == "gguf" {
+                if let Some(name) = path.file_name() {
+                    models.push(name.to_string_lossy().to_string());
                 }
             }
         }
-        Err(_) => {
-            eprintln!("Файл presets.json не найден");
-            vec![]
-        }
     }
+    models.sort();
+    Ok(models)
 }
 
-async fn models_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    (StatusCode::OK, Json(ModelsResponse { 
-        models: state.available_models.clone() 
-    }))
-}
-
-async fn presets_handler(_state: State<Arc<AppState>>) -> impl IntoResponse {
-    let presets = load_presets();
-    let presets_info: Vec<PresetInfo> = presets.iter()
-        .map(|p| PresetInfo {
-            name: p.name.clone(),
-            description: p.description.clone(),
-        })
-        .collect();
-    
-    (StatusCode::OK, Json(PresetsResponse { presets: presets_info }))
-}
-
-async fn chat_handler(
-    State(state): State<Arc<AppState>>,
-    Json(req): Json<ChatRequest>,
-) -> impl IntoResponse {
-    let model_name = req.model.as_ref()
-        .or_else(|| state.available_models.first())
-        .cloned()
-        .unwrap_or_default();
-
-    if !state.available_models.contains(&model_name) {
-        return (StatusCode::BAD_REQUEST, Json(ChatResponse { 
-            response: format!("Model '{}' not found. Available models: {:?}", model_name, state.available_models) 
-        }));
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let cli = CliArgs::parse(&args, &["server", "json", "no-examples", "overwrite", "no-normalize", "no-special"])?;
+    let server_mode = cli.flag("server");
+    // Only the server logs at info level by default; other modes stay quiet unless something goes wrong
+    logging::init(&cli, if server_mode { "info" } else { "warn" })?;
+
+    if cli.command.as_deref() == Some("hash-key") {
+        return auth::hash_key_command(&cli);
     }
-
-    // Load presets on each request (so changes apply without restart)
-    let presets = load_presets();
-    
-    // Determine parameters from preset or request
-    let (prompt, max_tokens, stop_on_newline) = if let Some(preset_name) = &req.preset {
-        if let Some(preset) = presets.iter().find(|p| p.name == *preset_name) {
-            (
-                preset.build_full_prompt(&req.prompt),
-                req.max_tokens.unwrap_or(preset.max_tokens),
-                preset.stop_on_newline,
-            )
-        } else {
-            return (StatusCode::BAD_REQUEST, Json(ChatResponse { 
-                response: format!("Preset '{}' not found. Use /presets to see available presets", preset_name) 
-            }));
-        }
-    } else {
-        let system_prompt = req.system_prompt.clone().unwrap_or_default();
-        let prompt = if system_prompt.is_empty() {
-            req.prompt.clone()
-        } else {
-            format!("{}\n\n{}", system_prompt, req.prompt)
-        };
-        (
-            prompt,
-            req.max_tokens.unwrap_or(100),
-            false,
-        )
-    };
-
-    // Load model for each request (simplified version)
-    let model_params = LlamaModelParams::default().with_n_gpu_layers(0);
-    let model = match LlamaModel::load_from_file(&state.backend, &model_name, &model_params) {
-        Ok(m) => m,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(ChatResponse { 
-            response: format!("Failed to load model: {}", e) 
-        })),
-    };
-
-    let ctx_params = LlamaContextParams::default()
-        .with_n_ctx(std::num::NonZero::new(2048));
-    
-    let mut ctx = match model.new_context(&state.backend, ctx_params) {
-        Ok(c) => c,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(ChatResponse { 
-            response: format!("Failed to create context: {}", e) 
-        })),
-    };
-
-    ctx.clear_kv_cache();
-
-    let tokens = match model.str_to_token(&prompt, llama_cpp_2::model::AddBos::Always) {
-        Ok(t) => t,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(ChatResponse { 
-            response: format!("Tokenization error: {}", e) 
-        })),
-    };
-
-    let mut batch = LlamaBatch::new(512, 1);
-    let last_index = tokens.len() - 1;
-    for (i, token) in tokens.iter().enumerate() {
-        let is_last = i == last_index;
-        if let Err(e) = batch.add(*token, i as i32, &[0], is_last) {
-            return (StatusCode::INTERNAL_SERVER_ERROR, Json(ChatResponse { 
-                response: format!("Batch error: {}", e) 
-            }));
-        }
+    if cli.command.as_deref() == Some("audit") {
+        return audit::run_command(&cli);
     }
 
-    if let Err(e) = ctx.decode(&mut batch) {
-        return (StatusCode::INTERNAL_SERVER_ERROR, Json(ChatResponse { 
-            response: format!("Decode error: {}", e) 
-        }));
+    // Embeddings go to stdout as JSONL, so the banner is skipped
+    if cli.command.as_deref() == Some("embed") {
+        let backend = LlamaBackend::init()?;
+        return embeddings::run_command(&backend, &find_models()?, &cli);
     }
 
-    let mut result = String::new();
-    let mut pos = tokens.len() as i32;
-
-    for _ in 0..max_tokens {
-        let candidates = ctx.candidates_ith(batch.n_tokens() - 1);
-        
-        let token = candidates
-            .max_by(|a, b| a.logit().partial_cmp(&b.logit()).unwrap())
-            .map(|c| c.id())
-            .unwrap();
-
-        if token == model.token_eos() {
-            break;
-        }
-
-        let piece = match model.token_to_str(token, llama_cpp_2::model::Special::Tokenize) {
-            Ok(p) => p,
-            Err(_) => {
-                // Skip tokens with decoding errors (incomplete UTF-8 sequences)
-                batch.clear();
-                if let Err(e) = batch.add(token, pos, &[0], true) {
-                    return (StatusCode::INTERNAL_SERVER_ERROR, Json(ChatResponse { 
-                        response: format!("Batch add error: {}", e) 
-                    }));
-                }
-                pos += 1;
-                
-                if let Err(e) = ctx.decode(&mut batch) {
-                    return (StatusCode::INTERNAL_SERVER_ERROR, Json(ChatResponse { 
-                        response: format!("Decode error: {}", e) 
-                    }));
-                }
-                continue;
-            }
-        };
-        
-        result.push_str(&piece);
-
-        if stop_on_newline && result.contains('\n') {
-            break;
-        }
-
-        batch.clear();
-        if let Err(e) = batch.add(token, pos, &[0], true) {
-            return (StatusCode::INTERNAL_SERVER_ERROR, Json(ChatResponse { 
-                response: format!("Batch add error: {}", e) 
-            }));
-        }
-        pos += 1;
-        
-        if let Err(e) = ctx.decode(&mut batch) {
-            return (StatusCode::INTERNAL_SERVER_ERROR, Json(ChatResponse { 
-                response: format!("Decode error: {}", e) 
-            }));
-        }
+    // Prompt debugging commands print tokens or the rendered prompt to stdout
+    if let Some(command @ ("tokenize" | "detokenize" | "render")) = cli.command.as_deref() {
+        let backend = LlamaBackend::init()?;
+        return inspect::run_command(command, &backend, &find_models()?, &cli);
     }
 
-    (StatusCode::OK, Json(ChatResponse { response: result.trim().to_string() }))
-}
+    // One-shot / pipe mode: no banner, only the model output on stdout
+    if oneshot::requested(&cli) {
+        let models = find_models().unwrap_or_default();
+        std::process::exit(oneshot::run(&models, &cli));
+    }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    let server_mode = args.len() > 1 && args[1] == "--server";
     const VERSION: &str = env!("CARGO_PKG_VERSION");
     println!("CHAT-NP v{}", VERSION);
     println!("Local LLM Chat with Preset System & REST API");
     println!();
     
-    // Find all .gguf files in current directory
-    let mut models = Vec::new();
-    for entry in fs::read_dir(".")? {
-        let entry = entry?;
-        let path = entry.path();
-        if let Some(ext) = path.extension() {
-            if ext == "gguf" {
-                if let Some(name) = path.file_name() {
-                    models.push(name.to_string_lossy().to_string());
-                }
-            }
-        }
-    }
+    let models = find_models()?;
     
     if models.is_empty() {
         println!("Ошибка: не найдено ни одного .gguf файла в текущей директории!");
@@ -467,52 +198,26 @@ async fn main() -> Result<()> {
 
     let backend = LlamaBackend::init()?;
 
+    match cli.command.as_deref() {
+        Some("eval") => return eval::run_command(&backend, &models, &cli),
+        Some("run") => return batch::run_command(&backend, &models, &cli),
+        Some("index") => return retrieval::run_command(&backend, &models, &cli),
+        Some(other) => anyhow::bail!("неизвестная команда: {}", other),
+        None => {}
+    }
+
     if server_mode {
-        println!("Найдено моделей: {}", models.len());
-        for model in &models {
-            println!("  - {}", model);
-        }
-        
-        let presets = load_presets();
-        println!("\nЗагружено пресетов: {}", presets.len());
-        for preset in &presets {
-            println!("  - {} ({})", preset.name, preset.description);
+        let mut config = config::Config::load(cli.value("config"))?;
+        let listen = cli.values("listen");
+        if !listen.is_empty() {
+            config.server.listen = listen;
         }
-        
-        println!("\nПресеты будут автоматически перечитываться из presets.json при каждом запросе");
-        println!("Запуск веб-сервера...\n");
-        
-        let state = Arc::new(AppState {
-            backend,
-            available_models: models.clone(),
-        });
-
-        let app = Router::new()
-            .route("/models", axum::routing::get(models_handler))
-            .route("/presets", axum::routing::get(presets_handler))
-            .route("/chat", post(chat_handler))
-            .layer(CorsLayer::permissive())
-            .with_state(state);
-
-        let addr = "127.0.0.1:3000";
-        println!("Сервер запущен на http://{}", addr);
-        println!("\nДоступные эндпоинты:");
-        println!("  GET  /models  - список доступных моделей");
-        println!("  GET  /presets - список доступных пресетов");
-        println!("  POST /chat    - отправка запроса к модели");
-        println!("\nПримеры запросов:");
-        println!(r#"curl http://127.0.0.1:3000/models"#);
-        println!(r#"curl http://127.0.0.1:3000/presets"#);
-        println!(r#"curl -X POST http://127.0.0.1:3000/chat -H "Content-Type: application/json" -d '{{"prompt": "iPhone 15", "preset": "price_classifier"}}'"#);
-        println!(r#"curl -X POST http://127.0.0.1:3000/chat -H "Content-Type: application/json" -d '{{"prompt": "Что такое Rust?", "preset": "assistant"}}'"#);
-        println!("\nДля остановки нажмите Ctrl+C\n");
-
-        let listener = tokio::net::TcpListener::bind(addr).await?;
-        axum::serve(listener, app).await?;
-        
-        return Ok(());
+        return server::run(backend, models, config).await;
     }
     
+    // Indexes for presets with `retrieval` stay loaded between sessions
+    let retriever = retrieval::Retriever::default();
+
     // Main loop to allow returning to model selection
     loop {
         let model_path = if models.len() == 1 {
@@ -527,14 +232,10 @@ async fn main() -> Result<()> {
         
         println!("Загрузка модели {}...", model_path);
         
-        let model_params = LlamaModelParams::default()
-            .with_n_gpu_layers(0);
-        let model = LlamaModel::load_from_file(&backend, &model_path, &model_params)?;
-
-    let ctx_params = LlamaContextParams::default()
-        .with_n_ctx(std::num::NonZero::new(2048));
-    
-    let mut ctx = model.new_context(&backend, ctx_params)?;
+        // The session's cache also serves retrieval, so an index built with this model does not load it twice
+        let session_models = engine::ModelCache::default();
+        let model = session_models.get_or_load(&backend, &model_path)?;
+    let mut ctx = engine::new_context(&model, &backend)?;
     
     // Load presets and let user choose
     let presets = load_presets();
@@ -657,6 +358,15 @@ async fn main() -> Result<()> {
         }
     };
 
+        // Apply per-model overrides for the loaded model
+        let selected_preset = selected_preset.map(|preset| {
+            if !preset.allows_model(&model_path) {
+                println!("Внимание: пресет {} не рассчитан на модель {} (разрешены: {})\n",
+                    preset.name, model_path, preset.allowed_models.join(", "));
+            }
+            preset.for_model(&model_path)
+        });
+
         if let Some(ref preset) = selected_preset {
             println!("Примеры запросов для этого пресета:");
             if let Some(examples) = &preset.examples {
@@ -684,81 +394,41 @@ async fn main() -> Result<()> {
                 break;
             }
 
-            // Clear KV cache before each request
-            ctx.clear_kv_cache();
-
-            let prompt = if let Some(ref preset) = selected_preset {
-                preset.build_full_prompt(input)
-            } else {
-                input.to_string()
-            };
-
-            let tokens = model.str_to_token(&prompt, llama_cpp_2::model::AddBos::Always)?;
-
-            let mut batch = LlamaBatch::new(512, 1);
-            let last_index = tokens.len() - 1;
-            for (i, token) in tokens.iter().enumerate() {
-                let is_last = i == last_index;
-                batch.add(*token, i as i32, &[0], is_last)?;
-            }
-
-            ctx.decode(&mut batch)?;
-
-            let mut result = String::new();
-            let mut pos = tokens.len() as i32;
-            
-            // Use max_tokens and stop_on_newline from preset if available
-            let max_tokens = if let Some(ref preset) = selected_preset {
-                preset.max_tokens
-            } else {
-                100
-            };
-            
-            let stop_on_newline = if let Some(ref preset) = selected_preset {
-                preset.stop_on_newline
+            let (prompt, mut params) = if let Some(ref preset) = selected_preset {
+                let context = retriever.context(&session_models, &backend, preset, input)?;
+                (engine::render_prompt(&model, preset, input, context.as_deref())?, GenerationParams::from_preset(preset))
             } else {
-                false
+                (input.to_string(), GenerationParams::default())
             };
-            
-            for _ in 0..max_tokens {
-                let candidates = ctx.candidates_ith(batch.n_tokens() - 1);
-                
-                let token = candidates
-                    .max_by(|a, b| a.logit().partial_cmp(&b.logit()).unwrap())
-                    .map(|c| c.id())
-                    .unwrap();
-
-                if token == model.token_eos() {
-                    break;
-                }
-
-                let piece = model.token_to_str(token, llama_cpp_2::model::Special::Tokenize)?;
-                result.push_str(&piece);
-
-                // Stop on newline if configured
-                if stop_on_newline && result.contains('\n') {
-                    break;
-                }
-                
-                // Stop after complete JSON object (for JSON presets)
-                let trimmed = result.trim();
-                if trimmed.starts_with('{') && trimmed.ends_with('}') {
-                    // Check if it's a complete JSON by counting braces
-                    let open_braces = trimmed.chars().filter(|&c| c == '{').count();
-                    let close_braces = trimmed.chars().filter(|&c| c == '}').count();
-                    if open_braces == close_braces && open_braces > 0 {
-                        break;
+            // Stop after complete JSON object (for JSON presets)
+            params.stop_on_json = true;
+
+            let generated = postprocess::generate_validated(selected_preset.as_ref(), &params, |params| {
+                engine::generate(&model, &mut ctx, &prompt, params)
+            });
+            let (generation, parsed) = match generated {
+                Ok(result) => result,
+                Err(e) => match e.downcast::<postprocess::ValidationFailed>() {
+                    Ok(failed) => {
+                        println!("→ {}", failed.response);
+                        println!("  Ответ не прошёл проверку ({} попыток): {}\n", failed.attempts, failed.message);
+                        continue;
                     }
-                }
+                    Err(e) => return Err(e),
+                },
+            };
 
-                batch.clear();
-                batch.add(token, pos, &[0], true)?;
-                pos += 1;
-                ctx.decode(&mut batch)?;
+            println!("→ {}", generation.text);
+            for label in &generation.probabilities {
+                println!("  {}: {:.1}%", label.label, label.probability * 100.0);
             }
-
-            // Trim whitespace
-            println!("→ {}\n", result.trim());
+            if let Some(value) = parsed.text() {
+                println!("  Результат: {}", value);
+            }
+            if let Some(error) = parsed.parse_error {
+                println!("  Не удалось обработать ответ: {}", error);
+            }
+            println!();
         }
         
         // Inner loop finished, continue outer loop for new model selection
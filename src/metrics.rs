@@ -0,0 +1,269 @@
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::engine::Generation;
+use crate::server::AppState;
+
+/// Upper bounds (seconds) of the latency histogram buckets
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+/// Model and preset of a request, attached by handlers to the response for [`track`]
+#[derive(Clone, Default)]
+pub struct RequestLabels {
+    pub model: String,
+    pub preset: String,
+}
+
+impl RequestLabels {
+    pub fn new(model: &str, preset: Option<&str>) -> Self {
+        Self {
+            model: model.to_string(),
+            preset: preset.unwrap_or_default().to_string(),
+        }
+    }
+
+    pub fn apply(self, mut response: Response) -> Response {
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, value: Duration) {
+        let secs = value.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (count, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, sep, self.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    /// (endpoint, preset, model, status) -> count
+    requests: BTreeMap<(String, String, String, u16), u64>,
+    /// endpoint -> latency
+    request_duration: BTreeMap<String, Histogram>,
+    /// model -> tokens
+    prompt_tokens: BTreeMap<String, u64>,
+    completion_tokens: BTreeMap<String, u64>,
+    /// model -> time
+    prompt_eval: BTreeMap<String, Histogram>,
+    generation: BTreeMap<String, Histogram>,
+}
+
+/// In-process metrics rendered in the Prometheus text format
+pub struct Metrics {
+    started: Instant,
+    counters: Mutex<Counters>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            counters: Mutex::new(Counters::default()),
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Metrics {
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    fn record_request(&self, endpoint: &str, labels: &RequestLabels, status: StatusCode, duration: Duration) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters.requests
+            .entry((endpoint.to_string(), labels.preset.clone(), labels.model.clone(), status.as_u16()))
+            .or_default() += 1;
+        counters.request_duration.entry(endpoint.to_string()).or_default().observe(duration);
+    }
+
+    /// Records tokens and timings of a finished generation
+    pub fn record_generation(&self, model: &str, generation: &Generation) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters.prompt_tokens.entry(model.to_string()).or_default() += generation.prompt_tokens as u64;
+        *counters.completion_tokens.entry(model.to_string()).or_default() += generation.completion_tokens as u64;
+        counters.prompt_eval.entry(model.to_string()).or_default().observe(generation.prompt_eval_time);
+        counters.generation.entry(model.to_string()).or_default().observe(generation.generation_time);
+    }
+
+    fn render(&self, state: &AppState) -> String {
+        let mut out = String::new();
+        let counters = self.counters.lock().unwrap();
+
+        let _ = writeln!(out, "# HELP chat_np_uptime_seconds Time since the server started");
+        let _ = writeln!(out, "# TYPE chat_np_uptime_seconds gauge");
+        let _ = writeln!(out, "chat_np_uptime_seconds {}", self.uptime().as_secs_f64());
+
+        let _ = writeln!(out, "# HELP chat_np_requests_total Generation requests by endpoint, preset, model and status");
+        let _ = writeln!(out, "# TYPE chat_np_requests_total counter");
+        for ((endpoint, preset, model, status), count) in &counters.requests {
+            let _ = writeln!(
+                out,
+                "chat_np_requests_total{{endpoint=\"{}\",preset=\"{}\",model=\"{}\",status=\"{}\"}} {}",
+                escape(endpoint), escape(preset), escape(model), status, count
+            );
+        }
+
+        let _ = writeln!(out, "# HELP chat_np_request_duration_seconds Generation request latency");
+        let _ = writeln!(out, "# TYPE chat_np_request_duration_seconds histogram");
+        for (endpoint, histogram) in &counters.request_duration {
+            histogram.render(&mut out, "chat_np_request_duration_seconds", &format!("endpoint=\"{}\"", escape(endpoint)));
+        }
+
+        let _ = writeln!(out, "# HELP chat_np_queue_waiting Tasks waiting in the inference queue");
+        let _ = writeln!(out, "# TYPE chat_np_queue_waiting gauge");
+        let _ = writeln!(out, "chat_np_queue_waiting {}", state.queue.waiting());
+        let _ = writeln!(out, "# HELP chat_np_queue_running Tasks running on inference workers");
+        let _ = writeln!(out, "# TYPE chat_np_queue_running gauge");
+        let _ = writeln!(out, "chat_np_queue_running {}", state.queue.running());
+
+        for (name, help, values) in [
+            ("chat_np_prompt_tokens_total", "Prompt tokens processed", &counters.prompt_tokens),
+            ("chat_np_generated_tokens_total", "Tokens generated", &counters.completion_tokens),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (model, count) in values {
+                let _ = writeln!(out, "{}{{model=\"{}\"}} {}", name, escape(model), count);
+            }
+        }
+
+        for (name, help, values) in [
+            ("chat_np_prompt_eval_seconds", "Time spent decoding the prompt", &counters.prompt_eval),
+            ("chat_np_generation_seconds", "Time spent generating tokens", &counters.generation),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} histogram", name);
+            for (model, histogram) in values {
+                histogram.render(&mut out, name, &format!("model=\"{}\"", escape(model)));
+            }
+        }
+
+        let cache = state.cache.stats();
+        for (name, kind, help, value) in [
+            ("chat_np_response_cache_hits_total", "counter", "Requests answered from the response cache", cache.hits),
+            ("chat_np_response_cache_misses_total", "counter", "Cacheable requests that had to be generated", cache.misses),
+            ("chat_np_response_cache_entries", "gauge", "Responses currently cached", cache.entries as u64),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        let _ = writeln!(out, "# HELP chat_np_model_size_bytes Tensor size of loaded models");
+        let _ = writeln!(out, "# TYPE chat_np_model_size_bytes gauge");
+        for (model, size) in state.models.loaded_sizes() {
+            let _ = writeln!(out, "chat_np_model_size_bytes{{model=\"{}\"}} {}", escape(&model), size);
+        }
+
+        out
+    }
+}
+
+/// Counts requests and their latency; labels come from [`RequestLabels`] on the response
+pub async fn track(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let endpoint = request.extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let labels = response.extensions().get::<RequestLabels>().cloned().unwrap_or_default();
+    state.metrics.record_request(&endpoint, &labels, response.status(), started.elapsed());
+    response
+}
+
+/// Whether the default model has been loaded
+pub enum Readiness {
+    Loading,
+    Ready,
+    Failed(String),
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    status: &'static str,
+    uptime_secs: u64,
+}
+
+#[derive(Serialize)]
+struct ReadyResponse {
+    status: &'static str,
+    model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// GET /health: the process is up and serving HTTP
+pub async fn health_handler(State(state): State<Arc<AppState>>) -> Response {
+    (StatusCode::OK, Json(HealthResponse {
+        status: "ok",
+        uptime_secs: state.metrics.uptime().as_secs(),
+    })).into_response()
+}
+
+/// GET /ready: 200 once the default model is loaded, 503 before that or if loading failed
+pub async fn ready_handler(State(state): State<Arc<AppState>>) -> Response {
+    let model = state.available_models.first().cloned().unwrap_or_default();
+    let (status, body) = match &*state.readiness.lock().unwrap() {
+        Readiness::Ready => (StatusCode::OK, ReadyResponse { status: "ready", model, error: None }),
+        Readiness::Loading => (StatusCode::SERVICE_UNAVAILABLE, ReadyResponse { status: "loading", model, error: None }),
+        Readiness::Failed(e) => (StatusCode::SERVICE_UNAVAILABLE, ReadyResponse { status: "failed", model, error: Some(e.clone()) }),
+    };
+    (status, Json(body)).into_response()
+}
+
+/// GET /metrics in the Prometheus text exposition format
+pub async fn metrics_handler(State(state): State<Arc<AppState>>) -> Response {
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(&state),
+    ).into_response()
+}
@@ -0,0 +1,192 @@
+use anyhow::Result;
+use llama_cpp_2::llama_backend::LlamaBackend;
+use serde::Serialize;
+use std::io::{self, IsTerminal, Read};
+use std::time::{Duration, Instant};
+
+use crate::cli::CliArgs;
+use crate::engine::{self, FinishReason, GenerationParams, LabelProbability};
+use crate::postprocess::{self, Parsed, ValidationFailed};
+use crate::presets::load_presets;
+use crate::retrieval::Retriever;
+
+/// Exit code for invalid arguments, unknown preset/model or empty input
+pub const EXIT_USAGE: i32 = 2;
+/// Exit code when no model is found or it fails to load
+pub const EXIT_MODEL: i32 = 3;
+/// Exit code when generation itself fails
+pub const EXIT_GENERATION: i32 = 4;
+/// Exit code when the output fails the preset's `output_parser` after all retries
+pub const EXIT_VALIDATION: i32 = 5;
+
+/// Checks whether the arguments ask for one-shot mode instead of the menus:
+/// a prompt, preset or model was given, or stdin is not a terminal
+pub fn requested(cli: &CliArgs) -> bool {
+    cli.command.is_none()
+        && !cli.flag("server")
+        && (cli.value("prompt").is_some()
+            || cli.value("preset").is_some()
+            || cli.value("model").is_some()
+            || !io::stdin().is_terminal())
+}
+
+#[derive(Serialize)]
+struct OneShotOutput {
+    model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preset: Option<String>,
+    response: String,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    finish_reason: FinishReason,
+    duration_ms: u128,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    probabilities: Vec<LabelProbability>,
+    #[serde(flatten)]
+    parsed: Parsed,
+}
+
+#[derive(Serialize)]
+struct OneShotError {
+    error: String,
+    exit_code: i32,
+}
+
+struct Failure {
+    code: i32,
+    message: String,
+}
+
+fn fail(code: i32, message: impl Into<String>) -> Failure {
+    Failure { code, message: message.into() }
+}
+
+/// Runs a single request and returns the process exit code.
+///
+/// Only the model output (or JSON with `--json`) goes to stdout; diagnostics go to stderr.
+pub fn run(available_models: &[String], cli: &CliArgs) -> i32 {
+    let json = cli.flag("json");
+    match execute(available_models, cli) {
+        Ok(output) => {
+            if json {
+                println!("{}", serde_json::to_string(&output).unwrap_or_default());
+            } else {
+                // Presets with `postprocess` or `output_parser` print the parsed value instead of the raw output
+                println!("{}", output.parsed.text().unwrap_or(output.response));
+            }
+            0
+        }
+        Err(failure) => {
+            eprintln!("Ошибка: {}", failure.message);
+            if json {
+                let error = OneShotError { error: failure.message, exit_code: failure.code };
+                println!("{}", serde_json::to_string(&error).unwrap_or_default());
+            }
+            failure.code
+        }
+    }
+}
+
+fn read_prompt(cli: &CliArgs) -> Result<String, Failure> {
+    if let Some(prompt) = cli.value("prompt") {
+        return Ok(prompt.to_string());
+    }
+    let stdin = io::stdin();
+    if stdin.is_terminal() {
+        return Err(fail(EXIT_USAGE, "не указан запрос: передайте --prompt или текст через stdin"));
+    }
+    let mut input = String::new();
+    stdin.lock().read_to_string(&mut input)
+        .map_err(|e| fail(EXIT_USAGE, format!("не удалось прочитать stdin: {}", e)))?;
+    Ok(input)
+}
+
+fn execute(available_models: &[String], cli: &CliArgs) -> Result<OneShotOutput, Failure> {
+    let prompt = read_prompt(cli)?;
+    let prompt = prompt.trim();
+    if prompt.is_empty() {
+        return Err(fail(EXIT_USAGE, "пустой запрос"));
+    }
+
+    let preset = match cli.value("preset") {
+        Some(name) => match load_presets().into_iter().find(|p| p.name == name) {
+            Some(p) => Some(p),
+            None => return Err(fail(EXIT_USAGE, format!("пресет '{}' не найден", name))),
+        },
+        None => None,
+    };
+
+    if available_models.is_empty() {
+        return Err(fail(EXIT_MODEL, "не найдено ни одного .gguf файла в текущей директории"));
+    }
+    let model_name = cli.value("model").map(str::to_string)
+        .or_else(|| preset.as_ref().and_then(|p| p.preferred_model(available_models)))
+        .or_else(|| available_models.first().cloned())
+        .unwrap_or_default();
+    if !available_models.contains(&model_name) {
+        return Err(fail(EXIT_USAGE, format!("модель '{}' не найдена. Доступные модели: {:?}", model_name, available_models)));
+    }
+    if let Some(p) = &preset {
+        if !p.allows_model(&model_name) {
+            return Err(fail(EXIT_USAGE, format!("пресет '{}' не разрешает модель '{}'", p.name, model_name)));
+        }
+    }
+    let preset = preset.map(|p| p.for_model(&model_name));
+
+    let max_tokens = cli.parse_value::<usize>("max-tokens")
+        .map_err(|e| fail(EXIT_USAGE, format!("{:#}", e)))?;
+    let max_time_ms = cli.parse_value::<u64>("max-time-ms")
+        .map_err(|e| fail(EXIT_USAGE, format!("{:#}", e)))?;
+
+    let backend = LlamaBackend::init()
+        .map_err(|e| fail(EXIT_MODEL, format!("не удалось инициализировать llama.cpp: {}", e)))?;
+    // Retrieval embeddings come from the same cache, so the chat model is not loaded twice
+    let models = engine::ModelCache::default();
+    let model = models.get_or_load(&backend, &model_name)
+        .map_err(|e| fail(EXIT_MODEL, format!("{:#}", e)))?;
+    let mut ctx = engine::new_context(&model, &backend)
+        .map_err(|e| fail(EXIT_MODEL, format!("{:#}", e)))?;
+
+    let (full_prompt, mut params) = match &preset {
+        Some(p) => (
+            Retriever::default().context(&models, &backend, p, prompt)
+                .and_then(|context| engine::render_prompt(&model, p, prompt, context.as_deref()))
+                .map_err(|e| fail(EXIT_GENERATION, format!("{:#}", e)))?,
+            GenerationParams::from_preset(p),
+        ),
+        None => {
+            let full = match cli.value("system-prompt") {
+                Some(system) if !system.is_empty() => format!("{}\n\n{}", system, prompt),
+                _ => prompt.to_string(),
+            };
+            (full, GenerationParams::default())
+        }
+    };
+    if let Some(max_tokens) = max_tokens {
+        params.max_tokens = max_tokens;
+    }
+    if let Some(ms) = max_time_ms {
+        params.max_time = Some(Duration::from_millis(ms));
+    }
+
+    let started = Instant::now();
+    let (generation, parsed) = postprocess::generate_validated(preset.as_ref(), &params, |params| {
+        engine::generate(&model, &mut ctx, &full_prompt, params)
+    })
+    .map_err(|e| {
+        let code = if e.is::<ValidationFailed>() { EXIT_VALIDATION } else { EXIT_GENERATION };
+        fail(code, format!("{:#}", e))
+    })?;
+
+    Ok(OneShotOutput {
+        model: model_name,
+        preset: preset.map(|p| p.name),
+        response: generation.text,
+        prompt_tokens: generation.prompt_tokens,
+        completion_tokens: generation.completion_tokens,
+        finish_reason: generation.finish_reason,
+        duration_ms: started.elapsed().as_millis(),
+        probabilities: generation.probabilities,
+        parsed,
+    })
+}
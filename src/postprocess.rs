@@ -0,0 +1,738 @@
+use anyhow::{bail, Context, Result};
+use chrono::{Datelike, Days, Local, Months, NaiveDate, Weekday};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tracing::warn;
+
+use crate::engine::{FinishReason, Generation, GenerationParams, SamplingParams};
+use crate::eval;
+use crate::presets::Preset;
+
+/// Temperature for retries of greedy presets; greedy decoding would repeat the same output
+const RETRY_TEMPERATURE: f32 = 0.7;
+
+/// Deterministic stage applied to a preset's output after generation
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PostProcess {
+    /// The output is a date or a relative expression ("послезавтра", "в следующую пятницу");
+    /// it is resolved against the current date to `YYYY-MM-DD`
+    Date,
+}
+
+impl PostProcess {
+    fn apply(self, output: &str) -> Result<serde_json::Value> {
+        match self {
+            PostProcess::Date => {
+                let date = resolve_date(output, Local::now().date_naive())?;
+                Ok(serde_json::Value::String(date.format("%Y-%m-%d").to_string()))
+            }
+        }
+    }
+}
+
+/// Checks the output of a preset and turns it into structured fields
+#[derive(Deserialize, Serialize, Clone)]
+pub struct OutputParser {
+    #[serde(flatten)]
+    pub kind: ParserKind,
+    /// Extra generations when the output fails validation
+    #[serde(default)]
+    pub retries: u32,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ParserKind {
+    /// The output must match; named groups become the fields of `parsed`
+    Regex { pattern: String },
+    /// The output must contain a JSON value
+    Json,
+    /// The output must be one of the labels (case-insensitive)
+    Enum { labels: Vec<String> },
+    /// The output must be a number within the optional bounds
+    Number {
+        #[serde(default)]
+        min: Option<f64>,
+        #[serde(default)]
+        max: Option<f64>,
+    },
+}
+
+impl OutputParser {
+    /// Checks the parser settings when presets are loaded
+    pub fn validate(&self) -> Result<()> {
+        match &self.kind {
+            ParserKind::Regex { pattern } => {
+                let re = Regex::new(pattern)
+                    .with_context(|| format!("некорректное регулярное выражение: {}", pattern))?;
+                if re.capture_names().flatten().next().is_none() {
+                    bail!("в регулярном выражении нет именованных групп: {}", pattern);
+                }
+            }
+            ParserKind::Json => {}
+            ParserKind::Enum { labels } => {
+                if labels.is_empty() {
+                    bail!("список меток пуст");
+                }
+                if labels.iter().any(|l| l.trim().is_empty()) {
+                    bail!("метки не могут быть пустыми");
+                }
+                // Labels are matched case-insensitively, so they must differ regardless of case
+                let mut seen = std::collections::HashSet::new();
+                if let Some(duplicate) = labels.iter().find(|l| !seen.insert(l.trim().to_lowercase())) {
+                    bail!("метка '{}' указана дважды", duplicate);
+                }
+            }
+            ParserKind::Number { min: Some(min), max: Some(max) } if min > max => {
+                bail!("min ({}) больше max ({})", min, max);
+            }
+            ParserKind::Number { .. } => {}
+        }
+        Ok(())
+    }
+
+    pub fn parse(&self, output: &str) -> Result<serde_json::Value> {
+        let text = output.trim();
+        match &self.kind {
+            ParserKind::Regex { pattern } => {
+                let re = Regex::new(pattern).context("Invalid output_parser pattern")?;
+                let Some(captures) = re.captures(text) else {
+                    bail!("Output does not match '{}'", pattern);
+                };
+                let fields = re.capture_names()
+                    .flatten()
+                    .map(|name| {
+                        let value = captures.name(name)
+                            .map_or(serde_json::Value::Null, |m| m.as_str().trim().into());
+                        (name.to_string(), value)
+                    })
+                    .collect();
+                Ok(serde_json::Value::Object(fields))
+            }
+            ParserKind::Json => eval::extract_json(text)
+                .context("Output does not contain valid JSON"),
+            ParserKind::Enum { labels } => {
+                let answer = text.trim_end_matches(&['.', '!'][..]).trim().to_lowercase();
+                match labels.iter().find(|label| label.trim().to_lowercase() == answer) {
+                    Some(label) => Ok(label.clone().into()),
+                    None => bail!("Output '{}' is not one of {:?}", text, labels),
+                }
+            }
+            ParserKind::Number { min, max } => {
+                let number = parse_number(text)
+                    .with_context(|| format!("Output '{}' is not a number", text))?;
+                if min.is_some_and(|min| number < min) || max.is_some_and(|max| number > max) {
+                    bail!("Number {} is out of range [{}, {}]",
+                        number,
+                        min.map_or("-inf".to_string(), |m| m.to_string()),
+                        max.map_or("inf".to_string(), |m| m.to_string()));
+                }
+                // Whole numbers stay integers in JSON
+                if number.fract() == 0.0 && number.abs() < 9_007_199_254_740_992.0 {
+                    Ok((number as i64).into())
+                } else {
+                    Ok(number.into())
+                }
+            }
+        }
+    }
+}
+
+/// Parses a number written with digit grouping: `1 234,5`, `1,234.5`, `1.234.567`.
+/// With both separators the last one is the decimal point; a single comma is a decimal comma.
+fn parse_number(text: &str) -> Option<f64> {
+    let compact: String = text.chars()
+        .filter(|c| !c.is_whitespace() && *c != '_' && *c != '\'')
+        .collect();
+    let commas = compact.matches(',').count();
+    let dots = compact.matches('.').count();
+    let normalized = match (commas, dots) {
+        (0, 0) | (0, 1) => compact,
+        (1, 0) => compact.replace(',', "."),
+        (_, 0) => compact.replace(',', ""),
+        (0, _) => compact.replace('.', ""),
+        _ if compact.rfind('.') > compact.rfind(',') => compact.replace(',', ""),
+        _ => compact.replace('.', "").replace(',', "."),
+    };
+    normalized.parse().ok().filter(|n: &f64| n.is_finite())
+}
+
+/// The output still failed the preset's `output_parser` after all retries
+#[derive(Serialize, Debug)]
+pub struct ValidationFailed {
+    /// Always `validation_failed`
+    pub error: &'static str,
+    pub message: String,
+    /// Output of the last attempt
+    pub response: String,
+    pub attempts: u32,
+    /// Tokens of all attempts
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+}
+
+impl fmt::Display for ValidationFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "validation_failed after {} attempt(s): {}", self.attempts, self.message)
+    }
+}
+
+impl std::error::Error for ValidationFailed {}
+
+/// Sampling for retry `attempt`: a shifted seed, and some temperature if the preset is greedy
+fn retry_sampling(sampling: &SamplingParams, attempt: u32) -> SamplingParams {
+    SamplingParams {
+        temperature: if sampling.is_greedy() { Some(RETRY_TEMPERATURE) } else { sampling.temperature },
+        seed: sampling.seed.map(|seed| seed.wrapping_add(attempt)),
+        ..sampling.clone()
+    }
+}
+
+/// Runs `generate` and applies the preset's post-processing. With an `output_parser`,
+/// invalid outputs are generated again up to `retries` times; then `ValidationFailed` is returned.
+///
+/// Tokens of all generated attempts are summed into the returned generation.
+pub fn generate_validated(
+    preset: Option<&Preset>,
+    params: &GenerationParams,
+    mut generate: impl FnMut(&GenerationParams) -> Result<Generation>,
+) -> Result<(Generation, Parsed)> {
+    let Some(parser) = preset.and_then(|p| p.output_parser.as_ref()) else {
+        let generation = generate(params)?;
+        let parsed = Parsed::from_generation(preset, &generation);
+        return Ok((generation, parsed));
+    };
+
+    let mut attempt_params = params.clone();
+    let (mut prompt_tokens, mut completion_tokens) = (0, 0);
+    let mut attempts = 0;
+    loop {
+        let mut generation = generate(&attempt_params)?;
+        attempts += 1;
+        if !generation.cache_hit {
+            prompt_tokens += generation.prompt_tokens;
+            completion_tokens += generation.completion_tokens;
+        }
+        if attempts > 1 {
+            generation.prompt_tokens = prompt_tokens;
+            generation.completion_tokens = completion_tokens;
+        }
+        // A stopped request is not retried
+        if matches!(generation.finish_reason, FinishReason::Cancelled | FinishReason::Timeout) {
+            let parsed = Parsed::from_generation(preset, &generation);
+            return Ok((generation, parsed));
+        }
+        match parser.parse(&generation.text) {
+            Ok(value) => return Ok((generation, Parsed { parsed: Some(value), parse_error: None })),
+            Err(e) if attempts > parser.retries => {
+                return Err(ValidationFailed {
+                    error: "validation_failed",
+                    message: format!("{:#}", e),
+                    response: generation.text,
+                    attempts,
+                    prompt_tokens,
+                    completion_tokens,
+                }.into());
+            }
+            Err(e) => {
+                warn!(
+                    preset = preset.map(|p| p.name.as_str()).unwrap_or_default(),
+                    attempt = attempts,
+                    error = %format!("{:#}", e),
+                    "output failed validation, retrying"
+                );
+                attempt_params.sampling = retry_sampling(&params.sampling, attempts);
+            }
+        }
+    }
+}
+
+/// Result of the preset's post-processing stage, flattened into responses
+#[derive(Serialize, Clone, Default)]
+pub struct Parsed {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parsed: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parse_error: Option<String>,
+}
+
+impl Parsed {
+    /// Runs the preset's `output_parser` or `postprocess` stage; empty for presets without one
+    pub fn from_output(preset: Option<&Preset>, output: &str) -> Self {
+        let result = match preset {
+            Some(Preset { output_parser: Some(parser), .. }) => parser.parse(output),
+            Some(Preset { postprocess: Some(stage), .. }) => stage.apply(output),
+            _ => return Self::default(),
+        };
+        match result {
+            Ok(value) => Self { parsed: Some(value), parse_error: None },
+            Err(e) => Self { parsed: None, parse_error: Some(format!("{:#}", e)) },
+        }
+    }
+
+    /// Like `from_output`, but a cancelled generation is not parsed
+    pub fn from_generation(preset: Option<&Preset>, generation: &Generation) -> Self {
+        if generation.finish_reason == FinishReason::Cancelled {
+            return Self::default();
+        }
+        Self::from_output(preset, &generation.text)
+    }
+
+    /// The parsed value as plain text (strings without quotes)
+    pub fn text(&self) -> Option<String> {
+        self.parsed.as_ref().map(|value| match value {
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        })
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "январ", "феврал", "март", "апрел", "ма", "июн",
+    "июл", "август", "сентябр", "октябр", "ноябр", "декабр",
+];
+
+fn month(word: &str) -> Option<u32> {
+    if word == "мая" || word == "май" {
+        return Some(5);
+    }
+    MONTHS.iter()
+        .position(|prefix| *prefix != "ма" && word.starts_with(prefix))
+        .map(|i| i as u32 + 1)
+}
+
+fn weekday(word: &str) -> Option<Weekday> {
+    const DAYS: [(&str, Weekday); 7] = [
+        ("понедельник", Weekday::Mon),
+        ("вторник", Weekday::Tue),
+        ("сред", Weekday::Wed),
+        ("четверг", Weekday::Thu),
+        ("пятниц", Weekday::Fri),
+        ("суббот", Weekday::Sat),
+        ("воскресен", Weekday::Sun),
+    ];
+    DAYS.iter().find(|(prefix, _)| word.starts_with(prefix)).map(|(_, day)| *day)
+}
+
+fn number(word: &str) -> Option<u32> {
+    word.parse().ok().or(match word {
+        "один" | "одна" | "одну" => Some(1),
+        "два" | "две" | "пару" | "пара" => Some(2),
+        "три" => Some(3),
+        "четыре" => Some(4),
+        "пять" => Some(5),
+        "шесть" => Some(6),
+        "семь" => Some(7),
+        "восемь" => Some(8),
+        "девять" => Some(9),
+        "десять" => Some(10),
+        _ => None,
+    })
+}
+
+#[derive(Clone, Copy)]
+enum Unit {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+fn unit(word: &str) -> Option<Unit> {
+    if word == "день" || word.starts_with("дн") || word == "сутки" || word == "суток" {
+        Some(Unit::Day)
+    } else if word.starts_with("недел") {
+        Some(Unit::Week)
+    } else if word.starts_with("месяц") {
+        Some(Unit::Month)
+    } else if word.starts_with("год") || word == "лет" {
+        Some(Unit::Year)
+    } else {
+        None
+    }
+}
+
+/// `today` moved by `amount` units forward (`forward`) or back
+fn shift(today: NaiveDate, amount: u32, unit: Unit, forward: bool) -> Option<NaiveDate> {
+    let (days, months) = match unit {
+        Unit::Day => (amount as u64, 0),
+        Unit::Week => (amount as u64 * 7, 0),
+        Unit::Month => (0, amount),
+        Unit::Year => (0, amount.saturating_mul(12)),
+    };
+    if forward {
+        today.checked_add_days(Days::new(days))?.checked_add_months(Months::new(months))
+    } else {
+        today.checked_sub_days(Days::new(days))?.checked_sub_months(Months::new(months))
+    }
+}
+
+/// "неделю", "3 дня", "две недели", "полгода"
+fn parse_period(words: &[&str]) -> Option<(u32, Unit)> {
+    match words {
+        ["полгода"] => Some((6, Unit::Month)),
+        [word] => Some((1, unit(word)?)),
+        [amount, word] => Some((number(amount)?, unit(word)?)),
+        _ => None,
+    }
+}
+
+/// "15.03.2024", "15.03.24", "2024-03-15", "15.03", "15 марта 2024 года", "15 марта"
+fn parse_absolute(text: &str, words: &[&str], today: NaiveDate) -> Option<NaiveDate> {
+    // `%y` goes first: `%Y` would read "24" as the year 24
+    for format in ["%d.%m.%y", "%d.%m.%Y", "%Y-%m-%d", "%d/%m/%Y"] {
+        if let Ok(date) = NaiveDate::parse_from_str(text, format) {
+            return Some(date);
+        }
+    }
+    if let Some((day, month_number)) = text.split_once('.') {
+        if let (Ok(day), Ok(month)) = (day.parse(), month_number.parse()) {
+            return NaiveDate::from_ymd_opt(today.year(), month, day);
+        }
+    }
+    let words: Vec<&str> = words.iter()
+        .copied()
+        .filter(|w| !matches!(*w, "года" | "год" | "г"))
+        .collect();
+    match words.as_slice() {
+        [day, name] => NaiveDate::from_ymd_opt(today.year(), month(name)?, day.parse().ok()?),
+        [day, name, year] => NaiveDate::from_ymd_opt(year.parse().ok()?, month(name)?, day.parse().ok()?),
+        _ => None,
+    }
+}
+
+/// Resolves an absolute date or a Russian relative expression against `today`.
+///
+/// Weekdays without a modifier mean the nearest one after today; "следующий" means
+/// that day of the next calendar week, "этот" of the current one, "прошлый" of the previous one.
+pub fn resolve_date(expression: &str, today: NaiveDate) -> Result<NaiveDate> {
+    let text = expression.trim()
+        .trim_matches(&['"', '\'', '«', '»'][..])
+        .trim_end_matches(&['.', ',', '!', ';'][..])
+        .to_lowercase()
+        .replace('ё', "е");
+    let words: Vec<&str> = text.split_whitespace()
+        .filter(|w| !matches!(*w, "в" | "во" | "на" | "к"))
+        .collect();
+    if let Some(date) = parse_absolute(&text, &words, today) {
+        return Ok(date);
+    }
+
+    let monday = today.checked_sub_days(Days::new(today.weekday().num_days_from_monday() as u64))
+        .context("Date out of range")?;
+    let resolved = match words.as_slice() {
+        ["сегодня"] => Some(today),
+        ["завтра"] => today.checked_add_days(Days::new(1)),
+        ["послезавтра"] => today.checked_add_days(Days::new(2)),
+        ["вчера"] => today.checked_sub_days(Days::new(1)),
+        ["позавчера"] => today.checked_sub_days(Days::new(2)),
+        ["через", period @ ..] => parse_period(period)
+            .and_then(|(amount, unit)| shift(today, amount, unit, true)),
+        [period @ .., "назад"] => parse_period(period)
+            .and_then(|(amount, unit)| shift(today, amount, unit, false)),
+        [day] if weekday(day).is_some() => {
+            let target = weekday(day).unwrap_or(Weekday::Mon).num_days_from_monday();
+            let ahead = (target + 7 - today.weekday().num_days_from_monday()) % 7;
+            today.checked_add_days(Days::new(if ahead == 0 { 7 } else { ahead as u64 }))
+        }
+        [modifier, day] if weekday(day).is_some() => {
+            let target = weekday(day).unwrap_or(Weekday::Mon).num_days_from_monday() as u64;
+            if modifier.starts_with("следующ") {
+                monday.checked_add_days(Days::new(7 + target))
+            } else if modifier.starts_with("эт") {
+                monday.checked_add_days(Days::new(target))
+            } else if modifier.starts_with("прошл") {
+                monday.checked_sub_days(Days::new(7)).and_then(|d| d.checked_add_days(Days::new(target)))
+            } else if modifier.starts_with("ближайш") {
+                return resolve_date(day, today);
+            } else {
+                None
+            }
+        }
+        // Start of the next, current or previous week, month or year
+        [modifier, period] if unit(period).is_some() => {
+            let start = match unit(period) {
+                Some(Unit::Week) => Some(monday),
+                Some(Unit::Month) => today.with_day(1),
+                Some(Unit::Year) => NaiveDate::from_ymd_opt(today.year(), 1, 1),
+                _ => None,
+            };
+            let unit = unit(period).unwrap_or(Unit::Day);
+            match start {
+                Some(start) if modifier.starts_with("следующ") => shift(start, 1, unit, true),
+                Some(start) if modifier.starts_with("эт") => Some(start),
+                Some(start) if modifier.starts_with("прошл") => shift(start, 1, unit, false),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    match resolved {
+        Some(date) => Ok(date),
+        None => bail!("Cannot resolve a date from '{}'", expression.trim()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parser(config: serde_json::Value) -> OutputParser {
+        let parser: OutputParser = serde_json::from_value(config).unwrap();
+        parser.validate().unwrap();
+        parser
+    }
+
+    fn preset(config: serde_json::Value) -> Preset {
+        serde_json::from_value(json!({
+            "name": "test",
+            "description": "",
+            "max_tokens": 10,
+            "stop_on_newline": true,
+            "output_parser": config,
+        }))
+        .unwrap()
+    }
+
+    fn generation(text: &str) -> Generation {
+        Generation {
+            text: text.to_string(),
+            prompt_tokens: 10,
+            completion_tokens: 3,
+            finish_reason: FinishReason::Stop,
+            ..Generation::cancelled()
+        }
+    }
+
+    #[test]
+    fn regex_named_groups_become_fields() {
+        let parser = parser(json!({"type": "regex", "pattern": r"^(?P<category>ДЕШЕВЫЙ|ДОРОГОЙ)\s*-\s*(?P<reason>.+)$"}));
+        assert_eq!(
+            parser.parse(" ДОРОГОЙ - дорогая электроника\n").unwrap(),
+            json!({"category": "ДОРОГОЙ", "reason": "дорогая электроника"})
+        );
+        assert!(parser.parse("НЕ ЗНАЮ").is_err());
+    }
+
+    #[test]
+    fn regex_without_named_groups_is_rejected() {
+        let parser: OutputParser = serde_json::from_value(json!({"type": "regex", "pattern": "^да|нет$"})).unwrap();
+        assert!(parser.validate().is_err());
+    }
+
+    #[test]
+    fn json_is_extracted_from_surrounding_text() {
+        let parser = parser(json!({"type": "json"}));
+        assert_eq!(
+            parser.parse(r#"Ответ: {"category": "ДЕШЕВЫЙ"} готово"#).unwrap(),
+            json!({"category": "ДЕШЕВЫЙ"})
+        );
+        assert!(parser.parse(r#"{"category": "ДЕШЕВЫЙ""#).is_err());
+    }
+
+    #[test]
+    fn enum_matches_case_insensitively_and_returns_the_label() {
+        let parser = parser(json!({"type": "enum", "labels": ["ПОЗИТИВНЫЙ", "НЕГАТИВНЫЙ"]}));
+        assert_eq!(parser.parse("позитивный.").unwrap(), json!("ПОЗИТИВНЫЙ"));
+        assert!(parser.parse("НЕЙТРАЛЬНЫЙ").is_err());
+        assert!(parser.parse("").is_err());
+    }
+
+    #[test]
+    fn enum_labels_must_be_non_blank_and_unique() {
+        for labels in [json!([]), json!(["да", ""]), json!(["да", " "]), json!(["да", "Да"])] {
+            let parser: OutputParser = serde_json::from_value(json!({"type": "enum", "labels": labels})).unwrap();
+            assert!(parser.validate().is_err(), "{}", labels);
+        }
+    }
+
+    #[test]
+    fn number_accepts_grouping_and_decimal_separators() {
+        let parser = parser(json!({"type": "number"}));
+        assert_eq!(parser.parse("42").unwrap(), json!(42));
+        assert_eq!(parser.parse("-7").unwrap(), json!(-7));
+        assert_eq!(parser.parse("3,5").unwrap(), json!(3.5));
+        assert_eq!(parser.parse("3.5").unwrap(), json!(3.5));
+        assert_eq!(parser.parse("1 234,5").unwrap(), json!(1234.5));
+        assert_eq!(parser.parse("1\u{a0}234").unwrap(), json!(1234));
+        assert_eq!(parser.parse("1,234.5").unwrap(), json!(1234.5));
+        assert_eq!(parser.parse("1.234,5").unwrap(), json!(1234.5));
+        assert_eq!(parser.parse("1,234,567").unwrap(), json!(1234567));
+        assert_eq!(parser.parse("1.234.567").unwrap(), json!(1234567));
+        assert!(parser.parse("около ста").is_err());
+        assert!(parser.parse("inf").is_err());
+    }
+
+    #[test]
+    fn number_bounds_are_inclusive() {
+        let parser = parser(json!({"type": "number", "min": 0, "max": 100}));
+        assert_eq!(parser.parse("0").unwrap(), json!(0));
+        assert_eq!(parser.parse("100").unwrap(), json!(100));
+        assert!(parser.parse("-1").is_err());
+        assert!(parser.parse("100,5").is_err());
+    }
+
+    #[test]
+    fn number_min_above_max_is_rejected() {
+        let parser: OutputParser = serde_json::from_value(json!({"type": "number", "min": 10, "max": 1})).unwrap();
+        assert!(parser.validate().is_err());
+    }
+
+    #[test]
+    fn valid_output_is_not_retried() {
+        let preset = preset(json!({"type": "number", "retries": 2}));
+        let mut calls = 0;
+        let (generation, parsed) = generate_validated(Some(&preset), &GenerationParams::default(), |_| {
+            calls += 1;
+            Ok(generation("5"))
+        })
+        .unwrap();
+        assert_eq!(calls, 1);
+        assert_eq!(generation.completion_tokens, 3);
+        assert_eq!(parsed.parsed, Some(json!(5)));
+    }
+
+    #[test]
+    fn invalid_output_is_retried_until_valid() {
+        let preset = preset(json!({"type": "number", "retries": 2}));
+        let mut calls = 0;
+        let (generation, parsed) = generate_validated(Some(&preset), &GenerationParams::default(), |params| {
+            calls += 1;
+            if calls > 1 {
+                // Greedy presets get some temperature on retries
+                assert_eq!(params.sampling.temperature, Some(RETRY_TEMPERATURE));
+            }
+            Ok(generation(if calls < 3 { "много" } else { "12" }))
+        })
+        .unwrap();
+        assert_eq!(calls, 3);
+        assert_eq!(generation.prompt_tokens, 30);
+        assert_eq!(generation.completion_tokens, 9);
+        assert_eq!(parsed.parsed, Some(json!(12)));
+    }
+
+    #[test]
+    fn validation_fails_after_all_retries() {
+        let preset = preset(json!({"type": "number", "retries": 2}));
+        let mut calls = 0;
+        let error = generate_validated(Some(&preset), &GenerationParams::default(), |_| {
+            calls += 1;
+            Ok(generation("много"))
+        })
+        .unwrap_err();
+        let failed = error.downcast_ref::<ValidationFailed>().unwrap();
+        assert_eq!(calls, 3);
+        assert_eq!(failed.attempts, 3);
+        assert_eq!(failed.response, "много");
+        assert_eq!(failed.completion_tokens, 9);
+    }
+
+    #[test]
+    fn stopped_generation_is_not_retried() {
+        let preset = preset(json!({"type": "number", "retries": 2}));
+        let mut calls = 0;
+        let (generation, _) = generate_validated(Some(&preset), &GenerationParams::default(), |_| {
+            calls += 1;
+            Ok(Generation { finish_reason: FinishReason::Timeout, ..generation("мно") })
+        })
+        .unwrap();
+        assert_eq!(calls, 1);
+        assert_eq!(generation.finish_reason, FinishReason::Timeout);
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn assert_resolves(today: NaiveDate, cases: &[(&str, NaiveDate)]) {
+        for (expression, expected) in cases {
+            assert_eq!(resolve_date(expression, today).unwrap(), *expected, "{}", expression);
+        }
+    }
+
+    /// Sunday
+    const SUNDAY: (i32, u32, u32) = (2026, 10, 18);
+    /// Wednesday of the same week
+    const WEDNESDAY: (i32, u32, u32) = (2026, 10, 14);
+
+    #[test]
+    fn resolves_day_words() {
+        let today = date(SUNDAY.0, SUNDAY.1, SUNDAY.2);
+        assert_resolves(today, &[
+            ("сегодня", today),
+            ("Завтра", date(2026, 10, 19)),
+            ("послезавтра.", date(2026, 10, 20)),
+            ("вчера", date(2026, 10, 17)),
+            ("позавчера", date(2026, 10, 16)),
+        ]);
+    }
+
+    #[test]
+    fn resolves_weekdays_with_modifiers() {
+        let today = date(SUNDAY.0, SUNDAY.1, SUNDAY.2);
+        assert_resolves(today, &[
+            ("в следующий понедельник", date(2026, 10, 19)),
+            ("в следующую пятницу", date(2026, 10, 23)),
+            ("пятница", date(2026, 10, 23)),
+            // The same weekday without a modifier means a week ahead
+            ("в воскресенье", date(2026, 10, 25)),
+            ("в эту субботу", date(2026, 10, 17)),
+            ("в прошлую среду", date(2026, 10, 7)),
+            ("ближайший вторник", date(2026, 10, 20)),
+        ]);
+    }
+
+    #[test]
+    fn resolves_past_and_future_weekdays_mid_week() {
+        let today = date(WEDNESDAY.0, WEDNESDAY.1, WEDNESDAY.2);
+        assert_resolves(today, &[
+            ("в пятницу", date(2026, 10, 16)),
+            ("в понедельник", date(2026, 10, 19)),
+            ("в этот понедельник", date(2026, 10, 12)),
+            ("в эту пятницу", date(2026, 10, 16)),
+            ("в следующую среду", date(2026, 10, 21)),
+            ("в прошлый вторник", date(2026, 10, 6)),
+        ]);
+    }
+
+    #[test]
+    fn resolves_periods() {
+        let today = date(SUNDAY.0, SUNDAY.1, SUNDAY.2);
+        assert_resolves(today, &[
+            ("через 2 недели", date(2026, 11, 1)),
+            ("через три дня", date(2026, 10, 21)),
+            ("через месяц", date(2026, 11, 18)),
+            ("неделю назад", date(2026, 10, 11)),
+            ("полгода назад", date(2026, 4, 18)),
+            ("на следующей неделе", date(2026, 10, 19)),
+            ("в следующем месяце", date(2026, 11, 1)),
+            ("в следующем году", date(2027, 1, 1)),
+        ]);
+    }
+
+    #[test]
+    fn resolves_absolute_formats() {
+        let today = date(SUNDAY.0, SUNDAY.1, SUNDAY.2);
+        assert_resolves(today, &[
+            ("15.03.2024", date(2024, 3, 15)),
+            ("15.03.24", date(2024, 3, 15)),
+            ("2024-03-15", date(2024, 3, 15)),
+            ("15/03/2024", date(2024, 3, 15)),
+            ("31.12.2024.", date(2024, 12, 31)),
+            ("15.03", date(2026, 3, 15)),
+            ("15 марта 2024 года", date(2024, 3, 15)),
+            ("5 мая", date(2026, 5, 5)),
+        ]);
+    }
+
+    #[test]
+    fn rejects_unknown_and_invalid_dates() {
+        let today = date(SUNDAY.0, SUNDAY.1, SUNDAY.2);
+        for expression in ["когда-нибудь", "31.02.2024", "", "в следующий раз"] {
+            assert!(resolve_date(expression, today).is_err(), "{}", expression);
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use anyhow::Result;
+use notify::{Event, RecursiveMode, Watcher};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::{mpsc, Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::presets::{try_load_presets, Preset, PRESETS_DIR, PRESETS_FILE};
+
+/// Delay used to coalesce bursts of file events (editors often save in several steps)
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// One successfully parsed generation of presets
+pub struct PresetSnapshot {
+    pub presets: Vec<Preset>,
+    pub version: u64,
+    pub hash: String,
+    pub loaded_at: String,
+}
+
+impl PresetSnapshot {
+    fn new(presets: Vec<Preset>, version: u64) -> Self {
+        Self {
+            hash: presets_hash(&presets),
+            presets,
+            version,
+            loaded_at: chrono::Local::now().to_rfc3339(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Preset> {
+        self.presets.iter().find(|p| p.name == name)
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct ReloadError {
+    pub message: String,
+    pub at: String,
+}
+
+#[derive(Serialize)]
+pub struct PresetStoreStatus {
+    pub version: u64,
+    pub hash: String,
+    pub loaded_at: String,
+    pub preset_count: usize,
+    pub watching: bool,
+    pub last_error: Option<ReloadError>,
+}
+
+/// In-memory preset cache, reloaded when preset files change.
+///
+/// A reload that fails to parse keeps the last good snapshot and records the error.
+pub struct PresetStore {
+    current: RwLock<Arc<PresetSnapshot>>,
+    last_error: RwLock<Option<ReloadError>>,
+    watching: RwLock<bool>,
+}
+
+fn presets_hash(presets: &[Preset]) -> String {
+    let bytes = serde_json::to_vec(presets).unwrap_or_default();
+    format!("{:x}", Sha256::digest(&bytes))
+}
+
+/// Checks whether a file system event touches `presets.json` or the `presets/` directory
+fn is_preset_event(event: &Event) -> bool {
+    event.paths.iter().any(|path| {
+        let name = path.file_name().and_then(|n| n.to_str());
+        let parent = path.parent()
+            .and_then(|p| p.file_name())
+            .and_then(|n| n.to_str());
+        name == Some(PRESETS_FILE) || name == Some(PRESETS_DIR) || parent == Some(PRESETS_DIR)
+    })
+}
+
+impl PresetStore {
+    /// Performs the initial load. Parse errors leave the store empty but recorded.
+    pub fn load() -> Arc<Self> {
+        let store = Self::empty();
+        store.reload();
+        Arc::new(store)
+    }
+
+    fn empty() -> Self {
+        Self {
+            current: RwLock::new(Arc::new(PresetSnapshot::new(vec![], 0))),
+            last_error: RwLock::new(None),
+            watching: RwLock::new(false),
+        }
+    }
+
+    pub fn snapshot(&self) -> Arc<PresetSnapshot> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Preset> {
+        self.snapshot().get(name).cloned()
+    }
+
+    /// Re-reads preset files. Returns `true` when the active presets changed.
+    pub fn reload(&self) -> bool {
+        self.apply(try_load_presets())
+    }
+
+    /// Installs the result of a load; the version only changes when the presets do
+    fn apply(&self, loaded: Result<Vec<Preset>>) -> bool {
+        match loaded {
+            Ok(presets) => {
+                *self.last_error.write().unwrap() = None;
+                let hash = presets_hash(&presets);
+                let mut current = self.current.write().unwrap();
+                if current.hash == hash && current.version > 0 {
+                    return false;
+                }
+                let version = current.version + 1;
+                *current = Arc::new(PresetSnapshot::new(presets, version));
+                info!(version, presets = current.presets.len(), hash = &current.hash[..12], "presets loaded");
+                true
+            }
+            Err(e) => {
+                let message = format!("{:#}", e);
+                warn!(error = %message, "preset reload failed, keeping the previous version");
+                *self.last_error.write().unwrap() = Some(ReloadError {
+                    message,
+                    at: chrono::Local::now().to_rfc3339(),
+                });
+                false
+            }
+        }
+    }
+
+    pub fn status(&self) -> PresetStoreStatus {
+        let snapshot = self.snapshot();
+        PresetStoreStatus {
+            version: snapshot.version,
+            hash: snapshot.hash.clone(),
+            loaded_at: snapshot.loaded_at.clone(),
+            preset_count: snapshot.presets.len(),
+            watching: *self.watching.read().unwrap(),
+            last_error: self.last_error.read().unwrap().clone(),
+        }
+    }
+
+    /// Starts a background thread that reloads presets whenever their files change
+    pub fn watch(self: &Arc<Self>) -> Result<()> {
+        let (tx, rx) = mpsc::channel::<notify::Result<Event>>();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        // The working directory is watched non-recursively to catch presets.json
+        // and the creation of the presets/ directory
+        watcher.watch(Path::new("."), RecursiveMode::NonRecursive)?;
+        let mut dir_watched = Path::new(PRESETS_DIR).is_dir()
+            && watcher.watch(Path::new(PRESETS_DIR), RecursiveMode::NonRecursive).is_ok();
+
+        *self.watching.write().unwrap() = true;
+        let store = Arc::clone(self);
+
+        std::thread::spawn(move || {
+            while let Ok(res) = rx.recv() {
+                // Other files in the working directory (audit logs, batch outputs) change all the time
+                if !matches!(&res, Ok(event) if is_preset_event(event)) {
+                    continue;
+                }
+                // Editors save in several steps; they are collected within a fixed window,
+                // so steady writes elsewhere cannot postpone the reload
+                let deadline = Instant::now() + RELOAD_DEBOUNCE;
+                while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+                    if rx.recv_timeout(left).is_err() {
+                        break;
+                    }
+                }
+
+                let dir_exists = Path::new(PRESETS_DIR).is_dir();
+                if dir_exists && !dir_watched {
+                    dir_watched = watcher
+                        .watch(Path::new(PRESETS_DIR), RecursiveMode::NonRecursive)
+                        .is_ok();
+                } else if !dir_exists {
+                    dir_watched = false;
+                }
+
+                store.reload();
+            }
+            *store.watching.write().unwrap() = false;
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::presets::try_load_presets_from;
+    use std::fs;
+
+    fn preset(name: &str, max_tokens: usize) -> Preset {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "description": "",
+            "max_tokens": max_tokens,
+            "stop_on_newline": true,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn first_load_sets_version_one_even_without_presets() {
+        let store = PresetStore::empty();
+        assert!(store.apply(Ok(vec![])));
+        assert_eq!(store.snapshot().version, 1);
+    }
+
+    #[test]
+    fn unchanged_presets_keep_the_version() {
+        let store = PresetStore::empty();
+        assert!(store.apply(Ok(vec![preset("a", 10)])));
+        let hash = store.snapshot().hash.clone();
+
+        assert!(!store.apply(Ok(vec![preset("a", 10)])));
+        assert_eq!(store.snapshot().version, 1);
+        assert_eq!(store.snapshot().hash, hash);
+
+        assert!(store.apply(Ok(vec![preset("a", 20)])));
+        assert_eq!(store.snapshot().version, 2);
+        assert_ne!(store.snapshot().hash, hash);
+    }
+
+    #[test]
+    fn failed_reload_keeps_the_snapshot_and_records_the_error() {
+        let store = PresetStore::empty();
+        store.apply(Ok(vec![preset("a", 10)]));
+
+        assert!(!store.apply(Err(anyhow::anyhow!("ошибка парсинга presets/a.yaml"))));
+        let status = store.status();
+        assert_eq!(status.version, 1);
+        assert_eq!(status.preset_count, 1);
+        assert_eq!(status.last_error.unwrap().message, "ошибка парсинга presets/a.yaml");
+
+        // A good load clears the error even when nothing changed
+        assert!(!store.apply(Ok(vec![preset("a", 10)])));
+        assert!(store.status().last_error.is_none());
+    }
+
+    #[test]
+    fn file_changes_bump_the_version() {
+        let dir = std::env::temp_dir().join(format!("chat-np-store-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join(PRESETS_DIR)).unwrap();
+        let file = dir.join(PRESETS_DIR).join("a.yaml");
+        let content = "name: a\ndescription: ''\nmax_tokens: 10\nstop_on_newline: true\n";
+        fs::write(&file, content).unwrap();
+
+        let store = PresetStore::empty();
+        assert!(store.apply(try_load_presets_from(&dir)));
+        assert_eq!(store.snapshot().version, 1);
+
+        // Saving the same content again is not a change
+        fs::write(&file, content).unwrap();
+        assert!(!store.apply(try_load_presets_from(&dir)));
+        assert_eq!(store.snapshot().version, 1);
+
+        fs::write(&file, content.replace("10", "20")).unwrap();
+        assert!(store.apply(try_load_presets_from(&dir)));
+        assert_eq!(store.snapshot().version, 2);
+        assert_eq!(store.get("a").unwrap().max_tokens, 20);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
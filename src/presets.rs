@@ -0,0 +1,654 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{error, warn};
+
+use crate::engine::SamplingParams;
+use crate::eval::EvalConfig;
+use crate::postprocess::{OutputParser, ParserKind, PostProcess};
+use crate::retrieval::RetrievalConfig;
+use crate::tools::{self, ToolDefinition};
+
+/// Legacy single-file preset storage (kept for backward compatibility)
+pub const PRESETS_FILE: &str = "presets.json";
+/// Directory with one preset per file (JSON, YAML or TOML)
+pub const PRESETS_DIR: &str = "presets";
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Preset {
+    pub name: String,
+    pub description: String,
+    #[serde(default)]
+    pub system_prompt: String,
+    #[serde(default)]
+    pub instruction: Option<String>,
+    #[serde(default)]
+    pub examples: Option<Vec<PromptExample>>,
+    #[serde(default)]
+    pub negative_prompt: Option<String>,
+    #[serde(default)]
+    pub response_format: Option<String>,
+    pub max_tokens: usize,
+    pub stop_on_newline: bool,
+    /// Wall-clock limit for a single generation, in milliseconds
+    #[serde(default)]
+    pub max_time_ms: Option<u64>,
+    #[serde(default)]
+    pub include_current_date: bool,
+    #[serde(default)]
+    pub sampling: Option<SamplingParams>,
+    #[serde(default)]
+    pub prompt_format: PromptFormat,
+    /// Models this preset works best with, in order of preference
+    #[serde(default)]
+    pub recommended_models: Vec<String>,
+    /// Models this preset may run on (empty means any model)
+    #[serde(default)]
+    pub allowed_models: Vec<String>,
+    /// Overrides keyed by a model name fragment, e.g. "Qwen3-1.7B"
+    #[serde(default)]
+    pub model_overrides: BTreeMap<String, PresetOverride>,
+    /// Regression test settings for `chat-np eval` and `/eval`
+    #[serde(default)]
+    pub eval: Option<EvalConfig>,
+    /// Document index whose most relevant chunks are inserted before the input
+    #[serde(default)]
+    pub retrieval: Option<RetrievalConfig>,
+    /// Functions the model may call through /v1/chat/completions
+    #[serde(default)]
+    pub tools: Vec<ToolDefinition>,
+    /// Deterministic stage that turns the output into the structured `parsed` field
+    #[serde(default)]
+    pub postprocess: Option<PostProcess>,
+    /// Validates the output and returns its fields in `parsed`; invalid outputs are regenerated
+    #[serde(default)]
+    pub output_parser: Option<OutputParser>,
+    /// Scores the listed labels instead of generating text
+    #[serde(default)]
+    pub classification: Option<ClassificationConfig>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PromptExample {
+    pub input: String,
+    pub output: String,
+}
+
+/// How the preset is turned into model input
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PromptFormat {
+    /// Plain text prompt ending with "Вход: ...\nВыход:"
+    #[default]
+    Plain,
+    /// Messages rendered with the model's built-in chat template
+    Chat,
+}
+
+/// Classification mode: the output is always one of the labels
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ClassificationConfig {
+    pub labels: Vec<String>,
+}
+
+impl ClassificationConfig {
+    /// Labels as the model would write them after the prompt (plain prompts end with "Выход:")
+    pub fn continuations(&self, format: PromptFormat) -> Vec<String> {
+        let prefix = match format {
+            PromptFormat::Plain => " ",
+            PromptFormat::Chat => "",
+        };
+        self.labels.iter().map(|label| format!("{}{}", prefix, label.trim())).collect()
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.labels.len() < 2 {
+            bail!("нужно хотя бы две метки");
+        }
+        for (i, label) in self.labels.iter().enumerate() {
+            if label.trim().is_empty() {
+                bail!("пустая метка");
+            }
+            if self.labels[..i].iter().any(|other| other.trim() == label.trim()) {
+                bail!("метка '{}' указана дважды", label.trim());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Per-model replacement values for preset fields
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct PresetOverride {
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    #[serde(default)]
+    pub instruction: Option<String>,
+    #[serde(default)]
+    pub examples: Option<Vec<PromptExample>>,
+    #[serde(default)]
+    pub negative_prompt: Option<String>,
+    #[serde(default)]
+    pub response_format: Option<String>,
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    #[serde(default)]
+    pub stop_on_newline: Option<bool>,
+    #[serde(default)]
+    pub max_time_ms: Option<u64>,
+    #[serde(default)]
+    pub sampling: Option<SamplingParams>,
+    #[serde(default)]
+    pub prompt_format: Option<PromptFormat>,
+}
+
+/// Checks whether a model file name matches a name fragment from a preset.
+///
+/// Matching is case-insensitive and ignores the `.gguf` extension,
+/// so "qwen3-1.7b" matches "Qwen3-1.7B-Q4_K_M.gguf".
+pub fn model_matches(pattern: &str, model: &str) -> bool {
+    let model = model.to_lowercase();
+    let model = model.strip_suffix(".gguf").unwrap_or(&model);
+    let pattern = pattern.to_lowercase();
+    let pattern = pattern.strip_suffix(".gguf").unwrap_or(&pattern);
+    model.contains(pattern)
+}
+
+impl Preset {
+    /// Returns a copy of the preset with overrides for the given model applied.
+    ///
+    /// When several override keys match, longer (more specific) keys win.
+    pub fn for_model(&self, model: &str) -> Preset {
+        let mut resolved = self.clone();
+        let mut matching: Vec<(&String, &PresetOverride)> = self.model_overrides.iter()
+            .filter(|(pattern, _)| model_matches(pattern, model))
+            .collect();
+        matching.sort_by_key(|(pattern, _)| pattern.len());
+
+        for (_, o) in matching {
+            if let Some(v) = &o.system_prompt {
+                resolved.system_prompt = v.clone();
+            }
+            if let Some(v) = &o.instruction {
+                resolved.instruction = Some(v.clone());
+            }
+            if let Some(v) = &o.examples {
+                resolved.examples = Some(v.clone());
+            }
+            if let Some(v) = &o.negative_prompt {
+                resolved.negative_prompt = Some(v.clone());
+            }
+            if let Some(v) = &o.response_format {
+                resolved.response_format = Some(v.clone());
+            }
+            if let Some(v) = o.max_tokens {
+                resolved.max_tokens = v;
+            }
+            if let Some(v) = o.stop_on_newline {
+                resolved.stop_on_newline = v;
+            }
+            if let Some(v) = o.max_time_ms {
+                resolved.max_time_ms = Some(v);
+            }
+            if let Some(v) = &o.sampling {
+                resolved.sampling = Some(resolved.sampling.take().unwrap_or_default().merge(v));
+            }
+            if let Some(v) = o.prompt_format {
+                resolved.prompt_format = v;
+            }
+        }
+        resolved.model_overrides.clear();
+        resolved
+    }
+
+    /// The output must be a JSON value (`output_parser: json`)
+    pub fn expects_json_answer(&self) -> bool {
+        matches!(&self.output_parser, Some(OutputParser { kind: ParserKind::Json, .. }))
+    }
+
+    pub fn allows_model(&self, model: &str) -> bool {
+        self.allowed_models.is_empty()
+            || self.allowed_models.iter().any(|pattern| model_matches(pattern, model))
+    }
+
+    /// Picks the first available model from `recommended_models`,
+    /// falling back to the first available allowed model
+    pub fn preferred_model(&self, available: &[String]) -> Option<String> {
+        self.recommended_models.iter()
+            .find_map(|pattern| available.iter().find(|m| model_matches(pattern, m)))
+            .or_else(|| {
+                if self.allowed_models.is_empty() {
+                    None
+                } else {
+                    available.iter().find(|m| self.allows_model(m))
+                }
+            })
+            .cloned()
+    }
+
+    /// System part of the prompt: role, date, instructions, examples and format
+    pub fn system_text(&self, include_examples: bool) -> String {
+        let mut parts = Vec::new();
+
+        // System prompt (main role)
+        if !self.system_prompt.is_empty() {
+            parts.push(self.system_prompt.clone());
+        }
+
+        // Current date (if required)
+        if self.include_current_date {
+            use chrono::{Local, Datelike};
+            let now = Local::now();
+            let date_str = now.format("%d.%m.%Y").to_string();
+            let weekday = match now.date_naive().weekday() {
+                chrono::Weekday::Mon => "понедельник",
+                chrono::Weekday::Tue => "вторник",
+                chrono::Weekday::Wed => "среда",
+                chrono::Weekday::Thu => "четверг",
+                chrono::Weekday::Fri => "пятница",
+                chrono::Weekday::Sat => "суббота",
+                chrono::Weekday::Sun => "воскресенье",
+            };
+            parts.push(format!("Сегодня: {} ({})", date_str, weekday));
+        }
+
+        // Instruction (detailed instructions)
+        if let Some(instruction) = &self.instruction {
+            parts.push(instruction.clone());
+        }
+
+        // Examples (few-shot examples)
+        if let (true, Some(examples)) = (include_examples, &self.examples) {
+            if !examples.is_empty() {
+                let examples_text = examples.iter()
+                    .map(|ex| format!("Вход: {}\nВыход: {}", ex.input, ex.output))
+                    .collect::<Vec<_>>()
+                    .join("\n\n");
+                parts.push(format!("Примеры:\n{}", examples_text));
+            }
+        }
+
+        // Negative prompt (what NOT to do)
+        if let Some(negative) = &self.negative_prompt {
+            parts.push(format!("НЕ ДЕЛАЙ: {}", negative));
+        }
+
+        // Response format (output format)
+        if let Some(format) = &self.response_format {
+            parts.push(format!("Формат ответа: {}", format));
+        }
+
+        // Combine everything together
+        parts.join("\n\n")
+    }
+
+    /// `context` is the retrieved reference text, placed right before the input
+    pub fn build_full_prompt(&self, user_input: &str, context: Option<&str>) -> String {
+        let mut full_system = self.system_text(true);
+
+        if let Some(context) = context {
+            full_system.push_str("\n\n");
+            full_system.push_str(context);
+        }
+
+        // Add user input
+        format!("{}\n\nВход: {}\nВыход:", full_system, user_input)
+    }
+
+    /// Examples as alternating user/assistant (role, content) messages
+    pub fn example_messages(&self) -> Vec<(String, String)> {
+        self.examples.iter()
+            .flatten()
+            .flat_map(|ex| [
+                ("user".to_string(), ex.input.clone()),
+                ("assistant".to_string(), ex.output.clone()),
+            ])
+            .collect()
+    }
+
+    /// Builds (role, content) messages for chat-template prompts.
+    ///
+    /// Examples become alternating user/assistant turns instead of inline text.
+    pub fn build_chat_messages(&self, user_input: &str, context: Option<&str>) -> Vec<(String, String)> {
+        let mut messages = Vec::new();
+        let system = self.system_text(false);
+        if !system.is_empty() {
+            messages.push(("system".to_string(), system));
+        }
+        messages.extend(self.example_messages());
+        let input = match context {
+            Some(context) => format!("{}\n\n{}", context, user_input),
+            None => user_input.to_string(),
+        };
+        messages.push(("user".to_string(), input));
+        messages
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PresetsConfig {
+    pub presets: Vec<Preset>,
+}
+
+/// Preset file formats supported in the presets directory
+enum PresetFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl PresetFormat {
+    fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "json" => Some(Self::Json),
+            "yaml" | "yml" => Some(Self::Yaml),
+            "toml" => Some(Self::Toml),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a single preset file from the presets directory
+fn parse_preset_file(path: &Path) -> Result<Option<Preset>> {
+    let Some(format) = PresetFormat::from_path(path) else {
+        return Ok(None);
+    };
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("не удалось прочитать {}", path.display()))?;
+    let preset = match format {
+        PresetFormat::Json => serde_json::from_str::<Preset>(&content)
+            .with_context(|| format!("ошибка парсинга {}", path.display()))?,
+        PresetFormat::Yaml => serde_yaml::from_str::<Preset>(&content)
+            .with_context(|| format!("ошибка парсинга {}", path.display()))?,
+        PresetFormat::Toml => toml::from_str::<Preset>(&content)
+            .with_context(|| format!("ошибка парсинга {}", path.display()))?,
+    };
+    Ok(Some(preset))
+}
+
+/// Lists preset files in the presets directory, sorted by file name
+pub fn preset_dir_files() -> Result<Vec<PathBuf>> {
+    preset_files_in(Path::new(PRESETS_DIR))
+}
+
+fn preset_files_in(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("не удалось открыть {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_file() && PresetFormat::from_path(&path).is_some() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Loads presets from `presets.json` and the `presets/` directory.
+///
+/// Presets from the directory replace legacy presets with the same name.
+/// Any parse error fails the whole load so callers can keep the previous set.
+pub fn try_load_presets() -> Result<Vec<Preset>> {
+    // An empty base keeps paths in messages relative: "presets/a.yaml"
+    try_load_presets_from(Path::new(""))
+}
+
+/// Same as `try_load_presets`, with `presets.json` and `presets/` looked up in `base`
+pub(crate) fn try_load_presets_from(base: &Path) -> Result<Vec<Preset>> {
+    let mut presets: Vec<Preset> = Vec::new();
+
+    let legacy = base.join(PRESETS_FILE);
+    if legacy.is_file() {
+        let content = fs::read_to_string(&legacy)
+            .with_context(|| format!("не удалось прочитать {}", PRESETS_FILE))?;
+        let config = serde_json::from_str::<PresetsConfig>(&content)
+            .with_context(|| format!("ошибка парсинга {}", PRESETS_FILE))?;
+        presets = config.presets;
+    }
+
+    let mut from_dir: Vec<(PathBuf, Preset)> = Vec::new();
+    for path in preset_files_in(&base.join(PRESETS_DIR))? {
+        if let Some(preset) = parse_preset_file(&path)? {
+            if let Some((other, _)) = from_dir.iter().find(|(_, p)| p.name == preset.name) {
+                bail!(
+                    "пресет '{}' объявлен дважды: {} и {}",
+                    preset.name, other.display(), path.display()
+                );
+            }
+            from_dir.push((path, preset));
+        }
+    }
+
+    for (path, preset) in from_dir {
+        if let Some(existing) = presets.iter_mut().find(|p| p.name == preset.name) {
+            warn!(
+                preset = %preset.name,
+                file = %path.display(),
+                "preset replaces the one with the same name from {}", PRESETS_FILE
+            );
+            *existing = preset;
+        } else {
+            presets.push(preset);
+        }
+    }
+
+    for preset in &presets {
+        tools::validate(&preset.tools)
+            .with_context(|| format!("некорректные инструменты в пресете '{}'", preset.name))?;
+        // The tool grammar reserves answers starting with `{` for calls
+        if !preset.tools.is_empty() && preset.expects_json_answer() {
+            bail!("в пресете '{}' нельзя указывать одновременно tools и output_parser типа json", preset.name);
+        }
+        if let Some(parser) = &preset.output_parser {
+            if preset.postprocess.is_some() {
+                bail!("в пресете '{}' нельзя указывать одновременно postprocess и output_parser", preset.name);
+            }
+            parser.validate()
+                .with_context(|| format!("некорректный output_parser в пресете '{}'", preset.name))?;
+        }
+        if let Some(classification) = &preset.classification {
+            if preset.output_parser.is_some() {
+                bail!("в пресете '{}' нельзя указывать одновременно classification и output_parser", preset.name);
+            }
+            classification.validate()
+                .with_context(|| format!("некорректная секция classification в пресете '{}'", preset.name))?;
+        }
+        if let Some(retrieval) = &preset.retrieval {
+            retrieval.validate()
+                .with_context(|| format!("некорректная секция retrieval в пресете '{}'", preset.name))?;
+        }
+    }
+
+    Ok(presets)
+}
+
+pub fn load_presets() -> Vec<Preset> {
+    if !Path::new(PRESETS_FILE).is_file() && !Path::new(PRESETS_DIR).is_dir() {
+        warn!("neither {} nor {}/ found", PRESETS_FILE, PRESETS_DIR);
+        return vec![];
+    }
+    match try_load_presets() {
+        Ok(presets) => presets,
+        Err(e) => {
+            error!(error = format!("{:#}", e), "failed to load presets");
+            vec![]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chat-np-presets-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join(PRESETS_DIR)).unwrap();
+        dir
+    }
+
+    fn write(dir: &Path, name: &str, content: &str) {
+        fs::write(dir.join(name), content).unwrap();
+    }
+
+    fn preset_json(name: &str, description: &str) -> serde_json::Value {
+        json!({"name": name, "description": description, "max_tokens": 10, "stop_on_newline": true})
+    }
+
+    fn names(presets: &[Preset]) -> Vec<&str> {
+        presets.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    #[test]
+    fn legacy_file_and_directory_formats_are_merged() {
+        let dir = temp_dir();
+        write(&dir, PRESETS_FILE, &json!({"presets": [preset_json("a", "legacy"), preset_json("b", "legacy")]}).to_string());
+        write(&dir, "presets/b.yml", "name: b\ndescription: из каталога\nmax_tokens: 20\nstop_on_newline: false\n");
+        write(&dir, "presets/c.json", &preset_json("c", "json").to_string());
+        write(&dir, "presets/d.toml", "name = \"d\"\ndescription = \"toml\"\nmax_tokens = 5\nstop_on_newline = true\n");
+        write(&dir, "presets/notes.txt", "не пресет");
+
+        let presets = try_load_presets_from(&dir).unwrap();
+        assert_eq!(names(&presets), ["a", "b", "c", "d"]);
+        // The directory preset replaces the legacy one in place
+        assert_eq!(presets[1].description, "из каталога");
+        assert_eq!(presets[1].max_tokens, 20);
+        assert_eq!(presets[3].description, "toml");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_files_give_no_presets() {
+        let dir = std::env::temp_dir().join(format!("chat-np-presets-{}", uuid::Uuid::new_v4()));
+        assert!(try_load_presets_from(&dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn duplicate_directory_presets_are_rejected() {
+        let dir = temp_dir();
+        write(&dir, "presets/a.json", &preset_json("a", "json").to_string());
+        write(&dir, "presets/a2.yaml", "name: a\ndescription: yaml\nmax_tokens: 10\nstop_on_newline: true\n");
+        let error = format!("{:#}", try_load_presets_from(&dir).unwrap_err());
+        assert!(error.contains("объявлен дважды"), "{}", error);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn one_broken_file_fails_the_whole_load() {
+        let dir = temp_dir();
+        write(&dir, "presets/a.json", &preset_json("a", "json").to_string());
+        write(&dir, "presets/b.yaml", "name: b\ndescription: [\n");
+        let error = format!("{:#}", try_load_presets_from(&dir).unwrap_err());
+        assert!(error.contains("b.yaml"), "{}", error);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_sections_are_rejected() {
+        let cases = [
+            (json!({"postprocess": "date", "output_parser": {"type": "json"}}), "postprocess и output_parser"),
+            (json!({"output_parser": {"type": "regex", "pattern": "^да$"}}), "output_parser"),
+            (json!({"classification": {"labels": ["да"]}}), "classification"),
+            (json!({"classification": {"labels": ["да", "нет"]}, "output_parser": {"type": "json"}}), "classification и output_parser"),
+            (json!({"tools": [{"name": "get weather", "description": "", "parameters": {}}]}), "инструменты"),
+            (json!({"retrieval": {"index": "../docs"}}), "retrieval"),
+            (json!({"tools": [{"name": "get_weather"}], "output_parser": {"type": "json"}}), "tools и output_parser"),
+        ];
+        for (extra, expected) in cases {
+            let dir = temp_dir();
+            let mut preset = preset_json("a", "json");
+            preset.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+            write(&dir, "presets/a.json", &preset.to_string());
+            let error = format!("{:#}", try_load_presets_from(&dir).unwrap_err());
+            assert!(error.contains(expected), "{}: {}", extra, error);
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    fn preset(config: serde_json::Value) -> Preset {
+        let mut preset = preset_json("test", "");
+        preset.as_object_mut().unwrap().extend(config.as_object().unwrap().clone());
+        serde_json::from_value(preset).unwrap()
+    }
+
+    #[test]
+    fn model_patterns_match_case_insensitively_without_extension() {
+        let cases = [
+            ("qwen3-1.7b", "Qwen3-1.7B-Q4_K_M.gguf", true),
+            ("Qwen3-1.7B-Q4_K_M.gguf", "Qwen3-1.7B-Q4_K_M.gguf", true),
+            ("Q4_K_M.GGUF", "qwen3-4b-q4_k_m.gguf", true),
+            ("", "Qwen3-4B.gguf", true),
+            ("qwen3-4b", "Qwen3-1.7B-Q4_K_M.gguf", false),
+            ("gguf", "Qwen3-4B.gguf", false),
+        ];
+        for (pattern, model, expected) in cases {
+            assert_eq!(model_matches(pattern, model), expected, "{} ~ {}", pattern, model);
+        }
+    }
+
+    #[test]
+    fn allowed_models_restrict_the_model() {
+        let cases = [
+            (json!([]), "Llama-3.2-1B.gguf", true),
+            (json!(["qwen3"]), "Qwen3-4B-Q4_K_M.gguf", true),
+            (json!(["qwen3"]), "Llama-3.2-1B.gguf", false),
+            (json!(["llama", "qwen3-1.7b"]), "Qwen3-1.7B-Q8_0.gguf", true),
+        ];
+        for (allowed, model, expected) in cases {
+            let preset = preset(json!({"allowed_models": allowed}));
+            assert_eq!(preset.allows_model(model), expected, "{} in {}", model, allowed);
+        }
+    }
+
+    #[test]
+    fn preferred_model_follows_recommendations_then_allowed_models() {
+        let available = ["Llama-3.2-1B.gguf", "Qwen3-1.7B.gguf", "Qwen3-4B.gguf"].map(String::from);
+        let cases = [
+            (json!({}), None),
+            (json!({"recommended_models": ["qwen3-4b", "qwen3-1.7b"]}), Some("Qwen3-4B.gguf")),
+            (json!({"recommended_models": ["mistral", "qwen3"]}), Some("Qwen3-1.7B.gguf")),
+            (json!({"recommended_models": ["mistral"]}), None),
+            (json!({"recommended_models": ["mistral"], "allowed_models": ["qwen3-4b"]}), Some("Qwen3-4B.gguf")),
+            (json!({"allowed_models": ["mistral"]}), None),
+        ];
+        for (config, expected) in cases {
+            let preset = preset(config.clone());
+            assert_eq!(preset.preferred_model(&available).as_deref(), expected, "{}", config);
+        }
+    }
+
+    #[test]
+    fn overrides_apply_from_general_to_specific() {
+        let preset = preset(json!({
+            "system_prompt": "базовый",
+            "max_tokens": 10,
+            "sampling": {"temperature": 0.7, "top_k": 40},
+            "model_overrides": {
+                "qwen3": {"system_prompt": "для qwen3", "max_tokens": 20, "sampling": {"temperature": 0.2}},
+                "qwen3-1.7b": {"max_tokens": 30, "prompt_format": "chat", "sampling": {"seed": 7}},
+                "llama": {"system_prompt": "для llama"},
+            },
+        }));
+
+        let cases = [
+            ("Qwen3-1.7B-Q4_K_M.gguf", "для qwen3", 30, PromptFormat::Chat, Some(0.2), Some(40), Some(7)),
+            ("Qwen3-4B.gguf", "для qwen3", 20, PromptFormat::Plain, Some(0.2), Some(40), None),
+            ("Llama-3.2-1B.gguf", "для llama", 10, PromptFormat::Plain, Some(0.7), Some(40), None),
+            ("Mistral-7B.gguf", "базовый", 10, PromptFormat::Plain, Some(0.7), Some(40), None),
+        ];
+        for (model, system_prompt, max_tokens, format, temperature, top_k, seed) in cases {
+            let resolved = preset.for_model(model);
+            let sampling = resolved.sampling.clone().unwrap_or_default();
+            assert_eq!(resolved.system_prompt, system_prompt, "{}", model);
+            assert_eq!(resolved.max_tokens, max_tokens, "{}", model);
+            assert!(resolved.prompt_format == format, "{}", model);
+            assert_eq!((sampling.temperature, sampling.top_k, sampling.seed), (temperature, top_k, seed), "{}", model);
+            assert!(resolved.model_overrides.is_empty());
+        }
+    }
+}
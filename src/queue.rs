@@ -0,0 +1,113 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use tokio::sync::oneshot;
+
+type Task = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug)]
+pub enum QueueError {
+    /// Too many tasks are waiting
+    Full,
+    /// The task panicked or the workers stopped
+    Failed,
+}
+
+impl std::fmt::Display for QueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueueError::Full => write!(f, "Inference queue is full, try again later"),
+            QueueError::Failed => write!(f, "Inference task failed"),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+/// Fixed pool of worker threads that run inference tasks in FIFO order.
+///
+/// Generation is CPU-bound and llama contexts are not `Send`, so every task
+/// creates and drops its context on the worker thread.
+pub struct InferenceQueue {
+    sender: Mutex<mpsc::Sender<Task>>,
+    waiting: Arc<AtomicUsize>,
+    running: Arc<AtomicUsize>,
+    capacity: usize,
+}
+
+impl InferenceQueue {
+    pub fn new(workers: usize, capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Task>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let waiting = Arc::new(AtomicUsize::new(0));
+        let running = Arc::new(AtomicUsize::new(0));
+
+        for i in 0..workers.max(1) {
+            let receiver = Arc::clone(&receiver);
+            let waiting = Arc::clone(&waiting);
+            let running = Arc::clone(&running);
+            std::thread::Builder::new()
+                .name(format!("inference-{}", i))
+                .spawn(move || loop {
+                    let task = receiver.lock().unwrap().recv();
+                    let Ok(task) = task else { break };
+                    waiting.fetch_sub(1, Ordering::SeqCst);
+                    running.fetch_add(1, Ordering::SeqCst);
+                    // A panicking task must not take the worker down with it
+                    let _ = catch_unwind(AssertUnwindSafe(task));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+                .expect("failed to spawn inference worker");
+        }
+
+        Self {
+            sender: Mutex::new(sender),
+            waiting,
+            running,
+            capacity,
+        }
+    }
+
+    /// Tasks waiting for a worker
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    /// Tasks currently executing
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Enqueues a task; the result arrives through the returned receiver
+    pub fn submit<T, F>(&self, f: F) -> Result<oneshot::Receiver<T>, QueueError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        if self.waiting.fetch_add(1, Ordering::SeqCst) >= self.capacity {
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+            return Err(QueueError::Full);
+        }
+        let (tx, rx) = oneshot::channel();
+        // The task runs inside the submitter's span, so worker logs keep the request id
+        let span = tracing::Span::current();
+        let task: Task = Box::new(move || {
+            let _enter = span.enter();
+            let _ = tx.send(f());
+        });
+        if self.sender.lock().unwrap().send(task).is_err() {
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+            return Err(QueueError::Failed);
+        }
+        Ok(rx)
+    }
+
+    /// Enqueues a task and waits for its result
+    pub async fn run<T, F>(&self, f: F) -> Result<T, QueueError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        self.submit(f)?.await.map_err(|_| QueueError::Failed)
+    }
+}
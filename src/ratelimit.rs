@@ -0,0 +1,390 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::{Local, NaiveDate};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::auth::ApiKey;
+use crate::config::LimitsConfig;
+use crate::listener::UnixPeer;
+use crate::server::{AppState, ErrorResponse};
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// Who a request is accounted to: the API key name, or the client IP
+/// (the peer process on Unix sockets) when auth is off
+#[derive(Clone)]
+pub struct Caller(pub String);
+
+/// Effective limits of one caller; `None` means unlimited
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Limits {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_day: Option<u64>,
+}
+
+impl Limits {
+    /// Values set here, the rest taken from `defaults`
+    fn or(self, defaults: Limits) -> Limits {
+        Limits {
+            requests_per_minute: self.requests_per_minute.or(defaults.requests_per_minute),
+            tokens_per_day: self.tokens_per_day.or(defaults.tokens_per_day),
+        }
+    }
+}
+
+/// Moment the counters are checked at; tests pass their own
+#[derive(Clone, Copy)]
+struct Now {
+    instant: Instant,
+    today: NaiveDate,
+}
+
+impl Now {
+    fn current() -> Self {
+        Self { instant: Instant::now(), today: Local::now().date_naive() }
+    }
+}
+
+struct Usage {
+    /// Limits applied on the caller's last request
+    limits: Limits,
+    window_start: Instant,
+    requests_in_window: u32,
+    day: NaiveDate,
+    tokens_today: u64,
+    total_requests: u64,
+    total_tokens: u64,
+}
+
+impl Usage {
+    fn new(limits: Limits, now: Now) -> Self {
+        Self {
+            limits,
+            window_start: now.instant,
+            requests_in_window: 0,
+            day: now.today,
+            tokens_today: 0,
+            total_requests: 0,
+            total_tokens: 0,
+        }
+    }
+
+    /// Starts a new minute window and a new day when they have passed
+    fn roll(&mut self, now: Now) {
+        if now.instant.saturating_duration_since(self.window_start) >= MINUTE {
+            self.window_start = now.instant;
+            self.requests_in_window = 0;
+        }
+        if self.day != now.today {
+            self.day = now.today;
+            self.tokens_today = 0;
+        }
+    }
+
+    fn window_reset_secs(&self, now: Now) -> u64 {
+        MINUTE.saturating_sub(now.instant.saturating_duration_since(self.window_start)).as_secs().max(1)
+    }
+}
+
+#[derive(Serialize)]
+pub struct UsageView {
+    pub caller: String,
+    pub requests_per_minute: Option<u32>,
+    pub requests_this_minute: u32,
+    pub window_reset_secs: u64,
+    pub tokens_per_day: Option<u64>,
+    pub tokens_today: u64,
+    pub total_requests: u64,
+    pub total_tokens: u64,
+}
+
+enum Rejection {
+    Requests { retry_after: u64 },
+    Tokens,
+}
+
+/// Request and token counters per caller, kept in memory
+pub struct RateLimiter {
+    defaults: Limits,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &LimitsConfig) -> Self {
+        Self {
+            defaults: Limits {
+                requests_per_minute: config.requests_per_minute,
+                tokens_per_day: config.tokens_per_day,
+            },
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Key-specific limits, falling back to the server defaults
+    pub fn limits_for(&self, key: &ApiKey) -> Limits {
+        key.limits.or(self.defaults)
+    }
+
+    /// Counts a request unless it is over a limit
+    fn acquire(&self, caller: &str, limits: Limits) -> Result<UsageView, Rejection> {
+        self.acquire_at(caller, limits, Now::current())
+    }
+
+    fn acquire_at(&self, caller: &str, limits: Limits, now: Now) -> Result<UsageView, Rejection> {
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(caller.to_string()).or_insert_with(|| Usage::new(limits, now));
+        entry.limits = limits;
+        entry.roll(now);
+
+        if limits.tokens_per_day.is_some_and(|limit| entry.tokens_today >= limit) {
+            return Err(Rejection::Tokens);
+        }
+        if limits.requests_per_minute.is_some_and(|limit| entry.requests_in_window >= limit) {
+            return Err(Rejection::Requests { retry_after: entry.window_reset_secs(now) });
+        }
+        entry.requests_in_window += 1;
+        entry.total_requests += 1;
+        Ok(view(caller, entry, now))
+    }
+
+    /// Adds generated tokens to the caller's daily quota
+    pub fn record_tokens(&self, caller: &Caller, tokens: usize) {
+        self.record_tokens_at(caller, tokens, Now::current());
+    }
+
+    fn record_tokens_at(&self, caller: &Caller, tokens: usize, now: Now) {
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(caller.0.clone()).or_insert_with(|| Usage::new(self.defaults, now));
+        entry.roll(now);
+        entry.tokens_today += tokens as u64;
+        entry.total_tokens += tokens as u64;
+    }
+
+    pub fn usage(&self, caller: &str, limits: Limits) -> UsageView {
+        let now = Now::current();
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(caller.to_string()).or_insert_with(|| Usage::new(limits, now));
+        entry.limits = limits;
+        entry.roll(now);
+        view(caller, entry, now)
+    }
+
+    /// Usage of every caller seen since start
+    pub fn all_usage(&self) -> Vec<UsageView> {
+        let now = Now::current();
+        let mut usage = self.usage.lock().unwrap();
+        let mut views: Vec<UsageView> = usage.iter_mut()
+            .map(|(caller, entry)| {
+                entry.roll(now);
+                view(caller, entry, now)
+            })
+            .collect();
+        views.sort_by(|a, b| a.caller.cmp(&b.caller));
+        views
+    }
+}
+
+fn view(caller: &str, usage: &Usage, now: Now) -> UsageView {
+    UsageView {
+        caller: caller.to_string(),
+        requests_per_minute: usage.limits.requests_per_minute,
+        requests_this_minute: usage.requests_in_window,
+        window_reset_secs: usage.window_reset_secs(now),
+        tokens_per_day: usage.limits.tokens_per_day,
+        tokens_today: usage.tokens_today,
+        total_requests: usage.total_requests,
+        total_tokens: usage.total_tokens,
+    }
+}
+
+fn caller_of(state: &AppState, key: &ApiKey, request: &Request) -> Caller {
+    if state.auth.enabled() {
+        return Caller(format!("key:{}", key.name));
+    }
+    if let Some(UnixPeer(peer)) = request.extensions().get::<UnixPeer>() {
+        return Caller(format!("unix:{}", peer));
+    }
+    let ip = request.extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    Caller(format!("ip:{}", ip))
+}
+
+fn set_header(headers: &mut HeaderMap, name: &'static str, value: impl ToString) {
+    if let Ok(value) = HeaderValue::from_str(&value.to_string()) {
+        headers.insert(name, value);
+    }
+}
+
+fn add_headers(headers: &mut HeaderMap, usage: &UsageView) {
+    if let Some(limit) = usage.requests_per_minute {
+        set_header(headers, "x-ratelimit-limit", limit);
+        set_header(headers, "x-ratelimit-remaining", limit.saturating_sub(usage.requests_this_minute));
+        set_header(headers, "x-ratelimit-reset", usage.window_reset_secs);
+    }
+    if let Some(limit) = usage.tokens_per_day {
+        set_header(headers, "x-ratelimit-limit-tokens", limit);
+        set_header(headers, "x-ratelimit-remaining-tokens", limit.saturating_sub(usage.tokens_today));
+    }
+}
+
+/// Enforces request and token limits before a generation is enqueued.
+/// Must run after [`crate::auth::require_api_key`]; stores the [`Caller`] for token accounting.
+pub async fn enforce_limits(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = request.extensions().get::<Arc<ApiKey>>().cloned() else {
+        return ErrorResponse::new(StatusCode::UNAUTHORIZED, "Missing API key");
+    };
+    let caller = caller_of(&state, &key, &request);
+    let limits = state.limits.limits_for(&key);
+
+    match state.limits.acquire(&caller.0, limits) {
+        Ok(usage) => {
+            request.extensions_mut().insert(caller);
+            let mut response = next.run(request).await;
+            add_headers(response.headers_mut(), &usage);
+            response
+        }
+        Err(rejection) => {
+            let usage = state.limits.usage(&caller.0, limits);
+            let (message, retry_after) = match rejection {
+                Rejection::Requests { retry_after } => (
+                    format!("Rate limit exceeded: {} requests per minute", limits.requests_per_minute.unwrap_or_default()),
+                    retry_after,
+                ),
+                Rejection::Tokens => (
+                    format!("Daily token quota exceeded: {} tokens per day", limits.tokens_per_day.unwrap_or_default()),
+                    seconds_until_midnight(),
+                ),
+            };
+            let mut response = ErrorResponse::new(StatusCode::TOO_MANY_REQUESTS, message);
+            add_headers(response.headers_mut(), &usage);
+            set_header(response.headers_mut(), "retry-after", retry_after);
+            response
+        }
+    }
+}
+
+fn seconds_until_midnight() -> u64 {
+    let now = Local::now().naive_local();
+    let midnight = (now.date() + chrono::Days::new(1)).and_hms_opt(0, 0, 0).unwrap_or(now);
+    (midnight - now).num_seconds().max(1) as u64
+}
+
+/// GET /usage: counters and limits of the calling key (or IP)
+pub async fn usage_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(key): Extension<Arc<ApiKey>>,
+    request: Request,
+) -> Response {
+    let caller = caller_of(&state, &key, &request);
+    let usage = state.limits.usage(&caller.0, state.limits.limits_for(&key));
+    let mut response = (StatusCode::OK, Json(&usage)).into_response();
+    add_headers(response.headers_mut(), &usage);
+    response
+}
+
+/// GET /usage/all: counters of every caller (admin only)
+pub async fn all_usage_handler(State(state): State<Arc<AppState>>) -> Response {
+    (StatusCode::OK, Json(state.limits.all_usage())).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(requests_per_minute: Option<u32>, tokens_per_day: Option<u64>) -> RateLimiter {
+        RateLimiter::new(&LimitsConfig { requests_per_minute, tokens_per_day })
+    }
+
+    fn at(start: Now, secs: u64, days: u64) -> Now {
+        Now {
+            instant: start.instant + Duration::from_secs(secs),
+            today: start.today + chrono::Days::new(days),
+        }
+    }
+
+    fn start() -> Now {
+        Now { instant: Instant::now(), today: NaiveDate::from_ymd_opt(2026, 10, 18).unwrap() }
+    }
+
+    #[test]
+    fn requests_are_limited_per_minute_window() {
+        let limiter = limiter(Some(2), None);
+        let limits = limiter.defaults;
+        let t0 = start();
+
+        assert!(limiter.acquire_at("ip:10.0.0.1", limits, t0).is_ok());
+        let usage = limiter.acquire_at("ip:10.0.0.1", limits, at(t0, 10, 0)).ok().unwrap();
+        assert_eq!(usage.requests_this_minute, 2);
+        assert_eq!(usage.window_reset_secs, 50);
+
+        assert!(matches!(
+            limiter.acquire_at("ip:10.0.0.1", limits, at(t0, 59, 0)),
+            Err(Rejection::Requests { retry_after: 1 })
+        ));
+        // Other callers have their own window
+        assert!(limiter.acquire_at("ip:10.0.0.2", limits, at(t0, 59, 0)).is_ok());
+
+        let usage = limiter.acquire_at("ip:10.0.0.1", limits, at(t0, 60, 0)).ok().unwrap();
+        assert_eq!(usage.requests_this_minute, 1);
+        assert_eq!(usage.total_requests, 3);
+    }
+
+    #[test]
+    fn daily_token_quota_resets_on_the_next_day() {
+        let limiter = limiter(None, Some(100));
+        let limits = limiter.defaults;
+        let caller = Caller("key:ci".to_string());
+        let t0 = start();
+
+        assert!(limiter.acquire_at(&caller.0, limits, t0).is_ok());
+        limiter.record_tokens_at(&caller, 60, at(t0, 1, 0));
+        // The last request may go over the quota, the next one is refused
+        assert!(limiter.acquire_at(&caller.0, limits, at(t0, 2, 0)).is_ok());
+        limiter.record_tokens_at(&caller, 60, at(t0, 3, 0));
+        assert!(matches!(limiter.acquire_at(&caller.0, limits, at(t0, 3600, 0)), Err(Rejection::Tokens)));
+
+        let usage = limiter.acquire_at(&caller.0, limits, at(t0, 3600, 1)).ok().unwrap();
+        assert_eq!(usage.tokens_today, 0);
+        assert_eq!(usage.total_tokens, 120);
+    }
+
+    #[test]
+    fn key_limits_override_the_server_defaults() {
+        let defaults = Limits { requests_per_minute: Some(60), tokens_per_day: Some(10_000) };
+        let cases = [
+            (Limits::default(), defaults),
+            (Limits { requests_per_minute: Some(5), tokens_per_day: None }, Limits { requests_per_minute: Some(5), tokens_per_day: Some(10_000) }),
+            (Limits { requests_per_minute: None, tokens_per_day: Some(50) }, Limits { requests_per_minute: Some(60), tokens_per_day: Some(50) }),
+        ];
+        for (key, expected) in cases {
+            assert_eq!(key.or(defaults), expected);
+        }
+        assert_eq!(Limits::default().or(Limits::default()), Limits::default());
+    }
+
+    #[test]
+    fn changed_limits_apply_to_existing_counters() {
+        let limiter = limiter(Some(10), None);
+        let t0 = start();
+        for n in 0..3 {
+            assert!(limiter.acquire_at("key:ci", limiter.defaults, at(t0, n, 0)).is_ok());
+        }
+        let tighter = Limits { requests_per_minute: Some(3), tokens_per_day: None };
+        assert!(matches!(limiter.acquire_at("key:ci", tighter, at(t0, 5, 0)), Err(Rejection::Requests { .. })));
+    }
+}
@@ -0,0 +1,329 @@
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::CacheConfig;
+use crate::engine::{FinishReason, Generation, GenerationParams, LabelProbability};
+use crate::server::ResolvedRequest;
+
+/// Lookup key of a cacheable request
+pub struct CacheKey {
+    hash: String,
+    /// Preset snapshot version the request was resolved against; `None` without a preset
+    preset_version: Option<u64>,
+}
+
+struct CacheEntry {
+    text: String,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    finish_reason: FinishReason,
+    probabilities: Vec<LabelProbability>,
+    preset_version: Option<u64>,
+    inserted: Instant,
+    last_used: Instant,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    /// Newest preset version seen; entries of older versions are dropped
+    preset_version: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl CacheState {
+    /// Forgets preset entries once presets have been reloaded
+    fn observe_version(&mut self, version: Option<u64>) {
+        if let Some(version) = version.filter(|v| *v > self.preset_version) {
+            self.preset_version = version;
+            self.entries.retain(|_, e| e.preset_version.is_none_or(|v| v >= version));
+        }
+    }
+}
+
+/// Counters for /metrics
+pub struct CacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Results of deterministic (greedy) requests, reused when the same request repeats
+pub struct ResponseCache {
+    max_entries: usize,
+    ttl: Duration,
+    state: Mutex<CacheState>,
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            max_entries: config.max_entries,
+            ttl: Duration::from_secs(config.ttl_secs),
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.max_entries > 0
+    }
+
+    /// Key over model, preset version, rendered prompt and everything that affects the output.
+    /// `None` when the cache is off, the request samples randomly or asks for logprobs.
+    pub fn key(&self, resolved: &ResolvedRequest, prompt: &str, params: &GenerationParams) -> Option<CacheKey> {
+        if !self.enabled() || !params.sampling.is_greedy() || params.logprobs.is_some() {
+            return None;
+        }
+        let preset_version = resolved.preset.as_ref().map(|_| resolved.preset_version);
+        let material = serde_json::json!({
+            "model": resolved.model_name,
+            "preset": resolved.preset.as_ref().map(|p| &p.name),
+            "preset_version": preset_version,
+            "prompt": prompt,
+            "max_tokens": params.max_tokens,
+            "stop_on_newline": params.stop_on_newline,
+            "stop_on_json": params.stop_on_json,
+            "sampling": params.sampling,
+            "grammar": params.grammar,
+            "labels": params.labels,
+        });
+        Some(CacheKey {
+            hash: format!("{:x}", Sha256::digest(material.to_string().as_bytes())),
+            preset_version,
+        })
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Generation> {
+        let mut state = self.state.lock().unwrap();
+        state.observe_version(key.preset_version);
+        if state.entries.get(&key.hash).is_some_and(|e| e.inserted.elapsed() >= self.ttl) {
+            state.entries.remove(&key.hash);
+        }
+        let hit = state.entries.get_mut(&key.hash).map(|entry| {
+            entry.last_used = Instant::now();
+            Generation {
+                text: entry.text.clone(),
+                prompt_tokens: entry.prompt_tokens,
+                cached_tokens: entry.prompt_tokens,
+                completion_tokens: entry.completion_tokens,
+                finish_reason: entry.finish_reason,
+                prompt_eval_time: Duration::ZERO,
+                generation_time: Duration::ZERO,
+                cache_hit: true,
+                probabilities: entry.probabilities.clone(),
+                logprobs: None,
+            }
+        });
+        if hit.is_some() {
+            state.hits += 1;
+        } else {
+            state.misses += 1;
+        }
+        hit
+    }
+
+    fn insert(&self, key: CacheKey, generation: &Generation) {
+        // Cancelled and timed-out outputs are partial and depend on timing
+        if !matches!(generation.finish_reason, FinishReason::Stop | FinishReason::Length) {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.observe_version(key.preset_version);
+        if key.preset_version.is_some_and(|v| v < state.preset_version) {
+            return;
+        }
+
+        let ttl = self.ttl;
+        state.entries.retain(|_, e| e.inserted.elapsed() < ttl);
+        if state.entries.len() >= self.max_entries && !state.entries.contains_key(&key.hash) {
+            // Least recently used entry makes room
+            if let Some(oldest) = state.entries.iter().min_by_key(|(_, e)| e.last_used).map(|(k, _)| k.clone()) {
+                state.entries.remove(&oldest);
+            }
+        }
+        let now = Instant::now();
+        state.entries.insert(key.hash, CacheEntry {
+            text: generation.text.clone(),
+            prompt_tokens: generation.prompt_tokens,
+            completion_tokens: generation.completion_tokens,
+            finish_reason: generation.finish_reason,
+            probabilities: generation.probabilities.clone(),
+            preset_version: key.preset_version,
+            inserted: now,
+            last_used: now,
+        });
+    }
+
+    /// Returns the cached result for `key`, or runs `generate` and caches a complete result
+    pub fn get_or_generate(&self, key: Option<CacheKey>, generate: impl FnOnce() -> Result<Generation>) -> Result<Generation> {
+        let Some(key) = key else {
+            return generate();
+        };
+        if let Some(hit) = self.get(&key) {
+            return Ok(hit);
+        }
+        let generation = generate()?;
+        self.insert(key, &generation);
+        Ok(generation)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            entries: state.entries.len(),
+            hits: state.hits,
+            misses: state.misses,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::SamplingParams;
+    use crate::presets::Preset;
+    use std::cell::Cell;
+
+    fn cache(max_entries: usize, ttl_secs: u64) -> ResponseCache {
+        ResponseCache::new(&CacheConfig { max_entries, ttl_secs })
+    }
+
+    fn resolved(preset_version: Option<u64>) -> ResolvedRequest {
+        let preset: Option<Preset> = preset_version.map(|_| serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "description": "",
+            "max_tokens": 10,
+            "stop_on_newline": true,
+        }))
+        .unwrap());
+        ResolvedRequest {
+            model_name: "Qwen3-1.7B.gguf".to_string(),
+            preset,
+            preset_version: preset_version.unwrap_or(0),
+        }
+    }
+
+    fn generation(text: &str) -> Generation {
+        Generation {
+            text: text.to_string(),
+            prompt_tokens: 10,
+            completion_tokens: 3,
+            finish_reason: FinishReason::Stop,
+            ..Generation::cancelled()
+        }
+    }
+
+    /// Runs a request through the cache; returns the text and whether it was generated
+    fn request(cache: &ResponseCache, key: Option<CacheKey>, text: &str) -> (String, bool) {
+        let generated = Cell::new(false);
+        let result = cache.get_or_generate(key, || {
+            generated.set(true);
+            Ok(generation(text))
+        }).unwrap();
+        (result.text, generated.get())
+    }
+
+    #[test]
+    fn key_is_stable_and_covers_the_inputs() {
+        let cache = cache(10, 60);
+        let params = GenerationParams::default();
+        let hash = |resolved: &ResolvedRequest, prompt: &str, params: &GenerationParams| {
+            cache.key(resolved, prompt, params).map(|key| key.hash)
+        };
+        let base = hash(&resolved(Some(1)), "Вход: iPhone", &params).unwrap();
+        assert_eq!(hash(&resolved(Some(1)), "Вход: iPhone", &params).unwrap(), base);
+
+        assert_ne!(hash(&resolved(Some(1)), "Вход: газета", &params).unwrap(), base);
+        assert_ne!(hash(&resolved(Some(2)), "Вход: iPhone", &params).unwrap(), base);
+        assert_ne!(hash(&resolved(None), "Вход: iPhone", &params).unwrap(), base);
+        let longer = GenerationParams { max_tokens: params.max_tokens + 1, ..params.clone() };
+        assert_ne!(hash(&resolved(Some(1)), "Вход: iPhone", &longer).unwrap(), base);
+    }
+
+    #[test]
+    fn only_deterministic_requests_get_a_key() {
+        let params = GenerationParams::default();
+        assert!(cache(0, 60).key(&resolved(None), "Вход", &params).is_none());
+
+        let cache = cache(10, 60);
+        assert!(cache.key(&resolved(None), "Вход", &params).is_some());
+        let sampled = GenerationParams {
+            sampling: SamplingParams { temperature: Some(0.7), ..SamplingParams::default() },
+            ..params.clone()
+        };
+        assert!(cache.key(&resolved(None), "Вход", &sampled).is_none());
+        let logprobs = GenerationParams { logprobs: Some(3), ..params };
+        assert!(cache.key(&resolved(None), "Вход", &logprobs).is_none());
+    }
+
+    #[test]
+    fn repeated_request_is_answered_from_the_cache() {
+        let cache = cache(10, 60);
+        let key = || cache.key(&resolved(None), "Вход", &GenerationParams::default());
+        assert_eq!(request(&cache, key(), "ДЕШЕВЫЙ"), ("ДЕШЕВЫЙ".to_string(), true));
+        assert_eq!(request(&cache, key(), "ДОРОГОЙ"), ("ДЕШЕВЫЙ".to_string(), false));
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (1, 1, 1));
+    }
+
+    #[test]
+    fn partial_generations_are_not_cached() {
+        let cache = cache(10, 60);
+        let key = || cache.key(&resolved(None), "Вход", &GenerationParams::default());
+        cache.get_or_generate(key(), || Ok(Generation { finish_reason: FinishReason::Timeout, ..generation("ДЕШ") })).unwrap();
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[test]
+    fn least_recently_used_entry_is_evicted() {
+        let cache = cache(2, 60);
+        let key = |prompt: &str| cache.key(&resolved(None), prompt, &GenerationParams::default());
+        let pause = || std::thread::sleep(Duration::from_millis(2));
+
+        request(&cache, key("a"), "A");
+        pause();
+        request(&cache, key("b"), "B");
+        pause();
+        // Using "a" makes "b" the least recently used entry
+        assert!(!request(&cache, key("a"), "A").1);
+        pause();
+        request(&cache, key("c"), "C");
+
+        assert_eq!(cache.stats().entries, 2);
+        assert!(!request(&cache, key("a"), "A").1);
+        assert!(!request(&cache, key("c"), "C").1);
+        assert!(request(&cache, key("b"), "B").1);
+    }
+
+    #[test]
+    fn expired_entries_are_generated_again() {
+        // With a zero TTL every entry is already expired when it is looked up
+        let cache = cache(10, 0);
+        let key = || cache.key(&resolved(None), "Вход", &GenerationParams::default());
+        assert!(request(&cache, key(), "ДЕШЕВЫЙ").1);
+        assert!(request(&cache, key(), "ДЕШЕВЫЙ").1);
+        assert_eq!(cache.stats().hits, 0);
+    }
+
+    #[test]
+    fn preset_reload_drops_entries_of_older_versions() {
+        let cache = cache(10, 60);
+        let params = GenerationParams::default();
+        request(&cache, cache.key(&resolved(Some(1)), "Вход", &params), "v1");
+        request(&cache, cache.key(&resolved(None), "Вход", &params), "без пресета");
+        assert_eq!(cache.stats().entries, 2);
+
+        // The first request against version 2 forgets version 1, but not requests without a preset
+        assert!(request(&cache, cache.key(&resolved(Some(2)), "Вход", &params), "v2").1);
+        assert_eq!(cache.stats().entries, 2);
+        assert!(!request(&cache, cache.key(&resolved(None), "Вход", &params), "без пресета").1);
+
+        // A late result of version 1 is not stored
+        assert!(request(&cache, cache.key(&resolved(Some(1)), "Вход", &params), "v1").1);
+        assert_eq!(cache.stats().entries, 2);
+    }
+}
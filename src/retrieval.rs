@@ -0,0 +1,306 @@
+use anyhow::{bail, Context, Result};
+use llama_cpp_2::llama_backend::LlamaBackend;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tracing::debug;
+
+use crate::cli::CliArgs;
+use crate::embeddings::{self, Pooling};
+use crate::engine::{self, ModelCache};
+use crate::presets::Preset;
+
+/// Directory with indexes built by `chat-np index`
+pub const INDEXES_DIR: &str = "indexes";
+/// Files picked up when indexing a folder
+const INDEXED_EXTENSIONS: &[&str] = &["txt", "md", "markdown"];
+const DEFAULT_CHUNK_CHARS: usize = 1000;
+const DEFAULT_OVERLAP_CHARS: usize = 200;
+/// Used when a preset's `retrieval.template` is not set
+const DEFAULT_TEMPLATE: &str = "Справочные материалы:\n{context}";
+
+/// Retrieval settings stored in a preset under `retrieval`
+#[derive(Deserialize, Serialize, Clone)]
+pub struct RetrievalConfig {
+    /// Index name, i.e. `indexes/<index>.json`
+    pub index: String,
+    /// How many of the most similar chunks are inserted
+    #[serde(default = "default_top_k")]
+    pub top_k: usize,
+    /// Chunks with a lower cosine similarity are skipped
+    #[serde(default)]
+    pub min_score: Option<f32>,
+    /// Text placed before the user input; `{context}` is replaced with the chunks
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+fn default_top_k() -> usize {
+    3
+}
+
+impl RetrievalConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !is_valid_index_name(&self.index) {
+            bail!("некорректное имя индекса: {}", self.index);
+        }
+        Ok(())
+    }
+}
+
+/// Index names become file names under `indexes/`, so they must not contain path separators
+fn is_valid_index_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(['/', '\\'])
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Chunk {
+    /// File path relative to the indexed folder
+    pub source: String,
+    pub text: String,
+    /// L2-normalised, so the dot product is the cosine similarity
+    pub embedding: Vec<f32>,
+}
+
+/// Chunks of a document folder with their embeddings
+#[derive(Deserialize, Serialize)]
+pub struct Index {
+    pub name: String,
+    /// Model used for the chunk embeddings; queries are embedded with the same model
+    pub model: String,
+    pub pooling: Pooling,
+    pub source_dir: String,
+    pub chunk_chars: usize,
+    pub overlap_chars: usize,
+    pub created_at: String,
+    pub chunks: Vec<Chunk>,
+}
+
+pub fn index_path(name: &str) -> PathBuf {
+    Path::new(INDEXES_DIR).join(format!("{}.json", name))
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+impl Index {
+    fn load(name: &str) -> Result<Self> {
+        let path = index_path(name);
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Retrieval index '{}' not found ({}); build it with `chat-np index`", name, path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse retrieval index {}", path.display()))
+    }
+
+    /// Most similar chunks first
+    fn search(&self, query: &[f32], top_k: usize, min_score: Option<f32>) -> Vec<(f32, &Chunk)> {
+        let mut scored: Vec<(f32, &Chunk)> = self.chunks.iter()
+            .map(|chunk| (dot(query, &chunk.embedding), chunk))
+            .filter(|(score, _)| min_score.is_none_or(|min| *score >= min))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.truncate(top_k);
+        scored
+    }
+}
+
+/// Looks up context for presets with a `retrieval` section.
+///
+/// Indexes are loaded on first use and reloaded when their file changes.
+#[derive(Default)]
+pub struct Retriever {
+    indexes: Mutex<HashMap<String, (SystemTime, Arc<Index>)>>,
+}
+
+impl Retriever {
+    fn index(&self, name: &str) -> Result<Arc<Index>> {
+        let modified = fs::metadata(index_path(name))
+            .and_then(|m| m.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut indexes = self.indexes.lock().unwrap();
+        if let Some((loaded_at, index)) = indexes.get(name) {
+            if *loaded_at == modified {
+                return Ok(Arc::clone(index));
+            }
+        }
+        let index = Arc::new(Index::load(name)?);
+        indexes.insert(name.to_string(), (modified, Arc::clone(&index)));
+        Ok(index)
+    }
+
+    /// Context block for the prompt, or `None` when the preset has no retrieval
+    /// or nothing relevant was found.
+    ///
+    /// The embedding model comes from `models`, so the server shares it with generation
+    /// (the index is usually built with the chat model itself).
+    pub fn context(&self, models: &ModelCache, backend: &LlamaBackend, preset: &Preset, input: &str) -> Result<Option<String>> {
+        let Some(config) = &preset.retrieval else {
+            return Ok(None);
+        };
+        let index = self.index(&config.index)?;
+        let model = models.get_or_load(backend, &index.model)?;
+        let query = embeddings::embed(&model, backend, &[input.to_string()], index.pooling, true)?;
+        let Some(query) = query.vectors.first() else {
+            return Ok(None);
+        };
+
+        let found = index.search(query, config.top_k, config.min_score);
+        debug!(index = %index.name, chunks = found.len(), best_score = found.first().map(|(s, _)| *s), "retrieved context");
+        if found.is_empty() {
+            return Ok(None);
+        }
+        let context = found.iter()
+            .map(|(_, chunk)| format!("[{}]\n{}", chunk.source, chunk.text))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let template = config.template.as_deref().unwrap_or(DEFAULT_TEMPLATE);
+        Ok(Some(template.replace("{context}", &context)))
+    }
+}
+
+/// Splits text into chunks of at most `max_chars` characters along paragraph boundaries.
+/// Consecutive chunks share trailing paragraphs of up to `overlap` characters.
+fn chunk_text(text: &str, max_chars: usize, overlap: usize) -> Vec<String> {
+    let mut paragraphs = Vec::new();
+    for paragraph in text.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+        if paragraph.chars().count() <= max_chars {
+            paragraphs.push(paragraph.to_string());
+            continue;
+        }
+        // Long paragraphs are cut at word boundaries
+        let mut piece = String::new();
+        for word in paragraph.split_whitespace() {
+            if !piece.is_empty() && piece.chars().count() + 1 + word.chars().count() > max_chars {
+                paragraphs.push(std::mem::take(&mut piece));
+            }
+            if !piece.is_empty() {
+                piece.push(' ');
+            }
+            piece.push_str(word);
+        }
+        if !piece.is_empty() {
+            paragraphs.push(piece);
+        }
+    }
+
+    let mut chunks = Vec::new();
+    let mut current: Vec<String> = Vec::new();
+    let mut length = 0;
+    let mut fresh = 0;
+    for paragraph in paragraphs {
+        let paragraph_len = paragraph.chars().count();
+        if fresh > 0 && length + paragraph_len > max_chars {
+            chunks.push(current.join("\n\n"));
+            // Keep the tail of the chunk as overlap for the next one
+            let mut kept = 0;
+            let mut tail = Vec::new();
+            while let Some(last) = current.pop() {
+                let len = last.chars().count();
+                if kept + len > overlap || kept + len + paragraph_len > max_chars {
+                    break;
+                }
+                kept += len;
+                tail.push(last);
+            }
+            tail.reverse();
+            current = tail;
+            length = kept;
+            fresh = 0;
+        }
+        length += paragraph_len;
+        fresh += 1;
+        current.push(paragraph);
+    }
+    if fresh > 0 {
+        chunks.push(current.join("\n\n"));
+    }
+    chunks
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir).with_context(|| format!("не удалось открыть {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| INDEXED_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// `chat-np index --name NAME --dir PATH [--model FILE] [--pooling mean|model|cls|last]
+/// [--chunk-chars 1000] [--overlap-chars 200]`
+///
+/// Splits `.txt`/`.md` files of a folder into chunks, embeds them and writes `indexes/NAME.json`.
+pub fn run_command(backend: &LlamaBackend, available_models: &[String], cli: &CliArgs) -> Result<()> {
+    let name = cli.require("name")?;
+    let dir = cli.require("dir")?;
+    if !is_valid_index_name(name) {
+        bail!("некорректное имя индекса: {}", name);
+    }
+    let model_name = cli.value("model")
+        .map(str::to_string)
+        .or_else(|| available_models.first().cloned())
+        .unwrap_or_default();
+    if !available_models.contains(&model_name) {
+        bail!("модель '{}' не найдена. Доступные модели: {:?}", model_name, available_models);
+    }
+    let pooling = Pooling::parse(cli.value("pooling").unwrap_or("mean"))?;
+    let chunk_chars = cli.parse_value::<usize>("chunk-chars")?.unwrap_or(DEFAULT_CHUNK_CHARS).max(1);
+    let overlap_chars = cli.parse_value::<usize>("overlap-chars")?.unwrap_or(DEFAULT_OVERLAP_CHARS);
+
+    let mut files = Vec::new();
+    collect_files(Path::new(dir), &mut files)?;
+    files.sort();
+    if files.is_empty() {
+        bail!("в {} нет файлов {:?}", dir, INDEXED_EXTENSIONS);
+    }
+
+    eprintln!("Загрузка модели {}...", model_name);
+    let model = engine::load_model(backend, &model_name)?;
+
+    let mut chunks = Vec::new();
+    for (n, path) in files.iter().enumerate() {
+        let source = path.strip_prefix(dir).unwrap_or(path).to_string_lossy().replace('\\', "/");
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("не удалось прочитать {}", path.display()))?;
+        let texts = chunk_text(&text, chunk_chars, overlap_chars);
+        let embedded = embeddings::embed(&model, backend, &texts, pooling, true)
+            .with_context(|| format!("не удалось получить эмбеддинги для {}", path.display()))?;
+        eprintln!("[{}/{}] {}: фрагментов {}", n + 1, files.len(), source, texts.len());
+        chunks.extend(texts.into_iter().zip(embedded.vectors).map(|(text, embedding)| Chunk {
+            source: source.clone(),
+            text,
+            embedding,
+        }));
+    }
+
+    let index = Index {
+        name: name.to_string(),
+        model: model_name,
+        pooling,
+        source_dir: dir.to_string(),
+        chunk_chars,
+        overlap_chars,
+        created_at: chrono::Local::now().to_rfc3339(),
+        chunks,
+    };
+    fs::create_dir_all(INDEXES_DIR)
+        .with_context(|| format!("не удалось создать директорию {}", INDEXES_DIR))?;
+    let path = index_path(name);
+    // Written to a temporary file first so a running server never reads a partial index
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec(&index)?)
+        .with_context(|| format!("не удалось записать {}", tmp.display()))?;
+    fs::rename(&tmp, &path)?;
+    eprintln!("Индекс '{}': файлов {}, фрагментов {} → {}", name, files.len(), index.chunks.len(), path.display());
+    Ok(())
+}
@@ -0,0 +1,1013 @@
+use anyhow::Result;
+use llama_cpp_2::{llama_backend::LlamaBackend, model::LlamaModel};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    middleware,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::UnboundedReceiverStream, StreamExt};
+use tracing::{debug, error, info, warn};
+
+use crate::audit::{self, AuditLog, Exchange, Source};
+use crate::auth::{self, ApiKey, Auth};
+use crate::cancel::{ActiveRequest, ActiveRequests, CancelOnDrop, CancelToken};
+use crate::completions;
+use crate::config::Config;
+use crate::embeddings;
+use crate::inspect;
+use crate::engine::{self, FinishReason, Generation, GenerationParams, LabelProbability, Logprobs, ModelCache, PromptCache};
+use crate::eval::{self, EvalRuntime, TestCase};
+use crate::jobs::{self, JobStore};
+use crate::listener::{self, Listen};
+use crate::logging::{self, RequestId};
+use crate::metrics::{self, Metrics, Readiness, RequestLabels};
+use crate::postprocess::{self, Parsed, ValidationFailed};
+use crate::preset_store::PresetStore;
+use crate::presets::Preset;
+use crate::queue::{InferenceQueue, QueueError};
+use crate::ratelimit::{self, Caller, RateLimiter};
+use crate::response_cache::ResponseCache;
+use crate::retrieval::Retriever;
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct ChatRequest {
+    pub prompt: String,
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    #[serde(default)]
+    pub max_time_ms: Option<u64>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub preset: Option<String>,
+    /// Client-chosen id that can be passed to `DELETE /chat/{request_id}`
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// Send tokens as server-sent events while they are generated
+    #[serde(default)]
+    pub stream: bool,
+    /// `false` bypasses the response cache
+    #[serde(default = "default_true")]
+    pub cache: bool,
+    /// Return the log-probability of every generated token
+    #[serde(default)]
+    pub logprobs: bool,
+    /// Number of most likely alternatives per token (requires `logprobs`)
+    #[serde(default)]
+    pub top_logprobs: Option<usize>,
+}
+
+impl ChatRequest {
+    /// Validated logprobs option, see [`check_logprobs`]
+    pub(crate) fn logprobs(&self) -> Result<Option<usize>, String> {
+        check_logprobs(self.logprobs, self.top_logprobs)
+    }
+}
+
+/// Number of alternatives per token when logprobs are requested, `None` when they are not
+pub(crate) fn check_logprobs(logprobs: bool, top_logprobs: Option<usize>) -> Result<Option<usize>, String> {
+    match top_logprobs {
+        Some(top) if top > engine::MAX_TOP_LOGPROBS => {
+            Err(format!("'top_logprobs' must be from 0 to {}", engine::MAX_TOP_LOGPROBS))
+        }
+        Some(_) if !logprobs => Err("'top_logprobs' requires 'logprobs': true".to_string()),
+        _ => Ok(logprobs.then(|| top_logprobs.unwrap_or(0))),
+    }
+}
+
+#[derive(Serialize)]
+struct ChatResponse {
+    response: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<FinishReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_hit: Option<bool>,
+    /// Label probabilities of classification presets
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    probabilities: Vec<LabelProbability>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<Logprobs>,
+    #[serde(flatten)]
+    parsed: Parsed,
+}
+
+impl ChatResponse {
+    fn error(message: impl Into<String>) -> Self {
+        Self {
+            response: message.into(),
+            request_id: None,
+            finish_reason: None,
+            cache_hit: None,
+            probabilities: Vec::new(),
+            logprobs: None,
+            parsed: Parsed::default(),
+        }
+    }
+}
+
+/// Final event of a streamed /chat response
+#[derive(Serialize)]
+struct ChatStreamDone {
+    response: String,
+    request_id: String,
+    finish_reason: FinishReason,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    cache_hit: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    probabilities: Vec<LabelProbability>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<Logprobs>,
+    #[serde(flatten)]
+    parsed: Parsed,
+}
+
+#[derive(Serialize)]
+struct ModelsResponse {
+    models: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct PresetsResponse {
+    presets: Vec<PresetInfo>,
+}
+
+#[derive(Serialize)]
+struct PresetInfo {
+    name: String,
+    description: String,
+}
+
+pub(crate) struct AppState {
+    pub backend: LlamaBackend,
+    pub available_models: Vec<String>,
+    pub models: ModelCache,
+    pub presets: Arc<PresetStore>,
+    pub queue: InferenceQueue,
+    pub jobs: Arc<JobStore>,
+    /// Running /chat requests that can be cancelled by id
+    pub active: Arc<ActiveRequests>,
+    /// Server-wide cap for generation time
+    pub max_time: Option<Duration>,
+    pub auth: Auth,
+    pub limits: RateLimiter,
+    pub metrics: Metrics,
+    /// Loading state of the default model for /ready
+    pub readiness: Mutex<Readiness>,
+    pub audit: AuditLog,
+    pub cache: ResponseCache,
+    /// Context lookup for presets with a `retrieval` section
+    pub retriever: Retriever,
+}
+
+async fn models_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(key): Extension<Arc<ApiKey>>,
+) -> impl IntoResponse {
+    // Only models the API key may use are listed
+    (StatusCode::OK, Json(ModelsResponse { 
+        models: state.available_models.iter()
+            .filter(|m| key.allows_model(m))
+            .cloned()
+            .collect()
+    }))
+}
+
+async fn presets_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(key): Extension<Arc<ApiKey>>,
+) -> impl IntoResponse {
+    let snapshot = state.presets.snapshot();
+    let presets_info: Vec<PresetInfo> = snapshot.presets.iter()
+        .filter(|p| key.allows_preset(&p.name))
+        .map(|p| PresetInfo {
+            name: p.name.clone(),
+            description: p.description.clone(),
+        })
+        .collect();
+    
+    (StatusCode::OK, Json(PresetsResponse { presets: presets_info }))
+}
+
+async fn presets_status_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (StatusCode::OK, Json(state.presets.status()))
+}
+
+async fn presets_reload_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    state.presets.reload();
+    (StatusCode::OK, Json(state.presets.status()))
+}
+
+/// Preset and model chosen for a request
+pub(crate) struct ResolvedRequest {
+    pub model_name: String,
+    /// Preset with per-model overrides already applied
+    pub preset: Option<Preset>,
+    /// Version of the preset snapshot the preset was taken from
+    pub preset_version: u64,
+}
+
+impl ResolvedRequest {
+    pub fn max_tokens(&self, requested: Option<usize>) -> usize {
+        requested
+            .or(self.preset.as_ref().map(|p| p.max_tokens))
+            .unwrap_or(engine::DEFAULT_MAX_TOKENS)
+    }
+}
+
+/// Picks the preset and model for a request. Errors are client errors (400).
+pub(crate) fn resolve_request(state: &AppState, preset_name: Option<&str>, model: Option<&str>) -> Result<ResolvedRequest, String> {
+    // Presets come from the in-memory store, which reloads them when files change
+    let presets = state.presets.snapshot();
+
+    let preset = match preset_name {
+        Some(preset_name) => match presets.get(preset_name) {
+            Some(preset) => Some(preset),
+            None => return Err(format!("Preset '{}' not found. Use /presets to see available presets", preset_name)),
+        },
+        None => None,
+    };
+
+    // Explicit model, then the preset's preferred model, then the first available one
+    let model_name = model.map(str::to_string)
+        .or_else(|| preset.and_then(|p| p.preferred_model(&state.available_models)))
+        .or_else(|| state.available_models.first().cloned())
+        .unwrap_or_default();
+
+    if !state.available_models.contains(&model_name) {
+        return Err(format!("Model '{}' not found. Available models: {:?}", model_name, state.available_models));
+    }
+
+    if let Some(preset) = preset {
+        if !preset.allows_model(&model_name) {
+            return Err(format!("Preset '{}' does not allow model '{}'. Allowed models: {:?}", preset.name, model_name, preset.allowed_models));
+        }
+    }
+
+    Ok(ResolvedRequest {
+        preset: preset.map(|p| p.for_model(&model_name)),
+        preset_version: presets.version,
+        model_name,
+    })
+}
+
+/// Determine prompt and generation parameters from preset or request
+pub(crate) fn prepare_prompt(
+    state: &AppState,
+    model: &LlamaModel,
+    resolved: &ResolvedRequest,
+    input: &str,
+    system_prompt: Option<&str>,
+    max_tokens: Option<usize>,
+    max_time_ms: Option<u64>,
+) -> anyhow::Result<(String, GenerationParams)> {
+    let (prompt, mut params) = if let Some(preset) = &resolved.preset {
+        let context = state.retriever.context(&state.models, &state.backend, preset, input)?;
+        let prompt = engine::render_prompt(model, preset, input, context.as_deref())?;
+        let mut params = GenerationParams::from_preset(preset);
+        params.max_tokens = max_tokens.unwrap_or(preset.max_tokens);
+        (prompt, params)
+    } else {
+        let prompt = match system_prompt {
+            Some(system_prompt) if !system_prompt.is_empty() => format!("{}\n\n{}", system_prompt, input),
+            _ => input.to_string(),
+        };
+        let params = GenerationParams {
+            max_tokens: max_tokens.unwrap_or(engine::DEFAULT_MAX_TOKENS),
+            ..GenerationParams::default()
+        };
+        (prompt, params)
+    };
+    if let Some(ms) = max_time_ms {
+        params.max_time = Some(Duration::from_millis(ms));
+    }
+    Ok((prompt, params))
+}
+
+/// Applies the server-wide time cap on top of the request and preset limits
+pub(crate) fn cap_max_time(state: &AppState, params: &mut GenerationParams) {
+    params.cap_max_time(state.max_time);
+}
+
+/// Runs a single chat request on the calling (worker) thread,
+/// counts the generated tokens against the caller's quota and writes the audit record.
+///
+/// A retry after failed validation is announced by calling `on_token` with empty text.
+pub(crate) fn run_chat(
+    state: &AppState,
+    caller: &Caller,
+    source: &Source,
+    resolved: &ResolvedRequest,
+    req: &ChatRequest,
+    on_token: &mut dyn FnMut(&str, usize) -> bool,
+) -> anyhow::Result<(Generation, Parsed)> {
+    let started = Instant::now();
+    let mut rendered_prompt = None;
+    let result = state.models.get_or_load(&state.backend, &resolved.model_name).and_then(|model| {
+        let (prompt, mut params) = prepare_prompt(state, &model, resolved, &req.prompt, req.system_prompt.as_deref(), req.max_tokens, req.max_time_ms)?;
+        cap_max_time(state, &mut params);
+        params.logprobs = req.logprobs().map_err(anyhow::Error::msg)?;
+        let mut attempts = 0;
+        let result = postprocess::generate_validated(resolved.preset.as_ref(), &params, |params| {
+            attempts += 1;
+            if attempts > 1 && !on_token("", 0) {
+                return Ok(Generation::cancelled());
+            }
+            let cache_key = req.cache.then(|| state.cache.key(resolved, &prompt, params)).flatten();
+            state.cache.get_or_generate(cache_key, || {
+                let mut ctx = engine::new_context(&model, &state.backend)?;
+                engine::generate_cached(&model, &mut ctx, &mut PromptCache::default(), &prompt, params, &mut *on_token)
+            })
+        });
+        rendered_prompt = Some(prompt);
+        result
+    });
+    state.audit.record(Exchange {
+        source,
+        model: &resolved.model_name,
+        preset: resolved.preset.as_ref(),
+        input: &req.prompt,
+        rendered_prompt: rendered_prompt.as_deref(),
+        result: result.as_ref().map(|(generation, _)| generation),
+        latency: started.elapsed(),
+    });
+
+    let (generation, parsed) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            // Rejected attempts still used the caller's tokens
+            if let Some(failed) = e.downcast_ref::<ValidationFailed>() {
+                state.limits.record_tokens(caller, failed.completion_tokens);
+            }
+            return Err(e);
+        }
+    };
+    if generation.cache_hit {
+        // Streams and jobs still receive the text; a cached answer costs no tokens
+        on_token(&generation.text, generation.completion_tokens);
+    } else {
+        state.limits.record_tokens(caller, generation.completion_tokens);
+        state.metrics.record_generation(&resolved.model_name, &generation);
+    }
+    info!(
+        model = %resolved.model_name,
+        preset = resolved.preset.as_ref().map(|p| p.name.as_str()).unwrap_or_default(),
+        prompt_tokens = generation.prompt_tokens,
+        completion_tokens = generation.completion_tokens,
+        finish_reason = ?generation.finish_reason,
+        prompt_eval_ms = generation.prompt_eval_time.as_millis() as u64,
+        generation_ms = generation.generation_time.as_millis() as u64,
+        cache_hit = generation.cache_hit,
+        "chat generation finished"
+    );
+    Ok((generation, parsed))
+}
+
+async fn chat_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(key): Extension<Arc<ApiKey>>,
+    Extension(caller): Extension<Caller>,
+    Extension(RequestId(http_request_id)): Extension<RequestId>,
+    Json(req): Json<ChatRequest>,
+) -> Response {
+    if let Err(response) = key.authorize_requested(req.model.as_deref(), req.preset.as_deref()) {
+        return response;
+    }
+    let resolved = match resolve_request(&state, req.preset.as_deref(), req.model.as_deref()) {
+        Ok(r) => r,
+        Err(message) => return (StatusCode::BAD_REQUEST, Json(ChatResponse::error(message))).into_response(),
+    };
+    if let Err(response) = key.authorize(&resolved.model_name, req.preset.as_deref()) {
+        return response;
+    }
+    let labels = RequestLabels::new(&resolved.model_name, req.preset.as_deref());
+    if let Err(message) = req.logprobs() {
+        return labels.apply((StatusCode::BAD_REQUEST, Json(ChatResponse::error(message))).into_response());
+    }
+
+    // Without an explicit id the request can be cancelled by its X-Request-Id
+    let request_id = req.request_id.clone().unwrap_or(http_request_id);
+    let token = CancelToken::default();
+    let Some(active) = state.active.register(&request_id, token.clone(), &key.name) else {
+        return (StatusCode::CONFLICT, Json(ChatResponse::error(format!("Request '{}' is already running", request_id)))).into_response();
+    };
+
+    let source = Source::new("/chat", &request_id, &key.name);
+
+    if req.stream {
+        return labels.apply(chat_stream(state, caller, source, resolved, req, token, active));
+    }
+
+    // Axum drops this future when the client disconnects, which cancels the generation
+    let _cancel_on_drop = CancelOnDrop(token.clone());
+    let _active = active;
+
+    let worker_state = Arc::clone(&state);
+    let result = state.queue
+        .run(move || {
+            // Cancelled while waiting in the queue
+            if token.is_cancelled() {
+                return Ok((Generation::cancelled(), Parsed::default()));
+            }
+            run_chat(&worker_state, &caller, &source, &resolved, &req, &mut |_, _| !token.is_cancelled())
+        })
+        .await;
+
+    let response = match result {
+        Ok(Ok((generation, parsed))) => (StatusCode::OK, Json(ChatResponse {
+            response: generation.text,
+            request_id: Some(request_id),
+            finish_reason: Some(generation.finish_reason),
+            cache_hit: Some(generation.cache_hit),
+            probabilities: generation.probabilities,
+            logprobs: generation.logprobs,
+            parsed,
+        })).into_response(),
+        Ok(Err(e)) => match e.downcast::<ValidationFailed>() {
+            Ok(failed) => (StatusCode::UNPROCESSABLE_ENTITY, Json(failed)).into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(ChatResponse::error(format!("{:#}", e)))).into_response(),
+        },
+        Err(e) => (queue_error_status(&e), Json(ChatResponse::error(e.to_string()))).into_response(),
+    };
+    labels.apply(response)
+}
+
+enum StreamMessage {
+    Token(String),
+    /// The output failed validation and is generated again from the start
+    Retry(u32),
+    Done(Generation, Parsed),
+    ValidationFailed(ValidationFailed),
+    Error(String),
+}
+
+/// Streams a /chat response as server-sent events: `token` events with new text,
+/// a `retry` event when an invalid output is generated again, then a single `done` (or `error`) event
+fn chat_stream(
+    state: Arc<AppState>,
+    caller: Caller,
+    source: Source,
+    resolved: ResolvedRequest,
+    req: ChatRequest,
+    token: CancelToken,
+    active: ActiveRequest,
+) -> Response {
+    let request_id = source.request_id.clone();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let worker_state = Arc::clone(&state);
+    let worker_token = token.clone();
+    let submitted = state.queue.submit(move || {
+        if worker_token.is_cancelled() {
+            return;
+        }
+        let mut sent = 0;
+        let mut attempt = 1;
+        let result = run_chat(&worker_state, &caller, &source, &resolved, &req, &mut |text, _| {
+            if text.is_empty() && sent > 0 {
+                attempt += 1;
+                if tx.send(StreamMessage::Retry(attempt)).is_err() {
+                    return false;
+                }
+                sent = 0;
+            }
+            if text.len() > sent {
+                // The receiver is gone once the client has disconnected
+                if tx.send(StreamMessage::Token(text[sent..].to_string())).is_err() {
+                    return false;
+                }
+                sent = text.len();
+            }
+            !worker_token.is_cancelled()
+        });
+        let _ = tx.send(match result {
+            Ok((generation, parsed)) => StreamMessage::Done(generation, parsed),
+            Err(e) => match e.downcast::<ValidationFailed>() {
+                Ok(failed) => StreamMessage::ValidationFailed(failed),
+                Err(e) => StreamMessage::Error(format!("{:#}", e)),
+            },
+        });
+    });
+    if let Err(e) = submitted {
+        return (queue_error_status(&e), Json(ChatResponse::error(e.to_string()))).into_response();
+    }
+
+    // The stream owns the guards, so dropping it (client disconnect) cancels the request
+    let guards = (CancelOnDrop(token), active);
+    let events = UnboundedReceiverStream::new(rx).map(move |message| {
+        let _guards = &guards;
+        match message {
+            StreamMessage::Token(text) => Event::default()
+                .event("token")
+                .json_data(serde_json::json!({ "text": text })),
+            StreamMessage::Retry(attempt) => Event::default()
+                .event("retry")
+                .json_data(serde_json::json!({ "attempt": attempt })),
+            StreamMessage::Done(generation, parsed) => Event::default()
+                .event("done")
+                .json_data(ChatStreamDone {
+                    response: generation.text,
+                    request_id: request_id.clone(),
+                    finish_reason: generation.finish_reason,
+                    prompt_tokens: generation.prompt_tokens,
+                    completion_tokens: generation.completion_tokens,
+                    cache_hit: generation.cache_hit,
+                    probabilities: generation.probabilities,
+                    logprobs: generation.logprobs,
+                    parsed,
+                }),
+            StreamMessage::ValidationFailed(failed) => Event::default()
+                .event("error")
+                .json_data(failed),
+            StreamMessage::Error(error) => Event::default()
+                .event("error")
+                .json_data(ErrorResponse { error }),
+        }
+    });
+
+    // Keep-alive comments also reveal a disconnect while the request is still queued
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+async fn cancel_chat_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(key): Extension<Arc<ApiKey>>,
+    Path(request_id): Path<String>,
+) -> Response {
+    if state.active.cancel(&request_id, &key) {
+        (StatusCode::OK, Json(serde_json::json!({ "request_id": request_id, "cancelled": true }))).into_response()
+    } else {
+        ErrorResponse::new(StatusCode::NOT_FOUND, format!("Request '{}' is not running", request_id))
+    }
+}
+
+/// Upper bound for prompts in a single /chat/batch request
+const MAX_BATCH_PROMPTS: usize = 1000;
+
+#[derive(Deserialize)]
+struct ChatBatchRequest {
+    prompts: Vec<String>,
+    #[serde(default)]
+    system_prompt: Option<String>,
+    #[serde(default)]
+    max_tokens: Option<usize>,
+    /// Time limit for each prompt of the batch
+    #[serde(default)]
+    max_time_ms: Option<u64>,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    preset: Option<String>,
+    /// `false` bypasses the response cache
+    #[serde(default = "default_true")]
+    cache: bool,
+}
+
+#[derive(Serialize)]
+struct ChatBatchItem {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<FinishReason>,
+    prompt_tokens: usize,
+    cached_tokens: usize,
+    completion_tokens: usize,
+    cache_hit: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    probabilities: Vec<LabelProbability>,
+    #[serde(flatten)]
+    parsed: Parsed,
+}
+
+#[derive(Serialize)]
+struct ChatBatchResponse {
+    model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preset: Option<String>,
+    results: Vec<ChatBatchItem>,
+    duration_ms: u128,
+}
+
+/// Runs all prompts of a batch on one context, reusing the KV cache of the shared prefix
+fn run_chat_batch(
+    state: &AppState,
+    source: &Source,
+    resolved: &ResolvedRequest,
+    req: &ChatBatchRequest,
+    token: &CancelToken,
+) -> anyhow::Result<Vec<ChatBatchItem>> {
+    let model = state.models.get_or_load(&state.backend, &resolved.model_name)?;
+    let mut ctx = engine::new_context(&model, &state.backend)?;
+    ctx.clear_kv_cache();
+    let mut prompt_cache = PromptCache::default();
+
+    let results = req.prompts.iter()
+        .enumerate()
+        // Prompts after a cancellation are not processed at all
+        .take_while(|_| !token.is_cancelled())
+        .map(|(index, input)| {
+            let started = Instant::now();
+            let mut rendered_prompt = None;
+            let generated = prepare_prompt(state, &model, resolved, input, req.system_prompt.as_deref(), req.max_tokens, req.max_time_ms)
+                .and_then(|(prompt, mut params)| {
+                    cap_max_time(state, &mut params);
+                    let generated = postprocess::generate_validated(resolved.preset.as_ref(), &params, |params| {
+                        let cache_key = req.cache.then(|| state.cache.key(resolved, &prompt, params)).flatten();
+                        state.cache.get_or_generate(cache_key, || {
+                            engine::generate_cached(&model, &mut ctx, &mut prompt_cache, &prompt, params, &mut |_, _| !token.is_cancelled())
+                        })
+                    });
+                    rendered_prompt = Some(prompt);
+                    generated
+                });
+            state.audit.record(Exchange {
+                source,
+                model: &resolved.model_name,
+                preset: resolved.preset.as_ref(),
+                input,
+                rendered_prompt: rendered_prompt.as_deref(),
+                result: generated.as_ref().map(|(g, _)| g),
+                latency: started.elapsed(),
+            });
+            if let Some((g, _)) = generated.as_ref().ok().filter(|(g, _)| !g.cache_hit) {
+                state.metrics.record_generation(&resolved.model_name, g);
+            }
+            match generated {
+                Ok((g, parsed)) => ChatBatchItem {
+                    index,
+                    parsed,
+                    response: Some(g.text),
+                    error: None,
+                    finish_reason: Some(g.finish_reason),
+                    prompt_tokens: g.prompt_tokens,
+                    cached_tokens: g.cached_tokens,
+                    completion_tokens: g.completion_tokens,
+                    cache_hit: g.cache_hit,
+                    probabilities: g.probabilities,
+                },
+                // The last invalid output is kept, and its tokens count against the quota
+                Err(e) => match e.downcast::<ValidationFailed>() {
+                    Ok(failed) => ChatBatchItem {
+                        index,
+                        error: Some(failed.to_string()),
+                        response: Some(failed.response),
+                        finish_reason: None,
+                        prompt_tokens: failed.prompt_tokens,
+                        cached_tokens: 0,
+                        completion_tokens: failed.completion_tokens,
+                        cache_hit: false,
+                        probabilities: Vec::new(),
+                        parsed: Parsed::default(),
+                    },
+                    Err(e) => ChatBatchItem {
+                        index,
+                        response: None,
+                        error: Some(format!("{:#}", e)),
+                        finish_reason: None,
+                        prompt_tokens: 0,
+                        cached_tokens: 0,
+                        completion_tokens: 0,
+                        cache_hit: false,
+                        probabilities: Vec::new(),
+                        parsed: Parsed::default(),
+                    },
+                },
+            }
+        })
+        .collect();
+    Ok(results)
+}
+
+async fn chat_batch_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(key): Extension<Arc<ApiKey>>,
+    Extension(caller): Extension<Caller>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Json(req): Json<ChatBatchRequest>,
+) -> Response {
+    if req.prompts.is_empty() || req.prompts.len() > MAX_BATCH_PROMPTS {
+        return ErrorResponse::new(StatusCode::BAD_REQUEST, format!("'prompts' must contain from 1 to {} items", MAX_BATCH_PROMPTS));
+    }
+
+    if let Err(response) = key.authorize_requested(req.model.as_deref(), req.preset.as_deref()) {
+        return response;
+    }
+    let resolved = match resolve_request(&state, req.preset.as_deref(), req.model.as_deref()) {
+        Ok(r) => r,
+        Err(message) => return ErrorResponse::new(StatusCode::BAD_REQUEST, message),
+    };
+    if let Err(response) = key.authorize(&resolved.model_name, req.preset.as_deref()) {
+        return response;
+    }
+    let labels = RequestLabels::new(&resolved.model_name, req.preset.as_deref());
+
+    let started = Instant::now();
+    let token = CancelToken::default();
+    let _cancel_on_drop = CancelOnDrop(token.clone());
+    let source = Source::new("/chat/batch", &request_id, &key.name);
+    let worker_state = Arc::clone(&state);
+    let result = state.queue.run(move || {
+        let results = run_chat_batch(&worker_state, &source, &resolved, &req, &token);
+        if let Ok(items) = &results {
+            let generated = items.iter().filter(|i| !i.cache_hit).map(|i| i.completion_tokens).sum();
+            worker_state.limits.record_tokens(&caller, generated);
+        }
+        (resolved, results)
+    }).await;
+
+    let response = match result {
+        Ok((resolved, Ok(results))) => (StatusCode::OK, Json(ChatBatchResponse {
+            model: resolved.model_name,
+            preset: resolved.preset.map(|p| p.name),
+            results,
+            duration_ms: started.elapsed().as_millis(),
+        })).into_response(),
+        Ok((_, Err(e))) => ErrorResponse::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)),
+        Err(e) => ErrorResponse::new(queue_error_status(&e), e.to_string()),
+    };
+    labels.apply(response)
+}
+
+#[derive(Deserialize)]
+struct EvalRequest {
+    preset: String,
+    #[serde(default)]
+    models: Vec<String>,
+    #[serde(default)]
+    cases: Vec<TestCase>,
+    #[serde(default = "default_true")]
+    include_examples: bool,
+    #[serde(default)]
+    min_pass_rate: Option<f64>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Serialize)]
+pub(crate) struct ErrorResponse {
+    pub error: String,
+}
+
+impl ErrorResponse {
+    pub fn new(status: StatusCode, error: impl Into<String>) -> Response {
+        (status, Json(ErrorResponse { error: error.into() })).into_response()
+    }
+}
+
+/// Maps queue rejections to HTTP status codes
+pub(crate) fn queue_error_status(e: &QueueError) -> StatusCode {
+    match e {
+        QueueError::Full => StatusCode::SERVICE_UNAVAILABLE,
+        QueueError::Failed => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+async fn eval_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(key): Extension<Arc<ApiKey>>,
+    Extension(caller): Extension<Caller>,
+    Json(req): Json<EvalRequest>,
+) -> Response {
+    if let Err(response) = key.authorize_requested(None, Some(&req.preset)) {
+        return response;
+    }
+    for model in &req.models {
+        if let Err(response) = key.authorize_requested(Some(model.as_str()), None) {
+            return response;
+        }
+    }
+    let Some(preset) = state.presets.get(&req.preset) else {
+        return ErrorResponse::new(StatusCode::BAD_REQUEST, format!("Preset '{}' not found. Use /presets to see available presets", req.preset));
+    };
+
+    let mut models = req.models.clone();
+    if models.is_empty() {
+        models.extend(
+            preset.preferred_model(&state.available_models)
+                .or_else(|| state.available_models.first().cloned()),
+        );
+    }
+    if let Some(missing) = models.iter().find(|m| !state.available_models.contains(m)) {
+        return ErrorResponse::new(StatusCode::BAD_REQUEST, format!("Model '{}' not found. Available models: {:?}", missing, state.available_models));
+    }
+    for model in &models {
+        if let Err(response) = key.authorize(model, Some(&preset.name)) {
+            return response;
+        }
+        if !preset.allows_model(model) {
+            return ErrorResponse::new(StatusCode::BAD_REQUEST, format!("Preset '{}' does not allow model '{}'. Allowed models: {:?}", preset.name, model, preset.allowed_models));
+        }
+    }
+
+    let cases = eval::collect_cases(&preset, req.include_examples, &req.cases);
+    if cases.is_empty() {
+        return ErrorResponse::new(StatusCode::BAD_REQUEST, format!("Preset '{}' has no examples or test cases", preset.name));
+    }
+    let min_pass_rate = req.min_pass_rate.unwrap_or(1.0);
+
+    let labels = RequestLabels::new(&models.join(","), Some(&preset.name));
+
+    // Evaluation runs many generations on the inference queue
+    let worker_state = Arc::clone(&state);
+    let report = state.queue.run(move || {
+        let runtime = EvalRuntime {
+            backend: &worker_state.backend,
+            models: &worker_state.models,
+            retriever: &worker_state.retriever,
+            max_time: worker_state.max_time,
+        };
+        let mut tokens = 0;
+        let report = eval::run_eval(&runtime, &preset, &models, &cases, min_pass_rate, &mut |_, case| {
+            tokens += case.completion_tokens;
+        });
+        worker_state.limits.record_tokens(&caller, tokens);
+        report
+    }).await;
+
+    let response = match report {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(e) => ErrorResponse::new(queue_error_status(&e), e.to_string()),
+    };
+    labels.apply(response)
+}
+
+/// Endpoints listed in the startup log: method, path and description
+const ENDPOINTS: &[(&str, &str, &str)] = &[
+    ("GET", "/models", "available models"),
+    ("GET", "/presets", "available presets"),
+    ("GET", "/presets/status", "preset version and reload errors"),
+    ("POST", "/presets/reload", "reload presets now"),
+    ("POST", "/chat", "generate a response"),
+    ("DELETE", "/chat/{request_id}", "cancel a /chat request"),
+    ("POST", "/chat/batch", "batch of prompts with one preset and model"),
+    ("POST", "/eval", "run preset examples and test cases"),
+    ("POST", "/jobs", "asynchronous generation job"),
+    ("GET", "/jobs/{id}", "job status, progress and result"),
+    ("DELETE", "/jobs/{id}", "cancel a job"),
+    ("POST", "/v1/embeddings", "text embeddings (OpenAI-compatible)"),
+    ("POST", "/v1/chat/completions", "chat with tool calling (OpenAI-compatible)"),
+    ("POST", "/tokenize", "tokens of a text"),
+    ("POST", "/detokenize", "text of token ids"),
+    ("POST", "/render", "final preset prompt and its size in tokens"),
+    ("GET", "/usage", "rate limits and usage"),
+    ("GET", "/audit", "search and export the audit log"),
+    ("GET", "/health", "liveness check"),
+    ("GET", "/ready", "readiness (default model loaded)"),
+    ("GET", "/metrics", "Prometheus metrics"),
+];
+
+pub async fn run(backend: LlamaBackend, models: Vec<String>, config: Config) -> Result<()> {
+    info!(count = models.len(), models = ?models, "models found");
+
+    let presets = PresetStore::load();
+    let snapshot = presets.snapshot();
+    for preset in &snapshot.presets {
+        debug!(preset = %preset.name, description = %preset.description, "preset available");
+    }
+
+    match presets.watch() {
+        Ok(()) => info!("watching presets.json and presets/ for changes"),
+        Err(e) => warn!(error = %e, "failed to watch preset files, automatic reload is off"),
+    }
+
+    let listen = config.server.listen.iter()
+        .map(|l| Listen::parse(l))
+        .collect::<Result<Vec<_>>>()?;
+    if listen.is_empty() {
+        anyhow::bail!("не указан ни один адрес в server.listen");
+    }
+    let cors = listener::cors_layer(&config.server.cors)?;
+
+    let auth = Auth::new(&config.auth.keys)?;
+    if auth.enabled() {
+        info!(keys = config.auth.keys.len(), "API key authentication enabled");
+    } else {
+        warn!("no API keys configured, the server accepts unauthenticated requests");
+    }
+
+    let audit = AuditLog::new(&config.audit)?;
+    if let Some(path) = &config.audit.path {
+        info!(path = %path, "audit log enabled");
+    }
+
+    let jobs = JobStore::new(Duration::from_secs(config.jobs.retention_secs));
+    jobs.spawn_cleanup();
+
+    let state = Arc::new(AppState {
+        backend,
+        available_models: models.clone(),
+        models: ModelCache::default(),
+        presets,
+        queue: InferenceQueue::new(config.queue.workers, config.queue.capacity),
+        jobs,
+        active: Arc::new(ActiveRequests::default()),
+        max_time: config.generation.max_time_ms.map(Duration::from_millis),
+        auth,
+        limits: RateLimiter::new(&config.limits),
+        metrics: Metrics::default(),
+        readiness: Mutex::new(Readiness::Loading),
+        audit,
+        cache: ResponseCache::new(&config.cache),
+        retriever: Retriever::default(),
+    });
+
+    // The default model is loaded in the background; /ready reports when it is done
+    let preload_state = Arc::clone(&state);
+    tokio::task::spawn_blocking(move || {
+        let Some(model) = preload_state.available_models.first() else { return };
+        let readiness = match preload_state.models.get_or_load(&preload_state.backend, model) {
+            Ok(_) => {
+                info!(model = %model, "default model loaded");
+                Readiness::Ready
+            }
+            Err(e) => {
+                error!(model = %model, error = format!("{:#}", e), "failed to load default model");
+                Readiness::Failed(format!("{:#}", e))
+            }
+        };
+        *preload_state.readiness.lock().unwrap() = readiness;
+    });
+
+    // Preset management, usage of all callers and the audit log require an admin key
+    let admin = Router::new()
+        .route("/presets/status", get(presets_status_handler))
+        .route("/presets/reload", post(presets_reload_handler))
+        .route("/usage/all", get(ratelimit::all_usage_handler))
+        .route("/audit", get(audit::audit_handler))
+        .route_layer(middleware::from_fn(auth::require_admin));
+
+    // Endpoints that enqueue work on the inference queue count against rate limits and token quotas
+    let generation = Router::new()
+        .route("/chat", post(chat_handler))
+        .route("/chat/batch", post(chat_batch_handler))
+        .route("/eval", post(eval_handler))
+        .route("/jobs", post(jobs::create_job_handler))
+        .route("/v1/embeddings", post(embeddings::embeddings_handler))
+        .route("/v1/chat/completions", post(completions::chat_completions_handler))
+        .route("/tokenize", post(inspect::tokenize_handler))
+        .route("/detokenize", post(inspect::detokenize_handler))
+        .route("/render", post(inspect::render_handler))
+        .route_layer(middleware::from_fn_with_state(Arc::clone(&state), ratelimit::enforce_limits))
+        .route_layer(middleware::from_fn_with_state(Arc::clone(&state), metrics::track));
+
+    let app = Router::new()
+        .route("/models", get(models_handler))
+        .route("/presets", get(presets_handler))
+        .route("/chat/:request_id", delete(cancel_chat_handler))
+        .route("/jobs/:id", get(jobs::get_job_handler).delete(jobs::delete_job_handler))
+        .route("/usage", get(ratelimit::usage_handler))
+        .merge(generation)
+        .merge(admin)
+        .layer(middleware::from_fn_with_state(Arc::clone(&state), auth::require_api_key))
+        // Probes and scraping work without an API key
+        .route("/health", get(metrics::health_handler))
+        .route("/ready", get(metrics::ready_handler))
+        .route("/metrics", get(metrics::metrics_handler))
+        .layer(cors)
+        .layer(middleware::from_fn(logging::trace_request))
+        .with_state(state);
+
+    let tls = config.server.tls.is_some();
+    for address in &listen {
+        info!(address = %address.url(tls), "listening");
+    }
+    let (curl, base) = match &listen[0] {
+        Listen::Tcp(_) => ("curl".to_string(), listen[0].url(tls)),
+        Listen::Unix(path) => (format!("curl --unix-socket {}", path), "http://localhost".to_string()),
+    };
+    for &(method, path, description) in ENDPOINTS {
+        info!(method, path, description, "endpoint");
+    }
+    let examples = [
+        format!("{} {}/models", curl, base),
+        format!("{} {}/presets", curl, base),
+        format!(r#"{} -X POST {}/chat -H "Content-Type: application/json" -d '{{"prompt": "iPhone 15", "preset": "price_classifier"}}'"#, curl, base),
+        format!(r#"{} -X POST {}/chat -H "Content-Type: application/json" -d '{{"prompt": "Что такое Rust?", "preset": "assistant"}}'"#, curl, base),
+    ];
+    for command in &examples {
+        info!(command = %command, "example request");
+    }
+    info!("press Ctrl+C to stop");
+
+    listener::serve(&listen, config.server.tls.as_ref(), app).await?;
+    
+    Ok(())
+}
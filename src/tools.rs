@@ -0,0 +1,466 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Time limit for a local tool without `timeout_ms`
+const DEFAULT_TOOL_TIMEOUT_MS: u64 = 10_000;
+/// Tool output is cut to this many bytes before it is shown to the model
+const MAX_TOOL_OUTPUT_BYTES: usize = 8192;
+
+/// A function the model may call
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ToolDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// JSON Schema of the arguments
+    #[serde(default = "default_parameters")]
+    pub parameters: serde_json::Value,
+    /// How the server runs the tool itself; without it calls are returned to the client
+    #[serde(default)]
+    pub exec: Option<ToolExec>,
+}
+
+/// Schema of a tool without parameters
+pub fn default_parameters() -> serde_json::Value {
+    serde_json::json!({ "type": "object", "properties": {} })
+}
+
+/// Local implementation of a preset tool
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ToolExec {
+    /// Program and its arguments; call arguments are written to stdin as JSON, stdout is the result
+    Command {
+        command: Vec<String>,
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+    },
+    /// POST of the call arguments as JSON to a service on localhost; the response body is the result
+    Http {
+        url: String,
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+    },
+}
+
+/// Whether and which tools the model may call in its next answer
+#[derive(Clone, PartialEq, Eq)]
+pub enum ToolChoice {
+    /// Plain text answer only
+    None,
+    /// Tool call or text, as the model decides
+    Auto,
+    /// Some tool call
+    Required,
+    /// A call of this tool
+    Function(String),
+}
+
+/// A tool call parsed from the model output
+#[derive(Clone)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
+impl ToolCall {
+    /// Text form of the call, as the model emits it and as it is shown in later turns
+    pub fn to_text(&self) -> String {
+        serde_json::json!({ "name": self.name, "arguments": self.arguments }).to_string()
+    }
+}
+
+pub fn new_call_id() -> String {
+    format!("call_{}", uuid::Uuid::new_v4().simple())
+}
+
+/// Checks tool names: unique, 1–64 characters of `a-z A-Z 0-9 _ -` (as OpenAI requires)
+pub fn validate(tools: &[ToolDefinition]) -> Result<()> {
+    for (i, tool) in tools.iter().enumerate() {
+        let valid = !tool.name.is_empty()
+            && tool.name.len() <= 64
+            && tool.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid {
+            bail!("Invalid tool name '{}': use 1-64 characters a-z, A-Z, 0-9, _ and -", tool.name);
+        }
+        if tools[..i].iter().any(|t| t.name == tool.name) {
+            bail!("Tool '{}' is defined twice", tool.name);
+        }
+        if let Some(ToolExec::Command { command, .. }) = &tool.exec {
+            if command.is_empty() {
+                bail!("Tool '{}' has an empty command", tool.name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Instructions about the available tools, appended to the system message
+pub fn system_text(tools: &[ToolDefinition]) -> Option<String> {
+    if tools.is_empty() {
+        return None;
+    }
+    let list = tools.iter()
+        .map(|t| format!("- {}: {}\n  Параметры (JSON Schema): {}", t.name, t.description, t.parameters))
+        .collect::<Vec<_>>()
+        .join("\n");
+    Some(format!(
+        "Доступные функции:\n{}\n\n\
+         Если для ответа нужна функция, ответь только JSON-объектом вида \
+         {{\"name\": \"<функция>\", \"arguments\": {{<аргументы>}}}} без другого текста. \
+         Результат функции придёт следующим сообщением. Если функция не нужна, отвечай обычным текстом.",
+        list
+    ))
+}
+
+/// JSON values for the call arguments (same rules as llama.cpp's json.gbnf)
+const JSON_RULES: &str = r#"
+object ::= "{" ws ( string ":" ws value ( "," ws string ":" ws value )* )? "}" ws
+array  ::= "[" ws ( value ( "," ws value )* )? "]" ws
+value  ::= object | array | string | number | ( "true" | "false" | "null" ) ws
+string ::= "\"" ( [^"\\\x7F\x00-\x1F] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F]{4} ) )* "\"" ws
+number ::= "-"? ( [0-9] | [1-9] [0-9]{0,15} ) ( "." [0-9]+ )? ( [eE] [-+]? [0-9]{1,15} )? ws
+ws     ::= | " " | "\n" [ \t]{0,20}
+"#;
+
+/// GBNF grammar that lets the model answer with text or a tool call, depending on `choice`.
+///
+/// A text answer may not start with `{`, so any output starting with `{`
+/// has to be a complete call of a known tool. Without tools there is no constraint.
+pub fn grammar(tools: &[ToolDefinition], choice: &ToolChoice) -> Option<String> {
+    if tools.is_empty() {
+        return None;
+    }
+    let names: Vec<&str> = match choice {
+        ToolChoice::Function(name) => vec![name.as_str()],
+        _ => tools.iter().map(|t| t.name.as_str()).collect(),
+    };
+    let root = match choice {
+        ToolChoice::None => "root ::= [ \\t\\n]* answer",
+        ToolChoice::Auto => "root ::= [ \\t\\n]* ( call | answer )",
+        ToolChoice::Required | ToolChoice::Function(_) => "root ::= [ \\t\\n]* call",
+    };
+    // Names are validated, so they need no escaping inside the literal
+    let name_rule = names.iter()
+        .map(|n| format!("\"\\\"{}\\\"\"", n))
+        .collect::<Vec<_>>()
+        .join(" | ");
+    Some(format!(
+        "{}\nanswer ::= [^{{ \\t\\n] [^\\x00]*\n\
+         call ::= \"{{\" ws \"\\\"name\\\"\" ws \":\" ws name ws \",\" ws \"\\\"arguments\\\"\" ws \":\" ws object \"}}\"\n\
+         name ::= {}\n{}",
+        root, name_rule, JSON_RULES
+    ))
+}
+
+/// Returns the call if the output is a JSON call of one of `tools`
+pub fn parse_call(text: &str, tools: &[ToolDefinition]) -> Option<ToolCall> {
+    #[derive(Deserialize)]
+    struct RawCall {
+        name: String,
+        #[serde(default)]
+        arguments: serde_json::Value,
+    }
+    let raw: RawCall = serde_json::from_str(text.trim()).ok()?;
+    if !tools.iter().any(|t| t.name == raw.name) {
+        return None;
+    }
+    Some(ToolCall {
+        id: new_call_id(),
+        name: raw.name,
+        arguments: if raw.arguments.is_null() { serde_json::json!({}) } else { raw.arguments },
+    })
+}
+
+/// Text of a tool result message fed back to the model
+pub fn result_text(name: &str, output: &str) -> String {
+    format!("Результат функции {}: {}", name, output)
+}
+
+/// Runs a preset tool with its local implementation.
+///
+/// Failures are returned as text so the model can still answer; they are also logged.
+/// `limit` caps the tool's own timeout (what is left of the request's time budget).
+pub fn execute(tool: &ToolDefinition, arguments: &serde_json::Value, limit: Option<Duration>) -> String {
+    let Some(exec) = &tool.exec else {
+        return format!("ошибка: функция {} недоступна на сервере", tool.name);
+    };
+    let started = Instant::now();
+    let input = arguments.to_string();
+    let timeout = |timeout_ms: Option<u64>| limit.map_or(timeout(timeout_ms), |limit| timeout(timeout_ms).min(limit));
+    let output = match exec {
+        ToolExec::Command { command, timeout_ms } => run_process(command, &input, timeout(*timeout_ms)),
+        ToolExec::Http { url, timeout_ms } => post_localhost(url, &input, timeout(*timeout_ms)),
+    };
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    match output {
+        Ok(mut text) => {
+            info!(tool = %tool.name, elapsed_ms, "tool executed");
+            if text.len() > MAX_TOOL_OUTPUT_BYTES {
+                let mut end = MAX_TOOL_OUTPUT_BYTES;
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                text.truncate(end);
+            }
+            text
+        }
+        Err(e) => {
+            warn!(tool = %tool.name, elapsed_ms, error = format!("{:#}", e), "tool failed");
+            format!("ошибка: {:#}", e)
+        }
+    }
+}
+
+fn timeout(timeout_ms: Option<u64>) -> Duration {
+    Duration::from_millis(timeout_ms.unwrap_or(DEFAULT_TOOL_TIMEOUT_MS))
+}
+
+fn run_process(command: &[String], input: &str, timeout: Duration) -> Result<String> {
+    let (program, args) = command.split_first().context("Empty tool command")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to start {}", program))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes()).context("Failed to write tool input")?;
+    }
+    // Pipes are drained on separate threads so a chatty tool cannot block on a full pipe
+    let mut stdout = child.stdout.take().context("No tool stdout")?;
+    let mut stderr = child.stderr.take().context("No tool stderr")?;
+    let stdout_reader = thread::spawn(move || {
+        let mut buf = Vec::new();
+        stdout.read_to_end(&mut buf).map(|_| buf)
+    });
+    let stderr_reader = thread::spawn(move || {
+        let mut buf = Vec::new();
+        stderr.read_to_end(&mut buf).map(|_| buf)
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("Tool timed out after {} ms", timeout.as_millis());
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    let stdout = stdout_reader.join().unwrap_or_else(|_| Ok(Vec::new()))?;
+    if !status.success() {
+        let stderr = stderr_reader.join().unwrap_or_else(|_| Ok(Vec::new()))?;
+        bail!("Tool exited with {}: {}", status, String::from_utf8_lossy(&stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&stdout).trim().to_string())
+}
+
+/// Minimal HTTP/1.0 POST; only services on the local machine are allowed
+fn post_localhost(url: &str, body: &str, timeout: Duration) -> Result<String> {
+    let rest = url.strip_prefix("http://")
+        .with_context(|| format!("Tool URL must start with http://: {}", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, port.parse::<u16>().context("Invalid port in tool URL")?),
+        _ => (authority, 80),
+    };
+    if !matches!(host, "localhost" | "127.0.0.1" | "[::1]") {
+        bail!("Tool URL must point to localhost: {}", url);
+    }
+
+    let address = (host.trim_matches(['[', ']']), port)
+        .to_socket_addrs()?
+        .next()
+        .with_context(|| format!("Cannot resolve {}", host))?;
+    let mut stream = TcpStream::connect_timeout(&address, timeout)
+        .with_context(|| format!("Failed to connect to {}", url))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    // HTTP/1.0 rules out chunked responses, the body ends when the connection closes
+    write!(
+        stream,
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        path, authority, body.len(), body
+    )?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).context("Failed to read tool response")?;
+
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head.split_whitespace().nth(1).and_then(|s| s.parse::<u16>().ok()).unwrap_or(0);
+    if !(200..300).contains(&status) {
+        bail!("Tool service returned HTTP {}: {}", status, body.trim());
+    }
+    Ok(body.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::net::TcpListener;
+
+    fn tool(config: serde_json::Value) -> ToolDefinition {
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn tools() -> Vec<ToolDefinition> {
+        vec![tool(json!({"name": "get_weather"})), tool(json!({"name": "get-time"}))]
+    }
+
+    fn root(grammar: &str) -> &str {
+        grammar.lines().next().unwrap()
+    }
+
+    fn name_rule(grammar: &str) -> &str {
+        grammar.lines().find(|line| line.starts_with("name ::=")).unwrap()
+    }
+
+    #[test]
+    fn no_tools_means_no_grammar() {
+        assert!(grammar(&[], &ToolChoice::Auto).is_none());
+        assert!(grammar(&[], &ToolChoice::Required).is_none());
+    }
+
+    #[test]
+    fn grammar_root_follows_the_tool_choice() {
+        let tools = tools();
+        let cases = [
+            (ToolChoice::None, "root ::= [ \\t\\n]* answer"),
+            (ToolChoice::Auto, "root ::= [ \\t\\n]* ( call | answer )"),
+            (ToolChoice::Required, "root ::= [ \\t\\n]* call"),
+            (ToolChoice::Function("get-time".to_string()), "root ::= [ \\t\\n]* call"),
+        ];
+        for (choice, expected) in cases {
+            let grammar = grammar(&tools, &choice).unwrap();
+            assert_eq!(root(&grammar), expected);
+            assert!(grammar.contains("answer ::= [^{ \\t\\n] [^\\x00]*"));
+            assert!(grammar.contains("object ::="));
+        }
+    }
+
+    #[test]
+    fn grammar_names_only_the_callable_tools() {
+        let tools = tools();
+        let all = grammar(&tools, &ToolChoice::Auto).unwrap();
+        assert_eq!(name_rule(&all), r#"name ::= "\"get_weather\"" | "\"get-time\"""#);
+        let one = grammar(&tools, &ToolChoice::Function("get-time".to_string())).unwrap();
+        assert_eq!(name_rule(&one), r#"name ::= "\"get-time\"""#);
+    }
+
+    #[test]
+    fn calls_of_known_tools_are_parsed() {
+        let tools = tools();
+        let call = parse_call(r#" {"name": "get_weather", "arguments": {"city": "Москва"}} "#, &tools).unwrap();
+        assert_eq!(call.name, "get_weather");
+        assert_eq!(call.arguments, json!({"city": "Москва"}));
+        assert!(call.id.starts_with("call_"));
+
+        // Missing and null arguments become an empty object
+        for text in [r#"{"name": "get-time"}"#, r#"{"name": "get-time", "arguments": null}"#] {
+            assert_eq!(parse_call(text, &tools).unwrap().arguments, json!({}));
+        }
+    }
+
+    #[test]
+    fn other_outputs_are_not_calls() {
+        let tools = tools();
+        for text in [
+            r#"{"name": "delete_files", "arguments": {}}"#,
+            r#"{"arguments": {}}"#,
+            r#"{"name": "get_weather", "arguments": {"city": "Москва"}"#,
+            "Сейчас в Москве +5",
+            "",
+        ] {
+            assert!(parse_call(text, &tools).is_none(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn tool_definitions_are_validated() {
+        assert!(validate(&tools()).is_ok());
+        let invalid = [
+            vec![tool(json!({"name": ""}))],
+            vec![tool(json!({"name": "get weather"}))],
+            vec![tool(json!({"name": "a".repeat(65)}))],
+            vec![tool(json!({"name": "get_weather"})), tool(json!({"name": "get_weather"}))],
+            vec![tool(json!({"name": "run", "exec": {"type": "command", "command": []}}))],
+        ];
+        for tools in invalid {
+            assert!(validate(&tools).is_err(), "{}", tools[0].name);
+        }
+    }
+
+    #[test]
+    fn tool_urls_must_point_to_localhost() {
+        for url in [
+            "https://localhost:8080/weather",
+            "ftp://localhost/weather",
+            "http://example.com/weather",
+            "http://10.0.0.5:8080/weather",
+            "http://127.0.0.2/weather",
+            "http://0.0.0.0:8080/weather",
+            "http://localhost.example.com/weather",
+            "http://localhost@example.com/weather",
+            "http://example.com#@localhost/weather",
+            "http://[::2]:8080/weather",
+            "http://LOCALHOST.example.com:80/weather",
+        ] {
+            let error = post_localhost(url, "{}", Duration::from_millis(100)).unwrap_err();
+            let error = format!("{:#}", error);
+            assert!(error.contains("localhost") || error.contains("http://"), "{}: {}", url, error);
+        }
+        assert!(post_localhost("http://localhost:99999/", "{}", Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn localhost_service_receives_the_arguments() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            // The request is complete once its JSON body has been read
+            while !request.ends_with(b"}") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\n+5, cloudy\n").unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let output = post_localhost(&format!("http://127.0.0.1:{}/weather", port), r#"{"city":"Москва"}"#, Duration::from_secs(5)).unwrap();
+        assert_eq!(output, "+5, cloudy");
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /weather HTTP/1.0\r\n"), "{}", request);
+        assert!(request.ends_with("\r\n\r\n{\"city\":\"Москва\"}"), "{}", request);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tool_timeout_is_capped_by_the_remaining_time() {
+        let slow = tool(json!({"name": "slow", "exec": {"type": "command", "command": ["sleep", "5"], "timeout_ms": 5000}}));
+        let started = Instant::now();
+        let output = execute(&slow, &json!({}), Some(Duration::from_millis(100)));
+        assert_eq!(output, "ошибка: Tool timed out after 100 ms");
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}